@@ -0,0 +1,65 @@
+use crate::test_runner::TestReport;
+
+/// Where cycle outcomes are surfaced besides the log
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum AnnotationFormat {
+    #[default]
+    None,
+    /// GitHub Actions workflow commands (`::error file=...::`, `::notice::`)
+    Github,
+}
+
+impl AnnotationFormat {
+    /// GitHub when running inside GitHub Actions, nothing otherwise
+    pub fn detect() -> Self {
+        if std::env::var("GITHUB_ACTIONS").is_ok_and(|v| v == "true") {
+            AnnotationFormat::Github
+        } else {
+            AnnotationFormat::None
+        }
+    }
+
+    /// One `::error` per failing test, pointing at its source location when known
+    pub fn test_failures(self, title: &str, report: &TestReport, raw_output: &str) {
+        if self != AnnotationFormat::Github {
+            return;
+        }
+        if report.failures.is_empty() {
+            println!(
+                "::error title={}::{}",
+                escape_property(title),
+                escape_data(raw_output)
+            );
+        }
+        for f in &report.failures {
+            let mut props = Vec::new();
+            if let Some(file) = &f.file {
+                props.push(format!("file={}", escape_property(file)));
+            }
+            if let Some(line) = f.line {
+                props.push(format!("line={line}"));
+            }
+            props.push(format!(
+                "title={}",
+                escape_property(&format!("{title}: {}", f.name))
+            ));
+            println!("::error {}::{}", props.join(","), escape_data(&f.message));
+        }
+    }
+
+    pub fn notice(self, message: &str) {
+        if self == AnnotationFormat::Github {
+            println!("::notice::{}", escape_data(message));
+        }
+    }
+}
+
+fn escape_data(s: &str) -> String {
+    s.replace('%', "%25")
+        .replace('\r', "%0D")
+        .replace('\n', "%0A")
+}
+
+fn escape_property(s: &str) -> String {
+    escape_data(s).replace(':', "%3A").replace(',', "%2C")
+}
@@ -1,14 +1,31 @@
-use anyhow::Result;
+use anyhow::{Context, Result};
 use clap::{Parser, Subcommand};
+use std::io::Write;
 use std::path::{Path, PathBuf};
+use std::time::Duration;
+use tracing_subscriber::prelude::*;
 use tracing_subscriber::{EnvFilter, fmt};
 
+mod annotations;
 mod orchestrator;
 mod providers;
+mod state;
+#[cfg(feature = "telemetry")]
+mod telemetry;
+mod test_runner;
 mod vcs;
 mod workspace;
 
-use orchestrator::{Orchestrator, OrchestratorConfig};
+use annotations::AnnotationFormat;
+use orchestrator::{CycleSummary, Orchestrator, OrchestratorConfig, Phase, ProjectTarget};
+use providers::cost_tracker::BudgetExceededError;
+use providers::openai_batch::OpenAiBatchProvider;
+use tracing::Instrument;
+
+#[cfg(feature = "telemetry")]
+use telemetry::TelemetryGuard;
+#[cfg(not(feature = "telemetry"))]
+type TelemetryGuard = std::convert::Infallible;
 
 #[derive(Parser, Debug)]
 #[command(
@@ -17,77 +34,936 @@ use orchestrator::{Orchestrator, OrchestratorConfig};
     about = "Orchestrate TDD with LLM roles: tester, implementor, refactorer."
 )]
 struct Cli {
-    /// Path to the kata project (a cargo project recommended)
+    /// Path to the kata project (a cargo project recommended), or a git URL to clone
     #[arg(long, default_value = ".")]
     project: PathBuf,
 
+    /// Only run the configured `projects` with these names (comma-separated)
+    #[arg(long, global = true, value_delimiter = ',')]
+    project_filter: Vec<String>,
+
+    /// Clone a `--project` URL here instead of a temporary directory removed on exit
+    #[arg(long, global = true)]
+    clone_to: Option<PathBuf>,
+
     /// Path to YAML config with provider settings
     #[arg(long)]
     config: Option<PathBuf>,
 
+    /// Let `<PREFIX>_<FIELD>` env vars override config values (`__` separates nested fields)
+    #[arg(long, global = true)]
+    config_env_prefix: Option<String>,
+
     /// Increase verbosity (-v, -vv)
     #[arg(short, long, action = clap::ArgAction::Count)]
     verbose: u8,
 
+    /// Expose Prometheus metrics on this port (at /metrics)
+    #[arg(long, global = true)]
+    metrics_port: Option<u16>,
+
+    /// Export cycle spans to this OTLP/HTTP endpoint (requires the `telemetry` feature)
+    #[arg(long, global = true)]
+    otlp_endpoint: Option<String>,
+
+    /// Output format for commands that print results
+    #[arg(long, value_enum, default_value_t = OutputFormat::Text, global = true)]
+    output_format: OutputFormat,
+
+    /// Print the estimated total provider cost when rgr exits
+    #[arg(long, global = true)]
+    cost_summary: bool,
+
+    /// Stop once the estimated provider spend exceeds this many USD (overrides `budget_limit_usd`)
+    #[arg(long, global = true)]
+    budget_limit_usd: Option<f64>,
+
+    /// Warn once the spend reaches this percentage of the budget (overrides `budget_alert_at_pct`)
+    #[arg(long, global = true)]
+    budget_alert_at_pct: Option<f64>,
+
+    /// Name of the kata being worked on, given to every role (overrides `kata_name`)
+    #[arg(long, global = true)]
+    kata_name: Option<String>,
+
+    /// Before running cycles, go back to the HEAD of the snapshot saved after the last
+    /// completed cycle, dropping whatever an interrupted cycle committed. A `--config` given
+    /// now still applies; without one, the snapshot's config is used
+    #[arg(long, global = true)]
+    resume: bool,
+
+    /// Emit CI annotations for cycle outcomes (default: `github` under GitHub Actions)
+    #[arg(long, value_enum, global = true)]
+    annotations: Option<AnnotationFormat>,
+
     #[command(subcommand)]
     command: Option<Commands>,
 }
 
+#[derive(clap::ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+enum OutputFormat {
+    Text,
+    Json,
+}
+
 #[derive(Subcommand, Debug)]
 enum Commands {
     /// Run the Red-Green-Refactor loop once (tester -> implementor -> refactorer)
     RunOnce,
+    /// Run exactly COUNT cycles, then print how many succeeded
+    RunN {
+        count: usize,
+        /// Keep going after a cycle fails with an error instead of stopping
+        #[arg(long)]
+        continue_on_error: bool,
+    },
     /// Run continuously until stopped (Ctrl-C)
-    Run,
+    Run {
+        /// Exit with "Kata complete" when the suite already passes at the start of a cycle
+        #[arg(long)]
+        stop_when_tests_pass: bool,
+    },
     /// Initialize a sample config file
     InitConfig {
         #[arg(long, default_value = "red-green-refactor.yaml")]
         out: PathBuf,
     },
+    /// Restore the project to a known-good state (default: the last `rgr/cycle-*` tag)
+    Reset {
+        /// Commit-ish to reset to
+        #[arg(long)]
+        to: Option<String>,
+        /// Stash local changes before resetting
+        #[arg(long)]
+        keep_stash: bool,
+        /// Pick the target among recent `rgr/` tags
+        #[arg(long)]
+        interactive: bool,
+    },
+    /// Show past cycle outcomes recorded in `.rgr-state.json`
+    History {
+        /// Only show the last N cycles
+        #[arg(long)]
+        last: Option<usize>,
+        /// List test:/feat:/refactor: commits from `git log` instead of recorded cycles
+        #[arg(long)]
+        commits: bool,
+        /// Show how each failed implementor attempt changed the previous one, from the
+        /// saved `attempts/implementor-*` branches
+        #[arg(long, conflicts_with = "commits")]
+        diff_attempts: bool,
+    },
+    /// Print the JSON Schema of the config file (for editor autocomplete and validation)
+    PrintSchema,
+    /// Check that the config loads and every role's provider can be built
+    ValidateConfig {
+        /// Also print the project language used to pick the default `test_cmd`
+        #[arg(long)]
+        detect_language: bool,
+    },
+    /// Re-derive the patch from a `request_log_dir` entry, without calling the provider
+    Replay {
+        /// A `*-request.json` or `*-response.json` log file
+        log: PathBuf,
+    },
+    /// Print the prompt a role would send to its provider, without sending it
+    InspectContext {
+        #[arg(long, value_enum, default_value_t = Phase::Tester)]
+        role: Phase,
+    },
+    /// Delete the `attempts/implementor-*` branches saved by failed implementor phases
+    CleanupAttempts {
+        /// Only delete branches whose last commit is older than this many days
+        #[arg(long)]
+        older_than: Option<u64>,
+    },
+    /// Apply one commit (e.g. from an `attempts/implementor-*` branch) onto the current branch
+    CherryPick {
+        /// Commit-ish to apply
+        commit: String,
+    },
+    /// Send the prompt a role would get next to OpenAI's Batch API (half price, done within
+    /// 24h) using the role's provider settings, and print the batch id. Each batch holds that
+    /// one prompt: a whole session is not batched, since every prompt depends on the last patch
+    SubmitBatch {
+        #[arg(long, value_enum, default_value_t = Phase::Tester)]
+        role: Phase,
+    },
+    /// Print the status of a batch from `submit-batch`, or its patch as JSON once it is done.
+    /// Nothing is applied: queue the patch with `--save-to` and replay it with `local_file`
+    RetrieveBatch {
+        batch_id: String,
+        /// Role whose provider settings (base URL, API key) to use
+        #[arg(long, value_enum, default_value_t = Phase::Tester)]
+        role: Phase,
+        /// Save the patch as the next `NNNN-<role>.json` in this directory instead of printing
+        /// it, for a `local_file` provider with this `patch_dir` to apply, check and commit in
+        /// the next run
+        #[arg(long)]
+        save_to: Option<PathBuf>,
+    },
+    /// Check out an `attempts/implementor-*` branch rebased onto the latest tester commit,
+    /// to inspect the failed attempt or continue from it
+    ReplayAttempt {
+        branch: String,
+        /// Commit to rebase onto instead of the latest `test:` commit reachable from HEAD
+        #[arg(long)]
+        onto: Option<String>,
+    },
 }
 
-fn init_tracing(verbosity: u8) {
+fn init_tracing(verbosity: u8, otlp_endpoint: Option<&str>) -> Result<Option<TelemetryGuard>> {
     let level = match verbosity {
         0 => "info",
         1 => "debug",
         _ => "trace",
     };
     let filter = EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new(level));
-    let _ = fmt().with_env_filter(filter).without_time().try_init();
+    let registry = tracing_subscriber::registry()
+        .with(filter)
+        .with(fmt::layer().without_time());
+    match otlp_endpoint {
+        #[cfg(feature = "telemetry")]
+        Some(endpoint) => {
+            let (layer, guard) = telemetry::otlp_layer(endpoint)?;
+            let _ = registry.with(layer).try_init();
+            Ok(Some(guard))
+        }
+        #[cfg(not(feature = "telemetry"))]
+        Some(_) => Err(anyhow::anyhow!(
+            "--otlp-endpoint requires rgr to be built with the `telemetry` feature"
+        )),
+        None => {
+            let _ = registry.try_init();
+            Ok(None)
+        }
+    }
 }
 
 #[tokio::main]
 async fn main() -> Result<()> {
     let cli = Cli::parse();
-    init_tracing(cli.verbose);
+    let _telemetry = init_tracing(cli.verbose, cli.otlp_endpoint.as_deref())?;
+    if let Some(port) = cli.metrics_port {
+        metrics_exporter_prometheus::PrometheusBuilder::new()
+            .with_http_listener(([0, 0, 0, 0], port))
+            .set_buckets_for_metric(
+                metrics_exporter_prometheus::Matcher::Full("rgr_phase_duration_seconds".into()),
+                &[1.0, 5.0, 15.0, 30.0, 60.0, 120.0, 300.0, 600.0],
+            )?
+            .install()?;
+        tracing::info!("Serving Prometheus metrics on port {port}");
+    }
 
-    match cli.command.unwrap_or(Commands::RunOnce) {
+    let command = cli.command.unwrap_or(Commands::RunOnce);
+    let (project, clone) = if matches!(
+        command,
+        Commands::InitConfig { .. } | Commands::Replay { .. } | Commands::PrintSchema
+    ) {
+        (cli.project, None)
+    } else {
+        resolve_project(cli.project, cli.clone_to).await?
+    };
+    let resume = match (cli.resume, cli.config.is_some()) {
+        (false, _) => Resume::No,
+        (true, true) => Resume::KeepConfig,
+        (true, false) => Resume::SnapshotConfig,
+    };
+    let cycles_ran = matches!(
+        command,
+        Commands::RunOnce | Commands::RunN { .. } | Commands::Run { .. }
+    );
+    let load_config = || {
+        let mut cfg = orchestrator::load_orchestrator_config(
+            cli.config.as_ref(),
+            &project,
+            cli.config_env_prefix.as_deref(),
+        )?;
+        if let Some(name) = &cli.kata_name {
+            cfg.kata_name = Some(name.clone());
+        }
+        if let Some(limit) = cli.budget_limit_usd {
+            cfg.budget_limit_usd = Some(limit);
+        }
+        if let Some(pct) = cli.budget_alert_at_pct {
+            cfg.budget_alert_at_pct = pct;
+        }
+        anyhow::Ok(cfg)
+    };
+    let result = match command {
         Commands::InitConfig { out } => {
             let path = if out.is_dir() {
                 out.join("red-green-refactor.yaml")
             } else {
                 out
             };
-            let cfg = OrchestratorConfig::example();
-            let s = serde_yaml::to_string(&cfg)?;
+            let cfg = OrchestratorConfig::example_for(&project);
+            let s = format!(
+                "# yaml-language-server: $schema={}\n{}",
+                orchestrator::CONFIG_SCHEMA_URL,
+                serde_yaml::to_string(&cfg)?
+            );
             std::fs::write(&path, s)?;
             println!("Wrote sample config to {}", path.display());
             Ok(())
         }
-        Commands::RunOnce => run(&cli.project, &cli.config, false).await,
-        Commands::Run => run(&cli.project, &cli.config, true).await,
+        Commands::PrintSchema => {
+            println!(
+                "{}",
+                serde_json::to_string_pretty(&OrchestratorConfig::json_schema())?
+            );
+            Ok(())
+        }
+        Commands::ValidateConfig { detect_language } => {
+            if detect_language {
+                let language = workspace::detect_language(&project);
+                println!(
+                    "Detected language: {} (default test_cmd: {})",
+                    language.as_str(),
+                    language.default_test_cmd().unwrap_or("none")
+                );
+            }
+            let cfg = load_config()?;
+            for phase in [Phase::Tester, Phase::Implementor, Phase::Refactorer] {
+                providers::ProviderFactory::build(&cfg.provider_config(phase))
+                    .with_context(|| format!("{} provider", phase.as_str()))?;
+            }
+            println!("Config OK (test_cmd: {})", cfg.test_cmd);
+            Ok(())
+        }
+        Commands::Replay { log } => {
+            let patch = providers::replay_from_log(&log)?;
+            println!("{}", serde_json::to_string_pretty(&patch)?);
+            Ok(())
+        }
+        Commands::InspectContext { role } => {
+            inspect_context(&project, load_config()?, role, cli.output_format).await
+        }
+        Commands::CleanupAttempts { older_than } => cleanup_attempts(&project, older_than).await,
+        Commands::CherryPick { commit } => {
+            vcs::cherry_pick(&project, &commit).await?;
+            println!("Applied {commit}");
+            Ok(())
+        }
+        Commands::ReplayAttempt { branch, onto } => replay_attempt(&project, &branch, onto).await,
+        Commands::SubmitBatch { role } => submit_batch(&project, load_config()?, role).await,
+        Commands::RetrieveBatch {
+            batch_id,
+            role,
+            save_to,
+        } => retrieve_batch(load_config()?, &batch_id, role, save_to.as_deref()).await,
+        Commands::Reset {
+            to,
+            keep_stash,
+            interactive,
+        } => reset(&project, to, keep_stash, interactive).await,
+        Commands::History {
+            last,
+            commits,
+            diff_attempts,
+        } => {
+            if commits {
+                commit_history(&project, last, cli.output_format).await
+            } else if diff_attempts {
+                attempt_diffs(&project, last, cli.output_format).await
+            } else {
+                history(&project, last, cli.output_format)
+            }
+        }
+        Commands::RunOnce => {
+            let targets = load_config()?.project_targets(&project, &cli.project_filter)?;
+            let annotations = cli.annotations.unwrap_or_else(AnnotationFormat::detect);
+            run(
+                targets,
+                RunMode::Once,
+                cli.cost_summary,
+                annotations,
+                resume,
+            )
+            .await
+        }
+        Commands::RunN {
+            count,
+            continue_on_error,
+        } => {
+            let targets = load_config()?.project_targets(&project, &cli.project_filter)?;
+            let annotations = cli.annotations.unwrap_or_else(AnnotationFormat::detect);
+            let mode = RunMode::Count {
+                count,
+                continue_on_error,
+            };
+            run(targets, mode, cli.cost_summary, annotations, resume).await
+        }
+        Commands::Run {
+            stop_when_tests_pass,
+        } => {
+            let targets = load_config()?.project_targets(&project, &cli.project_filter)?;
+            let annotations = cli.annotations.unwrap_or_else(AnnotationFormat::detect);
+            let mode = RunMode::Continuous {
+                stop_when_tests_pass,
+            };
+            run(targets, mode, cli.cost_summary, annotations, resume).await
+        }
+    };
+
+    // A temporary clone disappears on exit, so show what the session changed
+    if let Some(clone) = &clone
+        && let Some(start) = &clone.start_commit
+        && cycles_ran
+    {
+        println!("===== DIFF ({}) =====", clone.url);
+        print!("{}", vcs::diff_since(&project, start).await?);
     }
+    result
 }
 
-async fn run(project: &Path, config_path: &Option<PathBuf>, continuous: bool) -> Result<()> {
-    let cfg = orchestrator::load_orchestrator_config(config_path.as_ref())?;
-    let mut orch = Orchestrator::new(project.to_path_buf(), cfg).await?;
+/// A `--project` URL cloned for this session
+struct ClonedProject {
+    url: String,
+    /// HEAD right after cloning; `None` for an empty repository
+    start_commit: Option<String>,
+    /// Removed when dropped at the end of `main`, unless `--clone-to` was given
+    _temp_dir: Option<tempfile::TempDir>,
+}
 
-    if continuous {
-        loop {
-            orch.red_green_refactor_cycle().await?;
+async fn resolve_project(
+    project: PathBuf,
+    clone_to: Option<PathBuf>,
+) -> Result<(PathBuf, Option<ClonedProject>)> {
+    let url = project.to_string_lossy().into_owned();
+    if !workspace::is_remote_project(&url) {
+        return Ok((project, None));
+    }
+    let (dest, temp_dir) = match clone_to {
+        Some(dir) => (dir, None),
+        None => {
+            let temp_dir = tempfile::Builder::new().prefix("rgr-").tempdir()?;
+            (temp_dir.path().to_path_buf(), Some(temp_dir))
+        }
+    };
+    println!("Cloning {url} into {}", dest.display());
+    workspace::clone_project(&url, &dest).await?;
+    let start_commit = vcs::get_head_commit(&dest).await.ok();
+    Ok((
+        dest,
+        Some(ClonedProject {
+            url,
+            start_commit,
+            _temp_dir: temp_dir,
+        }),
+    ))
+}
+
+/// How many rounds of cycles `run` goes through
+enum RunMode {
+    Once,
+    Count {
+        count: usize,
+        continue_on_error: bool,
+    },
+    Continuous {
+        stop_when_tests_pass: bool,
+    },
+}
+
+/// Whether `run` starts from the saved snapshots, and with which config
+#[derive(Clone, Copy, PartialEq)]
+enum Resume {
+    No,
+    /// The snapshot's HEAD, with the config loaded now
+    KeepConfig,
+    /// The snapshot's HEAD and config
+    SnapshotConfig,
+}
+
+/// Run cycles over the targets in order, one round per `RunMode` step
+async fn run(
+    targets: Vec<ProjectTarget>,
+    mode: RunMode,
+    cost_summary: bool,
+    annotations: AnnotationFormat,
+    resume: Resume,
+) -> Result<()> {
+    let mut orchs = Vec::new();
+    for target in targets {
+        let snapshot = if resume == Resume::No {
+            None
+        } else {
+            state::load_snapshot(&target.root)?
+        };
+        let mut orch = match &snapshot {
+            Some(snapshot) if resume == Resume::SnapshotConfig => {
+                Orchestrator::from_snapshot(target.root, snapshot).await?
+            }
+            Some(snapshot) => {
+                let mut orch = Orchestrator::new(target.root, target.cfg).await?;
+                orch.restore(snapshot).await?;
+                orch
+            }
+            None => Orchestrator::new(target.root, target.cfg).await?,
+        };
+        match snapshot {
+            Some(snapshot) => println!(
+                "Resuming {} after {} recorded cycles",
+                target.name, snapshot.cycle_count
+            ),
+            None if resume != Resume::No => {
+                println!("No snapshot to resume {} from", target.name)
+            }
+            None => {}
         }
+        orch.set_annotations(annotations);
+        orchs.push((target.name, orch));
+    }
+
+    let mut summaries = Vec::new();
+    let result = match mode {
+        RunMode::Once => run_round(&mut orchs, false, &mut summaries)
+            .await
+            .map(|_| ()),
+        RunMode::Count {
+            count,
+            continue_on_error,
+        } => {
+            tokio::select! {
+                result = run_n(&mut orchs, count, continue_on_error, &mut summaries) => result,
+                _ = tokio::signal::ctrl_c() => {
+                    println!("Interrupted");
+                    print_cycle_stats(&summaries);
+                    Ok(())
+                }
+            }
+        }
+        RunMode::Continuous {
+            stop_when_tests_pass,
+        } => {
+            tokio::select! {
+                result = run_until_done(&mut orchs, stop_when_tests_pass, &mut summaries) => result,
+                _ = tokio::signal::ctrl_c() => {
+                    println!("Interrupted");
+                    print_cycle_stats(&summaries);
+                    Ok(())
+                }
+            }
+        }
+    };
+    if cost_summary {
+        let total: f64 = orchs.iter().map(|(_, orch)| orch.total_cost_usd()).sum();
+        println!("Estimated total cost: ${total:.4}");
+    }
+    result
+}
+
+async fn run_until_done(
+    orchs: &mut [(String, Orchestrator)],
+    stop_when_tests_pass: bool,
+    summaries: &mut Vec<CycleSummary>,
+) -> Result<()> {
+    while run_round(orchs, stop_when_tests_pass, summaries).await? {}
+    println!("Kata complete: all tests pass");
+    print_cycle_stats(summaries);
+    Ok(())
+}
+
+/// Averages over the cycles that ran to completion (green or not)
+fn print_cycle_stats(summaries: &[CycleSummary]) {
+    if summaries.is_empty() {
+        return;
+    }
+    let n = summaries.len() as u32;
+    let green = summaries.iter().filter(|s| s.success).count();
+    let total: Duration = summaries.iter().map(|s| s.total_duration).sum();
+    let phase = |i: usize| {
+        let sum: Duration = summaries.iter().map(|s| s.phase_durations[i]).sum();
+        (sum / n).as_secs_f64()
+    };
+    let attempts: usize = summaries.iter().map(|s| s.implementor_attempts).sum();
+    println!(
+        "{n} cycles completed ({green} green); average {:.1}s (tester {:.1}s, implementor {:.1}s, refactorer {:.1}s), {:.1} implementor attempts",
+        (total / n).as_secs_f64(),
+        phase(0),
+        phase(1),
+        phase(2),
+        attempts as f64 / n as f64
+    );
+}
+
+/// `count` rounds, then a tally of the cycles that reached green
+async fn run_n(
+    orchs: &mut [(String, Orchestrator)],
+    count: usize,
+    continue_on_error: bool,
+    summaries: &mut Vec<CycleSummary>,
+) -> Result<()> {
+    let multi = orchs.len() > 1;
+    let (mut attempted, mut succeeded) = (0, 0);
+    let mut result = Ok(());
+    'rounds: for _ in 0..count {
+        for (name, orch) in orchs.iter_mut() {
+            attempted += 1;
+            match run_cycle(name, orch, multi).await {
+                Ok(summary) => {
+                    if summary.success {
+                        succeeded += 1;
+                    }
+                    summaries.push(summary);
+                }
+                // Running out of budget ends the run whatever `continue_on_error` says
+                Err(e) if continue_on_error && !e.is::<BudgetExceededError>() => {
+                    eprintln!("Cycle failed: {e:#}")
+                }
+                Err(e) => {
+                    result = Err(e);
+                    break 'rounds;
+                }
+            }
+        }
+    }
+    println!(
+        "{attempted} cycles attempted, {succeeded} succeeded, {} failed",
+        attempted - succeeded
+    );
+    result
+}
+
+/// One cycle per project; returns whether any project still needed one
+async fn run_round(
+    orchs: &mut [(String, Orchestrator)],
+    skip_green: bool,
+    summaries: &mut Vec<CycleSummary>,
+) -> Result<bool> {
+    let multi = orchs.len() > 1;
+    let mut ran = false;
+    for (name, orch) in orchs.iter_mut() {
+        if skip_green && orch.tests_pass().await? {
+            continue;
+        }
+        ran = true;
+        summaries.push(run_cycle(name, orch, multi).await?);
+    }
+    Ok(ran)
+}
+
+async fn run_cycle(name: &str, orch: &mut Orchestrator, multi: bool) -> Result<CycleSummary> {
+    let summary = if multi {
+        orch.red_green_refactor_cycle()
+            .instrument(tracing::info_span!("rgr.project", project = %name))
+            .await?
+    } else {
+        orch.red_green_refactor_cycle().await?
+    };
+    if multi {
+        println!("[{name}] {summary}");
     } else {
-        orch.red_green_refactor_cycle().await
+        println!("{summary}");
+    }
+    Ok(summary)
+}
+
+async fn cleanup_attempts(project: &Path, older_than_days: Option<u64>) -> Result<()> {
+    let cutoff = older_than_days
+        .map(|days| {
+            i64::try_from(days)
+                .ok()
+                .and_then(chrono::Duration::try_days)
+                .and_then(|age| chrono::Utc::now().checked_sub_signed(age))
+                .map(|cutoff| cutoff.timestamp())
+                .ok_or_else(|| anyhow::anyhow!("--older-than {days} is out of range"))
+        })
+        .transpose()?;
+    let current = vcs::current_branch(project).await.ok();
+    let mut deleted = 0;
+    for branch in vcs::list_branches_by_pattern(project, "attempts/implementor-*").await? {
+        if current.as_deref() == Some(branch.as_str()) {
+            println!("Skipping {branch} (checked out)");
+            continue;
+        }
+        if let Some(cutoff) = cutoff
+            && vcs::commit_timestamp(project, &branch).await? >= cutoff
+        {
+            continue;
+        }
+        vcs::delete_branch(project, &branch).await?;
+        println!("Deleted {branch}");
+        deleted += 1;
+    }
+    println!("Deleted {deleted} attempt branch(es)");
+    Ok(())
+}
+
+async fn replay_attempt(project: &Path, branch: &str, onto: Option<String>) -> Result<()> {
+    if !vcs::branch_exists(project, branch).await? {
+        return Err(anyhow::anyhow!("no branch named {branch}"));
+    }
+    if !vcs::is_working_tree_clean(project).await? {
+        return Err(anyhow::anyhow!(
+            "the working tree has uncommitted changes; commit or stash them first"
+        ));
+    }
+    let tester_sha = match onto {
+        Some(onto) => onto,
+        None => vcs::list_rgr_commits(project, usize::MAX)
+            .await?
+            .into_iter()
+            .find(|c| c.phase == Phase::Tester)
+            .map(|c| c.hash)
+            .ok_or_else(|| anyhow::anyhow!("no tester commit reachable from HEAD; pass --onto"))?,
+    };
+    let previous = vcs::current_branch(project).await.ok();
+    vcs::rebase_attempts_onto_tester(project, branch, &tester_sha).await?;
+    println!(
+        "Checked out {branch} rebased onto {}",
+        &tester_sha[..tester_sha.len().min(8)]
+    );
+    if let Some(previous) = previous {
+        println!("Run `git checkout {previous}` to go back");
+    }
+    Ok(())
+}
+
+async fn submit_batch(project: &Path, cfg: OrchestratorConfig, role: Phase) -> Result<()> {
+    let (context, instructions) = orchestrator::role_prompt(project, &cfg, role).await?;
+    let provider = OpenAiBatchProvider::new(cfg.provider_config(role))?;
+    let batch_id = provider
+        .submit(role.as_str(), &context, &instructions)
+        .await?;
+    println!("{batch_id}");
+    eprintln!(
+        "Run `rgr retrieve-batch {batch_id} --role {}` to check on it",
+        role.as_str()
+    );
+    Ok(())
+}
+
+async fn retrieve_batch(
+    cfg: OrchestratorConfig,
+    batch_id: &str,
+    role: Phase,
+    save_to: Option<&Path>,
+) -> Result<()> {
+    let provider = OpenAiBatchProvider::new(cfg.provider_config(role))?;
+    let status = provider.status(batch_id).await?;
+    if !status.is_finished() {
+        println!("Batch {batch_id} is {}", status.status);
+        return Ok(());
+    }
+    let patch = serde_json::to_string_pretty(&provider.results(status).await?)?;
+    let Some(dir) = save_to else {
+        println!("{patch}");
+        return Ok(());
+    };
+    std::fs::create_dir_all(dir)?;
+    // After the patches already queued for this role, which `local_file` replays in order
+    let suffix = format!("-{}.json", role.as_str());
+    let mut next = 0;
+    for entry in std::fs::read_dir(dir)? {
+        let name = entry?.file_name();
+        if let Some(index) = name
+            .to_str()
+            .and_then(|n| n.strip_suffix(&suffix))
+            .and_then(|n| n.parse::<usize>().ok())
+        {
+            next = next.max(index + 1);
+        }
+    }
+    let path = dir.join(format!("{next:04}{suffix}"));
+    std::fs::write(&path, patch)?;
+    println!("Saved the patch as {}", path.display());
+    Ok(())
+}
+
+async fn inspect_context(
+    project: &Path,
+    cfg: OrchestratorConfig,
+    role: Phase,
+    format: OutputFormat,
+) -> Result<()> {
+    let preview = orchestrator::preview_prompt(project, &cfg, role).await?;
+    match format {
+        OutputFormat::Json => println!("{}", serde_json::to_string_pretty(&preview)?),
+        OutputFormat::Text => {
+            println!(
+                "===== SYSTEM ({}, model {}) =====",
+                role.as_str(),
+                preview.model
+            );
+            println!("{}", preview.system_prompt);
+            println!("===== USER =====");
+            println!("{}", preview.user_prompt);
+            println!("===== ESTIMATE =====");
+            println!("~{} tokens", preview.estimated_tokens);
+            println!("===== DONE SO FAR =====");
+            for c in &preview.recent_commits {
+                println!("{:<8} {:<11} {}", &c.hash[..8], c.phase.as_str(), c.subject);
+            }
+        }
+    }
+    Ok(())
+}
+
+async fn reset(
+    project: &Path,
+    to: Option<String>,
+    keep_stash: bool,
+    interactive: bool,
+) -> Result<()> {
+    let target = match to {
+        Some(target) => target,
+        None => {
+            let recent: Vec<String> = vcs::list_tags(project, "rgr/*")
+                .await?
+                .into_iter()
+                .take(10)
+                .collect();
+            let default = vcs::list_tags(project, &format!("{}*", orchestrator::CYCLE_TAG_PREFIX))
+                .await?
+                .into_iter()
+                .next();
+            println!("Recent rgr tags:");
+            for (i, tag) in recent.iter().enumerate() {
+                println!("  [{}] {}", i + 1, tag);
+            }
+            if interactive {
+                prompt_reset_target(&recent, default)?
+            } else {
+                default.ok_or_else(|| {
+                    anyhow::anyhow!("no rgr/cycle-* tag found; pass --to <commit-ish>")
+                })?
+            }
+        }
+    };
+
+    if keep_stash {
+        vcs::stash_push(project, "rgr reset").await?;
+    }
+    // Untracked files here are the user's; --keep-stash already sets them aside
+    vcs::reset_hard_to(project, &target, None).await?;
+    let state = project.join(state::STATE_FILE);
+    if state.exists() {
+        std::fs::remove_file(&state)?;
+    }
+    println!("Reset {} to {}", project.display(), target);
+    Ok(())
+}
+
+fn prompt_reset_target(recent: &[String], default: Option<String>) -> Result<String> {
+    print!(
+        "Reset to [number or commit-ish]{}: ",
+        default
+            .as_deref()
+            .map(|d| format!(" (default {d})"))
+            .unwrap_or_default()
+    );
+    std::io::stdout().flush()?;
+    let mut line = String::new();
+    std::io::stdin().read_line(&mut line)?;
+    let answer = line.trim();
+    if answer.is_empty() {
+        return default.ok_or_else(|| anyhow::anyhow!("no target selected"));
+    }
+    match answer.parse::<usize>() {
+        Ok(n) if (1..=recent.len()).contains(&n) => Ok(recent[n - 1].clone()),
+        _ => Ok(answer.to_string()),
+    }
+}
+
+fn history(project: &Path, last: Option<usize>, format: OutputFormat) -> Result<()> {
+    let mut records = state::load_records(project)?;
+    if let Some(n) = last {
+        records = records.split_off(records.len().saturating_sub(n));
+    }
+    if format == OutputFormat::Json {
+        println!("{}", serde_json::to_string_pretty(&records)?);
+        return Ok(());
+    }
+    if records.is_empty() {
+        println!("No cycles recorded yet in {}", state::STATE_FILE);
+        return Ok(());
+    }
+    println!(
+        "{:>5}  {:<20}  {:<7}  {:<12}  {:>8}  {:>6}  {:<8}  {:<8}",
+        "cycle", "started", "result", "reached", "attempts", "passed", "tester", "refactor"
+    );
+    let short = |c: &Option<String>| {
+        c.as_deref()
+            .map_or("-".to_string(), |c| c.chars().take(8).collect())
+    };
+    for r in &records {
+        println!(
+            "{:>5}  {:<20}  {:<7}  {:<12}  {:>8}  {:>6}  {:<8}  {:<8}",
+            r.cycle_number,
+            r.started_at.format("%Y-%m-%d %H:%M:%S"),
+            if r.success { "ok" } else { "failed" },
+            r.phase_reached.as_str(),
+            r.implementor_attempts,
+            r.test_counts
+                .map_or("-".to_string(), |c| c.passed.to_string()),
+            short(&r.tester_commit),
+            short(&r.refactor_commit),
+        );
+    }
+    Ok(())
+}
+
+/// Per attempts branch (the last `last` of them), each attempt's commit diff
+async fn attempt_diffs(project: &Path, last: Option<usize>, format: OutputFormat) -> Result<()> {
+    let mut branches = vcs::list_branches_by_pattern(project, "attempts/implementor-*").await?;
+    if let Some(n) = last {
+        branches = branches.split_off(branches.len().saturating_sub(n));
+    }
+    let mut report = Vec::new();
+    for branch in branches {
+        let mut attempts = Vec::new();
+        for (commit, subject) in vcs::commits_not_on_head(project, &branch).await? {
+            let diff = vcs::commit_diff(project, &commit).await?;
+            attempts.push(serde_json::json!({"commit": commit, "subject": subject, "diff": diff}));
+        }
+        report.push(serde_json::json!({"branch": branch, "attempts": attempts}));
+    }
+    if format == OutputFormat::Json {
+        println!("{}", serde_json::to_string_pretty(&report)?);
+        return Ok(());
+    }
+    if report.is_empty() {
+        println!("No attempts/implementor-* branches found");
+        return Ok(());
+    }
+    for entry in &report {
+        println!(
+            "===== {} =====",
+            entry["branch"].as_str().unwrap_or_default()
+        );
+        for attempt in entry["attempts"].as_array().into_iter().flatten() {
+            let commit = attempt["commit"].as_str().unwrap_or_default();
+            println!(
+                "--- {} {}",
+                commit.get(..8).unwrap_or(commit),
+                attempt["subject"].as_str().unwrap_or_default()
+            );
+            print!("{}", attempt["diff"].as_str().unwrap_or_default());
+        }
+    }
+    Ok(())
+}
+
+async fn commit_history(project: &Path, last: Option<usize>, format: OutputFormat) -> Result<()> {
+    let commits = vcs::list_rgr_commits(project, last.unwrap_or(usize::MAX)).await?;
+    if format == OutputFormat::Json {
+        println!("{}", serde_json::to_string_pretty(&commits)?);
+        return Ok(());
+    }
+    if commits.is_empty() {
+        println!("No test:/feat:/refactor: commits found");
+        return Ok(());
+    }
+    for c in &commits {
+        println!(
+            "{:<8}  {:<25}  {:<11}  {}",
+            &c.hash[..8],
+            c.date,
+            c.phase.as_str(),
+            c.subject
+        );
     }
+    Ok(())
 }
@@ -1,58 +1,911 @@
-use crate::providers::{LlmProvider, ProviderFactory, RoleProviderConfig};
+use crate::annotations::AnnotationFormat;
+use crate::providers::cost_tracker::{self, BudgetExceededError, CostTracker, TokenPricing};
+use crate::providers::response_validator;
+use crate::providers::{
+    self, InstructionFormat, LlmPatch, LlmProvider, ProviderFactory, RoleProviderConfig,
+};
+use crate::state::{self, CycleRecord};
+use crate::test_runner::{TestCounts, TestReport, TestRunner};
 use crate::vcs;
-use crate::workspace;
-use anyhow::{anyhow, Context, Result};
+use crate::workspace::{self, TestResult};
+use anyhow::{Context, Result, anyhow};
+use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
-use std::path::PathBuf;
-use tracing::{info, warn};
+use std::collections::HashMap;
+use std::io::{IsTerminal, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant};
+use tracing::field::Empty;
+use tracing::{Instrument, Span, debug, info, info_span, instrument, warn};
 
+/// Successful cycles are tagged `rgr/cycle-NNNN` so they can be used as reset points
+pub const CYCLE_TAG_PREFIX: &str = "rgr/cycle-";
+/// Cap on the implementor failures kept per cycle record for later tester prompts
+const RECENT_FAILURES_MAX_BYTES: usize = 2_000;
+/// Refactors changing fewer lines than this are folded into the green commit with
+/// `amend_trivial_refactors`
+const TRIVIAL_REFACTOR_MAX_LINES: usize = 5;
+/// Worktree directory (under the project) and branch cycles run on with `use_worktree`
+const WORKTREE_DIR: &str = ".rgr-work";
+const WORKTREE_BRANCH: &str = "rgr/work";
+/// Where `init-config` points editors for the schema printed by `print-schema`
+pub const CONFIG_SCHEMA_URL: &str = "https://raw.githubusercontent.com/xpepper/red-green-refactor/main/red-green-refactor.schema.json";
+
+/// The three roles of the TDD trio, in cycle order
+#[derive(
+    Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize, JsonSchema, clap::ValueEnum,
+)]
+#[serde(rename_all = "snake_case")]
+pub enum Phase {
+    Tester,
+    Implementor,
+    Refactorer,
+}
+
+impl Phase {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Phase::Tester => "tester",
+            Phase::Implementor => "implementor",
+            Phase::Refactorer => "refactorer",
+        }
+    }
+}
+
+/// What one cycle did, for callers that want more than the log
+#[derive(Debug, Clone)]
+pub struct CycleSummary {
+    pub cycle_number: usize,
+    /// False when every implementor attempt failed and the cycle was reset to red
+    pub success: bool,
+    pub tester_commit: Option<String>,
+    pub green_commit: Option<String>,
+    pub refactor_commit: Option<String>,
+    pub implementor_attempts: usize,
+    pub total_duration: Duration,
+    /// Tester, implementor and refactorer time, in that order
+    pub phase_durations: [Duration; 3],
+    /// Test totals once the cycle went green
+    pub test_counts: Option<TestCounts>,
+    /// Estimated spend of the run so far
+    pub total_cost_usd: f64,
+    /// What is left of `budget_limit_usd`, when one is set
+    pub remaining_budget_usd: Option<f64>,
+}
+
+impl std::fmt::Display for CycleSummary {
+    /// `Cycle 3 green in 41.2s (red a1b2c3d, green e4f5a6b, refactor 9c8d7e6; 2 implementor attempts; 12 tests passing)`
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let short = |commit: &Option<String>| {
+            commit
+                .as_deref()
+                .map_or("-".to_string(), |c| c.chars().take(7).collect())
+        };
+        write!(
+            f,
+            "Cycle {} {} in {:.1}s (red {}, green {}, refactor {}; {} implementor attempts",
+            self.cycle_number,
+            if self.success { "green" } else { "failed" },
+            self.total_duration.as_secs_f64(),
+            short(&self.tester_commit),
+            short(&self.green_commit),
+            short(&self.refactor_commit),
+            self.implementor_attempts
+        )?;
+        if let Some(counts) = self.test_counts {
+            write!(f, "; {} tests passing", counts.passed)?;
+        }
+        if let Some(remaining) = self.remaining_budget_usd {
+            write!(
+                f,
+                "; ${:.4} spent, ${:.4} budget left",
+                self.total_cost_usd,
+                remaining.max(0.0)
+            )?;
+        }
+        write!(f, ")")
+    }
+}
+
+/// Where an orchestrator stands after a cycle, saved in `.rgr-state.json` so a crashed run
+/// can be resumed (`--resume`) or two points in a run compared
 #[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct OrchestratorSnapshot {
+    pub taken_at: chrono::DateTime<chrono::Utc>,
+    pub config: OrchestratorConfig,
+    /// Cycles recorded so far, successful or not
+    pub cycle_count: usize,
+    /// HEAD when the snapshot was taken
+    pub head: Option<String>,
+    pub last_tester_commit: Option<String>,
+    pub last_green_commit: Option<String>,
+    pub last_refactor_commit: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+#[schemars(title = "red-green-refactor config")]
 pub struct OrchestratorConfig {
     pub tester: RoleProviderConfig,
     pub implementor: RoleProviderConfig,
     pub refactorer: RoleProviderConfig,
+    /// Well-known kata being worked on (e.g. `Bowling Game`): named in every role's prompt,
+    /// the cycle records and an `Rgr-Kata` commit trailer
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub kata_name: Option<String>,
     #[serde(default = "default_test_cmd")]
     pub test_cmd: String,
+    /// Test output longer than this keeps only its first and last halves in prompts
+    #[serde(default = "default_max_test_output")]
+    pub max_test_output_bytes: usize,
+    /// Re-run a failing suite this many more times before trusting the failure (flaky tests)
+    #[serde(default)]
+    pub flaky_test_retries: usize,
+    /// Fail a test run that takes longer than this (SIGTERM, then SIGKILL after a grace
+    /// period); `null` lets it run forever
+    #[serde(default = "default_test_timeout_secs")]
+    pub test_timeout_secs: Option<u64>,
+    /// Show each test run in color on the terminal, from a second `--color always` run of a
+    /// `test_cmd` with `--color never` (the LLM still gets the plain output)
+    #[serde(default = "default_true")]
+    pub display_test_output: bool,
+    /// How to read `test_cmd` output (`cargo`, `pytest` or `generic`) for structured failures
+    #[serde(default)]
+    pub test_runner: TestRunner,
+    /// Lists existing tests for the tester (`<name>: test` lines); defaults to
+    /// `cargo test -- --list` in Rust projects, `""` disables
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub list_tests_cmd: Option<String>,
     #[serde(default = "default_max_context")]
     pub max_context_bytes: usize,
+    /// Send files changed since the previous cycle first, then the most recently modified others
+    #[serde(default)]
+    pub incremental_context: bool,
+    /// Files larger than this are truncated in the context so one file can't crowd out the rest
+    #[serde(default = "default_max_context_file")]
+    pub max_context_file_bytes: usize,
+    /// Same cap for files under `tests/`, kept higher since tests are the spec
+    #[serde(default = "default_max_context_test_file")]
+    pub max_context_test_file_bytes: usize,
+    /// Phases whose patch is shown for approval (commit / discard / edit) before committing;
+    /// ignored when stdin is not a terminal
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub interactive_phases: Vec<Phase>,
+    /// Linter run once the implementor's tests pass (e.g. `cargo clippy --all-targets -- -D warnings`)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub lint_cmd: Option<String>,
+    /// Treat a `lint_cmd` failure as a failed implementor attempt instead of only warning
+    #[serde(default)]
+    pub lint_is_hard_gate: bool,
+    /// `cargo check` implementor and refactorer patches on a scratch copy of the project
+    /// before committing them; a patch that doesn't compile is retried with the errors
+    #[serde(default)]
+    pub pre_commit_compile_check: bool,
+    /// Reject a tester patch adding more lines of test code than this (retried once); 0 disables
+    #[serde(default = "default_tester_max_test_lines")]
+    pub tester_max_test_lines: usize,
+    /// Show the tester the test failures the implementor ran into during this many recent
+    /// cycles (from `.rgr-state.json`), so it can pick an easier next step; 0 disables
+    #[serde(default = "default_tester_recent_failure_cycles")]
+    pub tester_recent_failure_cycles: usize,
     #[serde(default = "default_impl_attempts")]
     pub implementor_max_attempts: usize,
+    /// Wait before implementor retries, doubling each time (1s, 2s, 4s, ...); 0 disables
+    #[serde(default = "default_impl_backoff_base_ms")]
+    pub implementor_backoff_base_ms: u64,
+    /// Context files matching earlier prefixes are sent first (tests before implementation)
+    #[serde(default = "default_context_priority_prefixes")]
+    pub context_priority_prefixes: Vec<String>,
+    /// Drop comments from Rust files in the context to save tokens
+    #[serde(default)]
+    pub strip_comments_from_context: bool,
+    /// Flag Rust files in the context that don't parse (checked with `rustfmt`, which is slow
+    /// on large projects); the implementor is then told to fix them first
+    #[serde(default)]
+    pub annotate_syntax_errors_in_context: bool,
+    /// Open the context with a "Project hints" section listing the `// TODO`, `// FIXME`,
+    /// `// HACK` and `// XXX` comments of the project's files, with their location
+    #[serde(default = "default_true")]
+    pub include_todos_in_context: bool,
+    /// With `strip_comments_from_context`, keep doc comments on `pub` items (their API contract)
+    #[serde(default = "default_true")]
+    pub keep_pub_doc_comments: bool,
+    /// File-name globs never included in the context (`.env`, keys, credentials, ...)
+    #[serde(default = "default_context_secret_exclude_patterns")]
+    pub context_secret_exclude_patterns: Vec<String>,
+    /// Run each cycle on `rgr/cycle-NNNN-*` branches and merge it back with `--no-ff`
+    #[serde(default)]
+    pub per_cycle_branch: bool,
+    /// Run each cycle in a `.rgr-work/` worktree on the `rgr/work` branch, leaving the main
+    /// worktree to you; merge `rgr/work` to pick up the cycles
+    #[serde(default)]
+    pub use_worktree: bool,
+    /// After a green cycle, check `verify_cycle_postconditions`; if one fails, reset to the
+    /// commit the cycle started from and run it once more
+    #[serde(default)]
+    pub cycle_retry_on_state_error: bool,
+    /// Skip the refactorer when the green phase added fewer lines than this (0 = always refactor)
+    #[serde(default)]
+    pub refactor_skip_threshold: usize,
+    /// Fold a refactor changing fewer than 5 lines into the green commit (amending it)
+    /// instead of committing it separately; ignored with `per_cycle_branch`, whose
+    /// `-green` branch must keep pointing at the green commit
+    #[serde(default)]
+    pub amend_trivial_refactors: bool,
+    /// Member projects (paths relative to `--project`) to cycle through in order; empty means
+    /// the `--project` directory itself
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub projects: Vec<ProjectConfig>,
+    /// Refuse to start a cycle on a dirty working tree instead of only warning
+    #[serde(default)]
+    pub require_clean_tree: bool,
+    /// Commit identity for the kata repo (stored in its local git config)
+    #[serde(default)]
+    pub git_author_name: Option<String>,
+    #[serde(default)]
+    pub git_author_email: Option<String>,
+    /// Extra local git config for the kata repo, set before every cycle (e.g.
+    /// `commit.gpgSign: "false"` where CI has no signing key)
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub git_config: HashMap<String, String>,
+    /// Conventional-commit prefix per role for commits whose patch brings no message of its
+    /// own (e.g. `implementor: "feat(kata):"`); defaults to `test:`, `feat:` and `refactor:`.
+    /// `history --commits` only recognizes subjects starting with `test`, `feat` or `refactor`
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    #[schemars(schema_with = "commit_message_prefix_schema")]
+    pub commit_message_prefix: HashMap<Phase, String>,
+    /// Shell command run before each cycle; a failure aborts the cycle
+    #[serde(default)]
+    pub pre_cycle_hook: Option<String>,
+    /// Shell command run after each cycle, successful or not; a failure is only logged
+    #[serde(default)]
+    pub post_cycle_hook: Option<String>,
+    /// Globs the LLM may never modify; patches touching them are rejected
+    #[serde(default = "default_protected_files")]
+    pub protected_files: Vec<String>,
+    /// Reject patches touching more files than this, a sign the model left the baby-step path (0 = unlimited)
+    #[serde(default = "default_max_patch_files")]
+    pub max_patch_files: usize,
+    /// Back up files to `.rgr-backups/<timestamp>/` (with a `restore.sh`) before patching
+    #[serde(default)]
+    pub keep_backups: bool,
+    /// When an `insert_after_pattern` edit finds no matching line: `fail` the patch or `append`
+    #[serde(default)]
+    pub insert_pattern_missing: workspace::MissingPattern,
+    /// Keep `\r\n` line endings in LLM edits (by default they are written as `\n`)
+    #[serde(default)]
+    pub preserve_crlf: bool,
+    /// Warn when the estimated spend of a single cycle exceeds this amount
+    #[serde(default)]
+    pub max_cost_per_cycle_usd: Option<f64>,
+    /// Stop the run once the estimated total spend exceeds this amount (checked after every
+    /// phase and implementor attempt; the cycle it stops is rolled back; see also
+    /// `--budget-limit-usd`)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub budget_limit_usd: Option<f64>,
+    /// Warn once the spend reaches this percentage of `budget_limit_usd`
+    #[serde(default = "default_budget_alert_at_pct")]
+    pub budget_alert_at_pct: f64,
+    /// Default `seed` for every provider, for more reproducible runs (providers can override it)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub llm_seed: Option<u64>,
+    /// Per-model pricing (USD per million tokens), overriding the built-in table
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub model_pricing: HashMap<String, TokenPricing>,
+    /// Use the mock provider for any role whose provider can't be built (e.g. its API key
+    /// is not set), with a warning, instead of failing
+    #[serde(default)]
+    pub fallback_to_mock: bool,
+    /// Response format every role is asked for: `llm_patch_json` (default), `unified_diff`
+    /// (applied hunk by hunk), or `{custom: "<description of the format>"}`
+    #[serde(default)]
+    pub role_instruction_format: InstructionFormat,
 }
 
 fn default_test_cmd() -> String {
     "cargo test --color never".to_string()
 }
+fn default_test_timeout_secs() -> Option<u64> {
+    Some(600)
+}
+fn default_max_test_output() -> usize {
+    20_000
+}
 fn default_max_context() -> usize {
     200_000
 }
+fn default_max_context_file() -> usize {
+    50_000
+}
+fn default_max_context_test_file() -> usize {
+    200_000
+}
 fn default_impl_attempts() -> usize {
     3
 }
+fn default_impl_backoff_base_ms() -> u64 {
+    1000
+}
+fn default_protected_files() -> Vec<String> {
+    ["Cargo.lock", ".git/**"].map(String::from).to_vec()
+}
+fn default_max_patch_files() -> usize {
+    10
+}
+fn default_tester_max_test_lines() -> usize {
+    50
+}
+fn default_tester_recent_failure_cycles() -> usize {
+    1
+}
+
+fn default_context_secret_exclude_patterns() -> Vec<String> {
+    [
+        ".env",
+        ".env.*",
+        "*.pem",
+        "*.key",
+        "*secret*",
+        "*credential*",
+    ]
+    .map(String::from)
+    .to_vec()
+}
+
+/// `commit_message_prefix` keys are role names, which the derived map schema doesn't restrict
+fn commit_message_prefix_schema(
+    generator: &mut schemars::r#gen::SchemaGenerator,
+) -> schemars::schema::Schema {
+    let mut schema = generator.subschema_for::<HashMap<String, String>>();
+    if let schemars::schema::Schema::Object(object) = &mut schema {
+        let roles: Vec<_> = [Phase::Tester, Phase::Implementor, Phase::Refactorer]
+            .iter()
+            .map(|phase| phase.as_str().into())
+            .collect();
+        object.object().property_names = Some(Box::new(
+            schemars::schema::SchemaObject {
+                enum_values: Some(roles),
+                ..Default::default()
+            }
+            .into(),
+        ));
+    }
+    schema
+}
+
+fn default_true() -> bool {
+    true
+}
+
+fn default_budget_alert_at_pct() -> f64 {
+    80.0
+}
+
+fn default_context_priority_prefixes() -> Vec<String> {
+    ["tests/", "src/lib.rs", "src/main.rs", "src/"]
+        .map(String::from)
+        .to_vec()
+}
+
+/// One member of a multi-project (e.g. Cargo workspace) setup
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+pub struct ProjectConfig {
+    /// Used by `--project-filter`; defaults to the last component of `path`
+    #[serde(default)]
+    pub name: Option<String>,
+    pub path: PathBuf,
+    #[serde(default)]
+    pub test_cmd: Option<String>,
+    #[serde(default)]
+    pub roles: Option<RoleOverrides>,
+}
+
+impl ProjectConfig {
+    pub fn name(&self) -> String {
+        self.name.clone().unwrap_or_else(|| {
+            self.path.file_name().map_or_else(
+                || self.path.display().to_string(),
+                |n| n.to_string_lossy().into_owned(),
+            )
+        })
+    }
+}
+
+/// Per-project replacements for the top-level role settings
+#[derive(Debug, Clone, Default, Serialize, Deserialize, JsonSchema)]
+pub struct RoleOverrides {
+    #[serde(default)]
+    pub tester: Option<RoleProviderConfig>,
+    #[serde(default)]
+    pub implementor: Option<RoleProviderConfig>,
+    #[serde(default)]
+    pub refactorer: Option<RoleProviderConfig>,
+}
+
+/// A project directory to run cycles in, with its effective config
+pub struct ProjectTarget {
+    pub name: String,
+    pub root: PathBuf,
+    pub cfg: OrchestratorConfig,
+}
 
 impl OrchestratorConfig {
+    /// Resolve `projects` against `root`, keeping only those named in `filter` (if non-empty)
+    pub fn project_targets(&self, root: &Path, filter: &[String]) -> Result<Vec<ProjectTarget>> {
+        if self.projects.is_empty() {
+            let name = root
+                .canonicalize()
+                .ok()
+                .and_then(|p| p.file_name().map(|n| n.to_string_lossy().into_owned()))
+                .unwrap_or_else(|| root.display().to_string());
+            return Ok(vec![ProjectTarget {
+                name,
+                root: root.to_path_buf(),
+                cfg: self.clone(),
+            }]);
+        }
+        let names: Vec<String> = self.projects.iter().map(ProjectConfig::name).collect();
+        if let Some(unknown) = filter.iter().find(|f| !names.contains(f)) {
+            return Err(anyhow!(
+                "--project-filter: no project named {unknown} (known: {})",
+                names.join(", ")
+            ));
+        }
+        Ok(self
+            .projects
+            .iter()
+            .zip(names)
+            .filter(|(_, name)| filter.is_empty() || filter.contains(name))
+            .map(|(project, name)| {
+                let mut cfg = self.clone();
+                cfg.projects.clear();
+                if let Some(test_cmd) = &project.test_cmd {
+                    cfg.test_cmd = test_cmd.clone();
+                }
+                if let Some(roles) = &project.roles {
+                    let RoleOverrides {
+                        tester,
+                        implementor,
+                        refactorer,
+                    } = roles.clone();
+                    cfg.tester = tester.unwrap_or(cfg.tester);
+                    cfg.implementor = implementor.unwrap_or(cfg.implementor);
+                    cfg.refactorer = refactorer.unwrap_or(cfg.refactorer);
+                }
+                ProjectTarget {
+                    name,
+                    root: root.join(&project.path),
+                    cfg,
+                }
+            })
+            .collect())
+    }
+
     pub fn example() -> Self {
-        Self {
-            tester: RoleProviderConfig {
-                provider: crate::providers::ProviderConfig { kind: crate::providers::ProviderKind::Mock, model: "mock".into(), base_url: None, api_key_env: None, organization: None, api_key_header: None, api_key_prefix: None },
-                system_prompt: Some("Read docs/kata-rules.md. You are the Tester. Add a single failing test expressing the next small behavior per the rules. Only output a JSON LlmPatch.".into()),
-            },
-            implementor: RoleProviderConfig {
-                provider: crate::providers::ProviderConfig { kind: crate::providers::ProviderKind::Mock, model: "mock".into(), base_url: None, api_key_env: None, organization: None, api_key_header: None, api_key_prefix: None },
-                system_prompt: Some("Read docs/kata-rules.md. You are the Implementor. Make tests pass with minimal changes. Only output a JSON LlmPatch.".into())
-            },
-            refactorer: RoleProviderConfig {
-                provider: crate::providers::ProviderConfig { kind: crate::providers::ProviderKind::Mock, model: "mock".into(), base_url: None, api_key_env: None, organization: None, api_key_header: None, api_key_prefix: None },
-                system_prompt: Some("Read docs/kata-rules.md. You are the Refactorer. Improve code without changing behavior. Keep tests passing. Only output a JSON LlmPatch.".into())
+        Self::example_builder()
+            .build()
+            .expect("the example config is complete")
+    }
+
+    /// Mock providers for every role, with prompts to start from
+    fn example_builder() -> OrchestratorConfigBuilder {
+        let role = |system_prompt: &str| RoleProviderConfig {
+            // An open model on Together AI instead (reads TOGETHER_API_KEY):
+            // provider: ProviderConfig { kind: ProviderKind::TogetherAi, model: "meta-llama/Llama-3-70b-chat-hf".into(), ..Default::default() },
+            // Or xAI's Grok (reads GROK_API_KEY, base_url defaults to https://api.x.ai/v1):
+            // provider: ProviderConfig { kind: ProviderKind::Xai, model: "grok-2-latest".into(), ..Default::default() },
+            provider: providers::ProviderConfig {
+                kind: providers::ProviderKind::Mock,
+                model: "mock".into(),
+                ..Default::default()
             },
-            test_cmd: default_test_cmd(),
-            max_context_bytes: default_max_context(),
-            implementor_max_attempts: default_impl_attempts(),
+            system_prompt: Some(system_prompt.into()),
+            max_context_bytes: None,
+        };
+        OrchestratorConfigBuilder::new()
+            .tester(role("Read docs/kata-rules.md. You are the Tester. Add a single failing test expressing the next small behavior per the rules. Only output a JSON LlmPatch."))
+            .implementor(role("Read docs/kata-rules.md. You are the Implementor. Make tests pass with minimal changes. Only output a JSON LlmPatch."))
+            .refactorer(role("Read docs/kata-rules.md. You are the Refactorer. Improve code without changing behavior. Keep tests passing. Only output a JSON LlmPatch."))
+    }
+
+    /// JSON Schema for the config file
+    pub fn json_schema() -> serde_json::Value {
+        let schema = schemars::schema_for!(OrchestratorConfig);
+        serde_json::to_value(schema).unwrap_or_default()
+    }
+
+    /// The example config with `test_cmd` (and `test_runner`) suited to the project's language
+    pub fn example_for(project_root: &Path) -> Self {
+        let mut builder = Self::example_builder();
+        let language = workspace::detect_language(project_root);
+        if let Some(test_cmd) = language.default_test_cmd() {
+            builder = builder.test_cmd(test_cmd);
         }
+        builder
+            .test_runner(match language {
+                workspace::Language::Rust => TestRunner::Cargo,
+                workspace::Language::Python => TestRunner::Pytest,
+                _ => TestRunner::Generic,
+            })
+            .build()
+            .expect("the example config is complete")
     }
+
+    pub fn patch_policy(&self) -> workspace::PatchPolicy {
+        workspace::PatchPolicy {
+            protected_files: self.protected_files.clone(),
+            max_files: self.max_patch_files,
+        }
+    }
+
+    /// Commit message for a `phase` commit whose patch didn't suggest one
+    pub fn default_commit_message(&self, phase: Phase) -> String {
+        let (prefix, description) = match phase {
+            Phase::Tester => ("test:", "add failing test"),
+            Phase::Implementor => ("feat:", "make tests pass"),
+            Phase::Refactorer => ("refactor:", "improve design"),
+        };
+        let prefix = self
+            .commit_message_prefix
+            .get(&phase)
+            .map_or(prefix, String::as_str);
+        format!("{prefix} {description}")
+    }
+
+    pub fn apply_options(&self) -> workspace::ApplyOptions {
+        workspace::ApplyOptions {
+            missing_pattern: self.insert_pattern_missing,
+            preserve_crlf: self.preserve_crlf,
+        }
+    }
+
+    /// Context limits for `phase`, whose role may override `max_context_bytes`
+    pub fn context_options(&self, phase: Phase) -> workspace::ContextOptions {
+        workspace::ContextOptions {
+            max_bytes: self
+                .role(phase)
+                .max_context_bytes
+                .unwrap_or(self.max_context_bytes),
+            max_file_bytes: self.max_context_file_bytes,
+            max_test_file_bytes: self.max_context_test_file_bytes,
+            priority_prefixes: self.context_priority_prefixes.clone(),
+            strip_comments: self.strip_comments_from_context,
+            keep_pub_doc_comments: self.keep_pub_doc_comments,
+            secret_exclude_patterns: self.context_secret_exclude_patterns.clone(),
+            annotate_syntax_errors: self.annotate_syntax_errors_in_context,
+            include_todos: self.include_todos_in_context,
+        }
+    }
+
+    pub fn role(&self, phase: Phase) -> &RoleProviderConfig {
+        match phase {
+            Phase::Tester => &self.tester,
+            Phase::Implementor => &self.implementor,
+            Phase::Refactorer => &self.refactorer,
+        }
+    }
+
+    /// The role's provider settings with `llm_seed` filled in wherever no seed is set
+    pub fn provider_config(&self, phase: Phase) -> providers::ProviderConfig {
+        fn apply_seed(provider: &mut providers::ProviderConfig, seed: u64) {
+            provider.seed.get_or_insert(seed);
+            for inner in &mut provider.consensus_providers {
+                apply_seed(inner, seed);
+            }
+        }
+        fn apply_format(provider: &mut providers::ProviderConfig, format: &InstructionFormat) {
+            provider
+                .instruction_format
+                .get_or_insert_with(|| format.clone());
+            // JSON-only output would rule out the requested format
+            provider.json_mode.get_or_insert(false);
+            for inner in &mut provider.consensus_providers {
+                apply_format(inner, format);
+            }
+        }
+        let mut provider = self.role(phase).provider.clone();
+        if let Some(seed) = self.llm_seed {
+            apply_seed(&mut provider, seed);
+        }
+        if self.role_instruction_format != InstructionFormat::LlmPatchJson {
+            apply_format(&mut provider, &self.role_instruction_format);
+        }
+        provider
+    }
+
+    /// "You are working on the <kata_name> kata." opening every role's instructions
+    fn kata_intro(&self) -> String {
+        self.kata_name
+            .as_ref()
+            .map(|name| format!("You are working on the {name} kata.\n\n"))
+            .unwrap_or_default()
+    }
+
+    /// `test_count` is the number of existing tests, when it could be counted
+    pub fn tester_instructions(
+        &self,
+        test_count: Option<usize>,
+        existing_tests: &[String],
+        recent_cycles: &[CycleRecord],
+    ) -> String {
+        let mut instructions = self.kata_intro();
+        if let Some(system_prompt) = &self.tester.system_prompt {
+            instructions.push_str(system_prompt);
+            instructions.push_str("\n\n");
+        }
+        instructions.push_str("Task: Add exactly one failing unit test (red) for the next small behavior in the kata. Do not modify implementation code. ");
+        instructions.push_str(self.role_instruction_format.output_instruction());
+        if let Some(n) = test_count {
+            instructions.push_str(&format!(
+                "\n\nThere are currently {n} test functions in the project."
+            ));
+        }
+        if !existing_tests.is_empty() {
+            instructions.push_str("\n\nExisting tests (do not duplicate them):");
+            for name in existing_tests {
+                instructions.push_str("\n- ");
+                instructions.push_str(name);
+            }
+        }
+        for record in recent_cycles {
+            let Some(failures) = &record.implementor_failures else {
+                continue;
+            };
+            instructions.push_str(&format!(
+                "\n\nIn cycle {} the implementor made {} attempts; the test failures were:\n{}\nPrefer a next step that doesn't exercise the same hard path.",
+                record.cycle_number,
+                record.implementor_attempts,
+                failures.trim_end()
+            ));
+        }
+        instructions
+    }
+
+    /// How long a test run may take, unless `test_timeout_secs` is `null`
+    pub fn test_timeout(&self) -> Option<Duration> {
+        self.test_timeout_secs.map(Duration::from_secs)
+    }
+
+    /// `list_tests_cmd`, defaulting to `cargo test -- --list` for Rust projects; `""` disables
+    pub fn list_tests_cmd(&self, project_root: &Path) -> Option<&str> {
+        match self.list_tests_cmd.as_deref() {
+            Some("") => None,
+            Some(cmd) => Some(cmd),
+            None if self.test_runner == TestRunner::Cargo
+                && workspace::detect_language(project_root) == workspace::Language::Rust =>
+            {
+                Some("cargo test -- --list")
+            }
+            None => None,
+        }
+    }
+
+    pub fn implementor_instructions(&self, failing: &TestResult) -> String {
+        let mut instructions = self.kata_intro();
+        if let Some(system_prompt) = &self.implementor.system_prompt {
+            instructions.push_str(system_prompt);
+            instructions.push_str("\n\n");
+        }
+        instructions.push_str("Task: Make the test suite pass with the simplest change. Keep edits minimal and focused. Use baby steps. ");
+        instructions.push_str(self.role_instruction_format.output_instruction());
+        instructions.push_str("\n\n");
+        instructions.push_str(&failing.status_line());
+        let output = failing.output();
+        if failing.build_failed() {
+            // Compiler diagnostics go to stderr; stdout holds nothing useful yet
+            instructions.push_str(". Fix the build errors first:\n");
+            if failing.stderr.trim().is_empty() {
+                instructions.push_str(&output);
+            } else {
+                instructions.push_str(&failing.stderr);
+            }
+            return instructions;
+        }
+        instructions.push_str(". Test failures to fix:\n");
+        instructions.push_str(&self.test_failures(failing));
+        instructions
+    }
+
+    /// One entry per failing test, or the raw output when the runner's output wasn't recognised
+    pub fn test_failures(&self, failing: &TestResult) -> String {
+        let output = failing.output();
+        let failures = match (&failing.report, self.test_runner) {
+            (Some(report), _) => report.failures.clone(),
+            (None, TestRunner::Cargo) => workspace::summarize_test_failures(&output),
+            (None, runner) => runner.parser().parse(&output).failures,
+        };
+        if failures.is_empty() {
+            output
+        } else {
+            TestReport {
+                passed: Vec::new(),
+                failures,
+            }
+            .failure_summary()
+        }
+    }
+
+    pub fn refactorer_instructions(&self) -> String {
+        let mut instructions = self.kata_intro();
+        if let Some(system_prompt) = &self.refactorer.system_prompt {
+            instructions.push_str(system_prompt);
+            instructions.push_str("\n\n");
+        }
+        instructions.push_str("Task: Refactor to improve clarity, remove duplication, and prepare for change. Don't change behavior. After edits, all tests must still pass. Keep steps small. ");
+        instructions.push_str(self.role_instruction_format.output_instruction());
+        instructions
+    }
+}
+
+/// The exact prompt a role would send to its provider, without sending it
+#[derive(Debug, Serialize)]
+pub struct PromptPreview {
+    pub role: Phase,
+    pub model: String,
+    pub system_prompt: String,
+    pub user_prompt: String,
+    pub estimated_tokens: usize,
+    /// What the roles have done so far, newest first
+    pub recent_commits: Vec<vcs::CommitSummary>,
 }
 
-pub fn load_orchestrator_config(path: Option<&PathBuf>) -> Result<OrchestratorConfig> {
-    if let Some(p) = path {
+pub async fn preview_prompt(
+    project_root: &Path,
+    cfg: &OrchestratorConfig,
+    phase: Phase,
+) -> Result<PromptPreview> {
+    let (context, instructions) = role_prompt(project_root, cfg, phase).await?;
+    let system_prompt = cfg.provider_config(phase).system_prompt();
+    let user_prompt = providers::build_user_prompt(phase.as_str(), &context, &instructions);
+    let estimated_tokens =
+        providers::estimate_tokens(&system_prompt) + providers::estimate_tokens(&user_prompt);
+    let recent_commits = vcs::list_rgr_commits(project_root, 10).await?;
+    Ok(PromptPreview {
+        role: phase,
+        model: cfg.role(phase).provider.model.clone(),
+        system_prompt,
+        user_prompt,
+        estimated_tokens,
+        recent_commits,
+    })
+}
+
+/// The context and instructions `phase` would be given next, outside of a cycle
+pub async fn role_prompt(
+    project_root: &Path,
+    cfg: &OrchestratorConfig,
+    phase: Phase,
+) -> Result<(String, String)> {
+    let context = workspace::collect_context(project_root, &cfg.context_options(phase))?;
+    let instructions = match phase {
+        Phase::Tester => cfg.tester_instructions(
+            count_tests(project_root),
+            &existing_tests(project_root, cfg).await,
+            &recent_struggles(project_root, cfg),
+        ),
+        Phase::Implementor => {
+            // The implementor sees the current test output, so run the suite like a cycle would
+            let result = workspace::run_tests(
+                project_root,
+                &cfg.test_cmd,
+                cfg.max_test_output_bytes,
+                cfg.flaky_test_retries,
+                cfg.test_timeout(),
+                false,
+            )
+            .await?;
+            cfg.implementor_instructions(&result)
+        }
+        Phase::Refactorer => cfg.refactorer_instructions(),
+    };
+    Ok((context, instructions))
+}
+
+/// Builds an `OrchestratorConfig` in code. The three roles are required; every other field
+/// starts out as it would in a config file that leaves it out.
+#[derive(Debug, Default)]
+pub struct OrchestratorConfigBuilder {
+    tester: Option<RoleProviderConfig>,
+    implementor: Option<RoleProviderConfig>,
+    refactorer: Option<RoleProviderConfig>,
+    test_cmd: Option<String>,
+    test_runner: Option<TestRunner>,
+}
+
+/// Why `OrchestratorConfigBuilder::build` refused to build
+#[derive(Debug)]
+pub enum OrchestratorConfigBuilderError {
+    MissingRole(Phase),
+    EmptyTestCmd,
+    /// The role configs didn't make a valid config (e.g. a `provider` that doesn't round-trip)
+    Invalid(String),
+}
+
+impl std::fmt::Display for OrchestratorConfigBuilderError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            OrchestratorConfigBuilderError::MissingRole(phase) => {
+                write!(f, "no provider config for the {} role", phase.as_str())
+            }
+            OrchestratorConfigBuilderError::EmptyTestCmd => write!(f, "test_cmd is empty"),
+            OrchestratorConfigBuilderError::Invalid(reason) => {
+                write!(f, "invalid config: {reason}")
+            }
+        }
+    }
+}
+
+impl std::error::Error for OrchestratorConfigBuilderError {}
+
+impl OrchestratorConfigBuilder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn tester(mut self, cfg: RoleProviderConfig) -> Self {
+        self.tester = Some(cfg);
+        self
+    }
+
+    pub fn implementor(mut self, cfg: RoleProviderConfig) -> Self {
+        self.implementor = Some(cfg);
+        self
+    }
+
+    pub fn refactorer(mut self, cfg: RoleProviderConfig) -> Self {
+        self.refactorer = Some(cfg);
+        self
+    }
+
+    pub fn test_cmd(mut self, cmd: impl Into<String>) -> Self {
+        self.test_cmd = Some(cmd.into());
+        self
+    }
+
+    pub fn test_runner(mut self, runner: TestRunner) -> Self {
+        self.test_runner = Some(runner);
+        self
+    }
+
+    pub fn build(self) -> Result<OrchestratorConfig, OrchestratorConfigBuilderError> {
+        use OrchestratorConfigBuilderError::{EmptyTestCmd, Invalid, MissingRole};
+        let tester = self.tester.ok_or(MissingRole(Phase::Tester))?;
+        let implementor = self.implementor.ok_or(MissingRole(Phase::Implementor))?;
+        let refactorer = self.refactorer.ok_or(MissingRole(Phase::Refactorer))?;
+        if self
+            .test_cmd
+            .as_ref()
+            .is_some_and(|cmd| cmd.trim().is_empty())
+        {
+            return Err(EmptyTestCmd);
+        }
+        // The serde defaults are the single source of truth for the optional fields
+        let roles = serde_json::json!({
+            "tester": tester,
+            "implementor": implementor,
+            "refactorer": refactorer,
+        });
+        let mut cfg: OrchestratorConfig =
+            serde_json::from_value(roles).map_err(|e| Invalid(e.to_string()))?;
+        if let Some(cmd) = self.test_cmd {
+            cfg.test_cmd = cmd;
+        }
+        if let Some(runner) = self.test_runner {
+            cfg.test_runner = runner;
+        }
+        Ok(cfg)
+    }
+}
+
+/// Load the config file (or the example config for the project's language), then apply
+/// `<env_prefix>_*` overrides
+pub fn load_orchestrator_config(
+    path: Option<&PathBuf>,
+    project_root: &Path,
+    env_prefix: Option<&str>,
+) -> Result<OrchestratorConfig> {
+    let cfg = if let Some(p) = path {
         let s = std::fs::read_to_string(p)
             .with_context(|| format!("reading config {}", p.display()))?;
         let cfg: OrchestratorConfig = if p.extension().and_then(|e| e.to_str()) == Some("json") {
@@ -60,10 +913,61 @@ pub fn load_orchestrator_config(path: Option<&PathBuf>) -> Result<OrchestratorCo
         } else {
             serde_yaml::from_str(&s)?
         };
-        Ok(cfg)
+        cfg
     } else {
-        Ok(OrchestratorConfig::example())
+        OrchestratorConfig::example_for(project_root)
+    };
+    match env_prefix {
+        Some(prefix) => apply_env_overrides(cfg, prefix, std::env::vars()),
+        None => Ok(cfg),
+    }
+}
+
+/// Override config fields from `PREFIX_FIELD` variables, e.g. `RGR_TEST_CMD` or
+/// `RGR_TESTER__PROVIDER__MODEL` (`__` separates nested fields). Values are read as YAML,
+/// except for fields that are strings in the example config, which are taken verbatim.
+fn apply_env_overrides(
+    cfg: OrchestratorConfig,
+    prefix: &str,
+    vars: impl Iterator<Item = (String, String)>,
+) -> Result<OrchestratorConfig> {
+    let mut value = serde_json::to_value(cfg)?;
+    let reference = serde_json::to_value(OrchestratorConfig::example())?;
+    let prefix = format!("{}_", prefix.to_uppercase());
+    for (name, raw) in vars {
+        let Some(path) = name.strip_prefix(&prefix) else {
+            continue;
+        };
+        let keys: Vec<String> = path.split("__").map(str::to_lowercase).collect();
+        if reference.get(&keys[0]).is_none() {
+            warn!("{name} does not match a config field; ignoring it");
+            continue;
+        }
+        let is_string = keys
+            .iter()
+            .try_fold(&reference, |v, k| v.get(k))
+            .is_some_and(serde_json::Value::is_string);
+        let new_value = if is_string {
+            serde_json::Value::String(raw)
+        } else {
+            serde_yaml::from_str(&raw).with_context(|| format!("parsing {name}"))?
+        };
+        let (last, parents) = keys.split_last().expect("split yields at least one key");
+        let mut target = &mut value;
+        for key in parents {
+            let object = target
+                .as_object_mut()
+                .ok_or_else(|| anyhow!("{name}: `{key}` is not a nested config section"))?;
+            target = object
+                .entry(key.clone())
+                .or_insert_with(|| serde_json::Value::Object(Default::default()));
+        }
+        target
+            .as_object_mut()
+            .ok_or_else(|| anyhow!("{name}: cannot set `{last}` on a non-section value"))?
+            .insert(last.clone(), new_value);
     }
+    serde_json::from_value(value).with_context(|| format!("applying {prefix}* overrides"))
 }
 
 pub struct Orchestrator {
@@ -72,6 +976,20 @@ pub struct Orchestrator {
     tester: Box<dyn LlmProvider>,
     implementor: Box<dyn LlmProvider>,
     refactorer: Box<dyn LlmProvider>,
+    cost: Mutex<CostTracker>,
+    annotations: AnnotationFormat,
+    /// HEAD when the current cycle started (the previous cycle's last commit)
+    cycle_base: Option<String>,
+    /// Untracked paths that resets during the current cycle leave alone: the files that were
+    /// already there when it started, and request log directories inside the project
+    cycle_untracked: Vec<PathBuf>,
+    /// Uncommitted changes already there when the current cycle started, which the
+    /// postcondition check leaves out
+    cycle_dirty: Vec<String>,
+    /// Whether the `budget_alert_at_pct` warning was given
+    budget_alerted: AtomicBool,
+    /// The main worktree while a cycle runs in a `use_worktree` worktree; state stays there
+    main_root: Option<PathBuf>,
 }
 
 impl Orchestrator {
@@ -82,161 +1000,1256 @@ impl Orchestrator {
                 project_root.display()
             ));
         }
-        let tester = ProviderFactory::build(&cfg.tester.provider)?;
-        let implementor = ProviderFactory::build(&cfg.implementor.provider)?;
-        let refactorer = ProviderFactory::build(&cfg.refactorer.provider)?;
+        // Patched paths are joined onto the root and handed to git running inside it,
+        // so a relative root like `kata/` would be applied twice
+        let project_root = project_root.canonicalize()?;
+        let build = |phase| -> Result<Box<dyn LlmProvider>> {
+            let provider = cfg.provider_config(phase);
+            if cfg.fallback_to_mock {
+                Ok(ProviderFactory::build_with_mock_fallback(&provider))
+            } else {
+                ProviderFactory::build(&provider)
+            }
+        };
+        let tester = build(Phase::Tester)?;
+        let implementor = build(Phase::Implementor)?;
+        let refactorer = build(Phase::Refactorer)?;
+        let mut pricing = cost_tracker::default_pricing();
+        pricing.extend(cfg.model_pricing.clone());
         Ok(Self {
             project_root,
             cfg,
             tester,
             implementor,
             refactorer,
+            cost: Mutex::new(CostTracker::new(pricing)),
+            annotations: AnnotationFormat::None,
+            cycle_base: None,
+            cycle_untracked: Vec::new(),
+            cycle_dirty: Vec::new(),
+            budget_alerted: AtomicBool::new(false),
+            main_root: None,
         })
     }
 
-    pub async fn red_green_refactor_cycle(&mut self) -> Result<()> {
+    pub fn set_annotations(&mut self, annotations: AnnotationFormat) {
+        self.annotations = annotations;
+    }
+
+    /// The current config, cycle count, HEAD and latest commit of each phase
+    pub async fn snapshot(&self) -> Result<OrchestratorSnapshot> {
+        let records = state::load_records(&self.project_root)?;
+        let last = |commit: fn(&CycleRecord) -> &Option<String>| {
+            records.iter().rev().find_map(|r| commit(r).clone())
+        };
+        Ok(OrchestratorSnapshot {
+            taken_at: chrono::Utc::now(),
+            config: self.cfg.clone(),
+            cycle_count: records.len(),
+            head: vcs::get_head_commit(&self.project_root).await.ok(),
+            last_tester_commit: last(|r| &r.tester_commit),
+            last_green_commit: last(|r| &r.green_commit),
+            last_refactor_commit: last(|r| &r.refactor_commit),
+        })
+    }
+
+    /// An orchestrator built from the snapshot's config, with the project back at its HEAD
+    pub async fn from_snapshot(
+        project_root: PathBuf,
+        snapshot: &OrchestratorSnapshot,
+    ) -> Result<Self> {
+        let mut orch = Self::new(project_root, snapshot.config.clone()).await?;
+        orch.restore(snapshot).await?;
+        Ok(orch)
+    }
+
+    /// Go back to `snapshot`'s HEAD if it has moved since (commits made after it are
+    /// discarded, untracked files kept). The loaded config stays in effect; a differing one in
+    /// the snapshot is only reported
+    pub async fn restore(&mut self, snapshot: &OrchestratorSnapshot) -> Result<()> {
+        if let Some(head) = &snapshot.head
+            && vcs::get_head_commit(&self.project_root).await.ok().as_ref() != Some(head)
+        {
+            info!("Resetting to {head}, HEAD as of the snapshot");
+            vcs::reset_hard_to(&self.project_root, head, None).await?;
+        }
+        if serde_json::to_value(&snapshot.config)? != serde_json::to_value(&self.cfg)? {
+            warn!(
+                "The config has changed since the snapshot of {}; resuming with the current one",
+                snapshot.taken_at.format("%Y-%m-%d %H:%M:%S UTC")
+            );
+        }
+        Ok(())
+    }
+
+    /// Estimated spend of all provider calls made so far
+    pub fn total_cost_usd(&self) -> f64 {
+        self.cost.lock().unwrap().total_cost_usd()
+    }
+
+    /// What is left of `budget_limit_usd`, when one is set
+    pub fn remaining_budget_usd(&self) -> Option<f64> {
+        self.cfg
+            .budget_limit_usd
+            .map(|limit| limit - self.total_cost_usd())
+    }
+
+    /// Fail with `BudgetExceededError` once the total spend is over `budget_limit_usd`,
+    /// warning the first time it reaches `budget_alert_at_pct` of it
+    fn check_budget(&self) -> Result<()> {
+        let Some(limit) = self.cfg.budget_limit_usd else {
+            return Ok(());
+        };
+        let cost = self.cost.lock().unwrap();
+        cost.check_budget(limit)?;
+        let spent = cost.total_cost_usd();
+        if spent >= limit * self.cfg.budget_alert_at_pct / 100.0
+            && !self.budget_alerted.swap(true, Ordering::Relaxed)
+        {
+            warn!(
+                "Spent ${spent:.4}, {:.0}% of the ${limit:.4} budget",
+                spent / limit * 100.0
+            );
+        }
+        Ok(())
+    }
+
+    pub async fn tests_pass(&self) -> Result<bool> {
+        let result = workspace::run_tests(
+            &self.project_root,
+            &self.cfg.test_cmd,
+            self.cfg.max_test_output_bytes,
+            self.cfg.flaky_test_retries,
+            self.cfg.test_timeout(),
+            self.cfg.display_test_output,
+        )
+        .await?;
+        Ok(result.passed)
+    }
+
+    /// Run one cycle and append its outcome to the state file, whether it succeeded or not;
+    /// a failed implementor ends the cycle early without an error (`CycleSummary::success` is false)
+    pub async fn red_green_refactor_cycle(&mut self) -> Result<CycleSummary> {
+        let started = Instant::now();
+        self.check_budget()?;
+        let mut git_config: Vec<(&str, &str)> = self
+            .cfg
+            .git_config
+            .iter()
+            .map(|(key, value)| (key.as_str(), value.as_str()))
+            .collect();
+        git_config.sort();
+        if let Some(name) = &self.cfg.git_author_name {
+            git_config.push(("user.name", name));
+        }
+        if let Some(email) = &self.cfg.git_author_email {
+            git_config.push(("user.email", email));
+        }
+        vcs::ensure_repo(&self.project_root, &git_config).await?;
+        self.check_working_tree().await?;
+        let cycle_number = self.next_cycle_number().await?;
+        let mut record = CycleRecord::start(cycle_number);
+        record.kata = self.cfg.kata_name.clone();
+        self.cost.lock().unwrap().start_cycle();
+        let span = info_span!("rgr.cycle", cycle = cycle_number);
+        let mut phase_durations = [Duration::ZERO; 3];
+        let worktree = if self.cfg.use_worktree {
+            let worktree =
+                vcs::git_worktree_create(&self.project_root, WORKTREE_DIR, WORKTREE_BRANCH).await?;
+            info!("Running the cycle in {}", worktree.display());
+            // A project in a subdirectory of its repo sits at the same place in the worktree
+            let project_root = worktree.join(vcs::project_path_in_repo(&self.project_root).await?);
+            self.main_root = Some(std::mem::replace(&mut self.project_root, project_root));
+            Some(worktree)
+        } else {
+            None
+        };
+        if self.cfg.keep_backups {
+            self.ignore_backups().await?;
+        }
+        let pre_cycle = vcs::get_head_commit(&self.project_root).await.ok();
+        self.cycle_untracked = vcs::untracked_files(&self.project_root).await?;
+        self.cycle_untracked.extend(self.request_log_dirs());
+        self.cycle_dirty = self.uncommitted_changes().await?;
+        // Cycle branches are made from, and merged into, the branch the cycle starts on
+        let start_branch = if self.cfg.per_cycle_branch {
+            Some(vcs::current_branch(&self.project_root).await?)
+        } else {
+            None
+        };
+        let mut retried = false;
+        // In a block, so an early `?` still gets back out of the worktree below
+        let result: Result<()> = async {
+            loop {
+                let result = self
+                    .run_cycle(&mut record, &mut phase_durations)
+                    .instrument(span.clone())
+                    .await;
+                let Some(pre_cycle) = pre_cycle.as_deref().filter(|_| {
+                    self.cfg.cycle_retry_on_state_error && result.is_ok() && record.success
+                }) else {
+                    break result;
+                };
+                let problems = self
+                    .verify_cycle_postconditions(&record)
+                    .instrument(span.clone())
+                    .await?;
+                if problems.is_empty() {
+                    break result;
+                }
+                if retried {
+                    record.success = false;
+                    break Err(anyhow!(
+                        "cycle {cycle_number} left the repo in a bad state again: {}",
+                        problems.join("; ")
+                    ));
+                }
+                if !self.cycle_dirty.is_empty() {
+                    record.success = false;
+                    break Err(anyhow!(
+                        "cycle {cycle_number} left the repo in a bad state ({}); not re-running it, since resetting would discard the uncommitted changes it started with ({})",
+                        problems.join("; "),
+                        self.cycle_dirty.join(", ")
+                    ));
+                }
+                warn!(
+                    "Cycle {cycle_number} left the repo in a bad state ({}); re-running it from {pre_cycle}",
+                    problems.join("; ")
+                );
+                let tag = format!("{CYCLE_TAG_PREFIX}{cycle_number:04}");
+                vcs::delete_tag(&self.project_root, &tag).await?;
+                if let Some(start_branch) = &start_branch {
+                    vcs::checkout_branch(&self.project_root, start_branch).await?;
+                }
+                self.reset_cycle_to(pre_cycle).await?;
+                // The re-run creates its cycle branches afresh
+                for suffix in ["-tester", "-green", "-refactor"] {
+                    let branch = cycle_branch(cycle_number, suffix);
+                    if vcs::branch_exists(&self.project_root, &branch).await? {
+                        vcs::delete_branch(&self.project_root, &branch).await?;
+                    }
+                }
+                record = CycleRecord::start(cycle_number);
+                record.kata = self.cfg.kata_name.clone();
+                phase_durations = [Duration::ZERO; 3];
+                retried = true;
+            }
+        }
+        .await;
+        if let Err(e) = &result
+            && e.is::<BudgetExceededError>()
+            && let Some(pre_cycle) = &pre_cycle
+        {
+            if self.cycle_dirty.is_empty() {
+                warn!(
+                    "Out of budget in cycle {cycle_number}; resetting to {pre_cycle}, where it started"
+                );
+                if let Err(e) = self.reset_cycle_to(pre_cycle).await {
+                    warn!("{e:#}");
+                }
+            } else {
+                warn!(
+                    "Out of budget in cycle {cycle_number}; not resetting to {pre_cycle}, since that would discard the uncommitted changes it started with"
+                );
+            }
+        }
+        if let Some(hook) = &self.cfg.post_cycle_hook
+            && let Err(e) = self.run_hook("post_cycle", hook).instrument(span).await
+        {
+            warn!("{e:#}");
+        }
+        if let Some(worktree) = worktree {
+            self.project_root = self.main_root.take().unwrap_or_default();
+            // A cycle that errored out leaves its worktree for inspection until the next one
+            if result.is_ok() {
+                if let Err(e) = vcs::git_worktree_remove(&self.project_root, &worktree).await {
+                    warn!("{e:#}");
+                }
+            } else {
+                warn!("Leaving the cycle's worktree at {}", worktree.display());
+            }
+        }
+        record.completed_at = Some(chrono::Utc::now());
+        metrics::counter!("rgr_cycles_total", "success" => record.success.to_string()).increment(1);
+        let cycle_cost = self.cost.lock().unwrap().cycle_cost_usd();
+        info!("Cycle {cycle_number} cost: ${cycle_cost:.4}");
+        if let Some(max) = self.cfg.max_cost_per_cycle_usd
+            && cycle_cost > max
+        {
+            warn!(
+                "Cycle {cycle_number} cost ${cycle_cost:.4} exceeds max_cost_per_cycle_usd ${max:.4}"
+            );
+        }
+        if let Err(e) = state::append_record(&self.project_root, &record) {
+            warn!("Failed to record cycle {cycle_number}: {e:#}");
+        }
+        let snapshot = self.snapshot().await;
+        if let Err(e) = snapshot.and_then(|s| state::save_snapshot(&self.project_root, &s)) {
+            warn!("Failed to save a snapshot after cycle {cycle_number}: {e:#}");
+        }
+        result.map(|()| CycleSummary {
+            cycle_number,
+            success: record.success,
+            tester_commit: record.tester_commit,
+            green_commit: record.green_commit,
+            refactor_commit: record.refactor_commit,
+            implementor_attempts: record.implementor_attempts,
+            total_duration: started.elapsed(),
+            phase_durations,
+            test_counts: record.test_counts,
+            total_cost_usd: self.total_cost_usd(),
+            remaining_budget_usd: self.remaining_budget_usd(),
+        })
+    }
+
+    async fn run_cycle(
+        &mut self,
+        record: &mut CycleRecord,
+        phase_durations: &mut [Duration; 3],
+    ) -> Result<()> {
+        if let Some(hook) = &self.cfg.pre_cycle_hook {
+            self.run_hook("pre_cycle", hook).await?;
+        }
+        // No HEAD yet in a fresh repo, in which case the full context is sent
+        self.cycle_base = vcs::get_head_commit(&self.project_root).await.ok();
+        let main_branch = if self.cfg.per_cycle_branch {
+            Some(vcs::current_branch(&self.project_root).await?)
+        } else {
+            None
+        };
+        let result = self
+            .run_phases(record, phase_durations, main_branch.as_deref())
+            .await;
+        if result.is_err()
+            && let Some(main_branch) = &main_branch
+        {
+            // Left on a cycle branch, the next cycle would take it for the main branch
+            if let Err(e) = vcs::checkout_branch(&self.project_root, main_branch).await {
+                warn!("Failed to check out {main_branch} after the cycle failed: {e:#}");
+            }
+        }
+        result
+    }
+
+    /// The three phases of `run_cycle`, from its first cycle branch to the merge into `main_branch`
+    async fn run_phases(
+        &mut self,
+        record: &mut CycleRecord,
+        phase_durations: &mut [Duration; 3],
+        main_branch: Option<&str>,
+    ) -> Result<()> {
+        let cycle_number = record.cycle_number;
+        self.start_cycle_branch(cycle_number, "-tester").await?;
+        let started = Instant::now();
+        let (tester_head, red) = self.tester_phase(record).await?;
+        phase_durations[0] = started.elapsed();
+        self.check_budget()?;
+
+        self.start_cycle_branch(cycle_number, "-green").await?;
+        record.phase_reached = Phase::Implementor;
+        let started = Instant::now();
+        let impl_success = self.implementor_phase(record, red).await?;
+        phase_durations[1] = started.elapsed();
+        if !impl_success {
+            warn!(
+                "All implementor attempts failed; preserving attempts and resetting to tester commit"
+            );
+            let branch_name = format!(
+                "attempts/implementor-{}",
+                chrono::Utc::now().format("%Y%m%d%H%M%S")
+            );
+            let _ = vcs::create_branch_at_head(&self.project_root, &branch_name).await; // best effort
+            self.reset_cycle_to(&tester_head).await?;
+            if let Some(main_branch) = main_branch {
+                // The attempts branch keeps the failed commits; the tester branch stays for auditing
+                vcs::checkout_branch(&self.project_root, main_branch).await?;
+                vcs::delete_branch(&self.project_root, &cycle_branch(cycle_number, "-green"))
+                    .await?;
+            }
+            // End this cycle here; next cycle will try again from a clean tester state
+            return Ok(());
+        }
+        info!("Tests green");
+        record.green_commit = Some(vcs::get_head_commit(&self.project_root).await?);
+        self.check_budget()?;
+
+        self.start_cycle_branch(cycle_number, "-refactor").await?;
+        record.phase_reached = Phase::Refactorer;
+        let started = Instant::now();
+        let added = vcs::lines_added_since(&self.project_root, &tester_head).await?;
+        if added < self.cfg.refactor_skip_threshold {
+            info!(
+                "Skipping refactor: implementation added {added} lines (threshold {})",
+                self.cfg.refactor_skip_threshold
+            );
+            vcs::commit_paths(
+                &self.project_root,
+                &[],
+                "chore: skip refactor (trivial impl)",
+                &[],
+            )
+            .await?;
+        } else {
+            let green = vcs::get_head_commit(&self.project_root).await?;
+            let (ok3, out3) = self.refactor_phase(record).await?;
+            if !ok3 {
+                warn!("Refactor step broke tests, reverting commit");
+                self.annotate_failures("Refactor broke tests", &out3);
+                if record.green_commit.as_deref() == Some(green.as_str()) {
+                    vcs::reset_hard_head_minus_one(&self.project_root, Some(&self.cycle_untracked))
+                        .await?;
+                } else {
+                    // Back to the green commit as it was before a trivial refactor amended it
+                    self.reset_cycle_to(&green).await?;
+                    record.green_commit = Some(green);
+                }
+                // Green is still worth keeping, so the cycle branch is merged anyway
+                self.merge_cycle_branch(main_branch, cycle_number).await?;
+                return Err(anyhow!(
+                    "Refactor step failed tests and was reverted. Output:\n{}",
+                    out3
+                ));
+            }
+            info!("Refactor preserved green");
+        }
+        phase_durations[2] = started.elapsed();
+        record.refactor_commit = Some(vcs::get_head_commit(&self.project_root).await?);
+        self.check_budget()?;
+
+        self.merge_cycle_branch(main_branch, cycle_number).await?;
+        vcs::tag_head(
+            &self.project_root,
+            &format!("{CYCLE_TAG_PREFIX}{cycle_number:04}"),
+        )
+        .await?;
+        record.success = true;
+        self.annotations
+            .notice(&format!("rgr cycle {cycle_number} completed green"));
+        Ok(())
+    }
+
+    /// What is wrong with the repo after a green cycle, if anything: the suite must pass on
+    /// HEAD, HEAD must be the cycle's refactor commit (or, with `per_cycle_branch`, the merge
+    /// of the cycle branch containing it) and the working tree must be clean
+    pub async fn verify_cycle_postconditions(&self, record: &CycleRecord) -> Result<Vec<String>> {
+        let mut problems = Vec::new();
+        let head = vcs::get_head_commit(&self.project_root).await?;
+        for (phase, commit) in [
+            (Phase::Tester, &record.tester_commit),
+            (Phase::Implementor, &record.green_commit),
+            (Phase::Refactorer, &record.refactor_commit),
+        ] {
+            match commit {
+                Some(commit) if vcs::is_ancestor(&self.project_root, commit, &head).await? => {}
+                Some(commit) => {
+                    problems.push(format!("{} commit {commit} is not on HEAD", phase.as_str()))
+                }
+                None => problems.push(format!("no {} commit recorded", phase.as_str())),
+            }
+        }
+        if let Some(refactor) = &record.refactor_commit
+            && *refactor != head
+        {
+            let subject = vcs::commit_subject(&self.project_root, &head).await?;
+            let merge = format!("Merge {}", cycle_branch(record.cycle_number, "-refactor"));
+            if !(self.cfg.per_cycle_branch && subject == merge) {
+                problems.push(format!("HEAD is an unexpected commit: {subject}"));
+            }
+        }
+        let dirty: Vec<String> = self
+            .uncommitted_changes()
+            .await?
+            .into_iter()
+            .filter(|p| !self.cycle_dirty.contains(p))
+            .collect();
+        if !dirty.is_empty() {
+            problems.push(format!("uncommitted changes: {}", dirty.join(", ")));
+        }
+        let result = workspace::run_tests(
+            &self.project_root,
+            &self.cfg.test_cmd,
+            self.cfg.max_test_output_bytes,
+            self.cfg.flaky_test_retries,
+            self.cfg.test_timeout(),
+            self.cfg.display_test_output,
+        )
+        .await?;
+        if !result.passed {
+            problems.push(format!("tests fail on HEAD ({})", result.status_line()));
+        }
+        Ok(problems)
+    }
+
+    /// Commit one new failing test; returns the tester commit and the (red) test output
+    #[instrument(
+        name = "rgr.tester",
+        skip_all,
+        fields(provider.model = %self.cfg.tester.provider.model, duration_ms = Empty)
+    )]
+    async fn tester_phase(&self, record: &mut CycleRecord) -> Result<(String, TestResult)> {
+        let phase_started = Instant::now();
         info!(
             "Starting Red (Tester) step (model {})",
             &self.cfg.tester.provider.model
         );
-        vcs::ensure_repo(&self.project_root).await?;
-
-        let context = workspace::collect_context(&self.project_root, self.cfg.max_context_bytes)?;
-        let tester_instr = self.build_tester_instructions();
-        let patch = self
-            .tester
-            .generate_patch("tester", &context, &tester_instr)
+        let context = self.collect_context(Phase::Tester).await?;
+        let mut tester_instr = self.build_tester_instructions().await;
+        let mut patch = self
+            .generate(Phase::Tester, &context, &tester_instr)
             .await?;
-        let touched = workspace::apply_patch(&self.project_root, &patch).await?;
+        let max_lines = self.cfg.tester_max_test_lines;
+        if let Err(e) = workspace::validate_test_size(&self.project_root, &patch, max_lines) {
+            // One more try with the reason spelled out, like implementor rejections
+            warn!("Tester patch rejected: {e}");
+            tester_instr.push_str("\n\nYour previous patch was rejected: ");
+            tester_instr.push_str(&e.to_string());
+            patch = self
+                .generate(Phase::Tester, &context, &tester_instr)
+                .await?;
+            workspace::validate_test_size(&self.project_root, &patch, max_lines)
+                .map_err(|e| anyhow!("Tester patch rejected again: {e}"))?;
+        }
+        log_notes(Phase::Tester, &patch);
+        workspace::validate_patch(&self.project_root, &patch, &self.cfg.patch_policy())?;
+        let touched = self.apply_patch("tester", &patch).await?;
+        if self.review_patch(Phase::Tester, &touched).await? == Review::Discard {
+            return Err(anyhow!("Tester patch discarded during review"));
+        }
+        let msg = patch
+            .commit_message
+            .clone()
+            .unwrap_or_else(|| self.cfg.default_commit_message(Phase::Tester));
         vcs::commit_paths(
             &self.project_root,
             &touched,
-            patch
-                .commit_message
-                .as_deref()
-                .unwrap_or("test: add failing test"),
+            &with_notes_trailer(&msg, &patch),
+            &self.trailers(Phase::Tester, record.cycle_number, None),
         )
         .await?;
         let tester_head = vcs::get_head_commit(&self.project_root).await?;
+        record.tester_commit = Some(tester_head.clone());
 
-        let (ok, out) = workspace::run_tests(&self.project_root, &self.cfg.test_cmd).await?;
-        if ok {
+        // Red is the expected outcome here, so a failure is not worth re-running
+        let red = workspace::run_tests(
+            &self.project_root,
+            &self.cfg.test_cmd,
+            self.cfg.max_test_output_bytes,
+            0,
+            self.cfg.test_timeout(),
+            self.cfg.display_test_output,
+        )
+        .await?;
+        if red.passed {
             warn!("Tester step produced passing tests; proceeding anyway")
         } else {
             info!("Tests are red as expected")
         }
+        finish_phase(Phase::Tester, phase_started);
+        Ok((tester_head, red))
+    }
 
+    /// Retry the implementor until the suite is green; returns whether it succeeded
+    #[instrument(
+        name = "rgr.implementor",
+        skip_all,
+        fields(
+            provider.model = %self.cfg.implementor.provider.model,
+            attempt = Empty,
+            duration_ms = Empty
+        )
+    )]
+    async fn implementor_phase(&self, record: &mut CycleRecord, red: TestResult) -> Result<bool> {
+        let phase_started = Instant::now();
         info!(
             "Starting Green (Implementor) step (model {})",
             &self.cfg.implementor.provider.model
         );
-        let mut last_fail_output = out.clone();
+        let mut last_failure = red;
+        // Set while the latest green attempt was turned down by the lint hard gate
+        let mut lint_failure: Option<String> = None;
+        let mut rejection: Option<String> = None;
+        let mut previous_patch: Option<LlmPatch> = None;
         let mut impl_success = false;
         for attempt in 1..=self.cfg.implementor_max_attempts {
-            let context2 =
-                workspace::collect_context(&self.project_root, self.cfg.max_context_bytes)?;
-            let impl_instr = self.build_implementor_instructions(&last_fail_output);
+            if attempt > 1 {
+                // The previous attempt may have used up the budget
+                self.check_budget()?;
+            }
+            if attempt > 1 && self.cfg.implementor_backoff_base_ms > 0 {
+                let delay = self.cfg.implementor_backoff_base_ms << (attempt - 2).min(16);
+                info!("Waiting {delay}ms before implementor attempt {attempt}");
+                tokio::time::sleep(Duration::from_millis(delay)).await;
+            }
+            record.implementor_attempts = attempt;
+            Span::current().record("attempt", attempt);
+            let context2 = self.collect_context(Phase::Implementor).await?;
+            let mut impl_instr = self.build_implementor_instructions(&last_failure);
+            if context2.contains(workspace::SYNTAX_ERROR_MARKER) {
+                impl_instr.push_str("\n\nSome files are marked ");
+                impl_instr.push_str(workspace::SYNTAX_ERROR_MARKER);
+                impl_instr.push_str(". Fix syntax errors before adding new features.");
+            }
+            if let Some(reason) = rejection.take() {
+                impl_instr.push_str("\n\nYour previous patch was rejected: ");
+                impl_instr.push_str(&reason);
+            }
             let patch2 = self
-                .implementor
-                .generate_patch("implementor", &context2, &impl_instr)
+                .generate(Phase::Implementor, &context2, &impl_instr)
                 .await?;
-            let touched2 = workspace::apply_patch(&self.project_root, &patch2).await?;
+            log_notes(Phase::Implementor, &patch2);
+            if let Some(previous) = previous_patch.replace(patch2.clone()) {
+                debug!(
+                    "Attempt {attempt} patch vs attempt {}:\n{}",
+                    attempt - 1,
+                    workspace::diff_patches(&previous, &patch2)
+                );
+            }
+            if let Err(e) =
+                workspace::validate_patch(&self.project_root, &patch2, &self.cfg.patch_policy())
+            {
+                warn!("Implementor attempt {attempt} rejected: {e}");
+                rejection = Some(e.to_string());
+                continue;
+            }
+            if let Some(errors) = self.compile_failure(&patch2).await? {
+                warn!("Implementor attempt {attempt} does not compile; not committing it");
+                rejection = Some(format!("it does not compile:\n{errors}"));
+                continue;
+            }
+            let touched2 = self
+                .apply_patch(&format!("implementor-{attempt}"), &patch2)
+                .await?;
+            if self.review_patch(Phase::Implementor, &touched2).await? == Review::Discard {
+                rejection = Some("it was discarded by the reviewer".to_string());
+                continue;
+            }
             let msg = patch2
                 .commit_message
-                .as_deref()
-                .unwrap_or("feat: make tests pass");
-            let msg = &format!("{msg} (attempt {attempt})");
-            vcs::commit_paths(&self.project_root, &touched2, msg).await?;
+                .clone()
+                .unwrap_or_else(|| self.cfg.default_commit_message(Phase::Implementor));
+            let msg = with_notes_trailer(&format!("{msg} (attempt {attempt})"), &patch2);
+            let trailers = self.trailers(Phase::Implementor, record.cycle_number, Some(attempt));
+            vcs::commit_paths(&self.project_root, &touched2, &msg, &trailers).await?;
 
-            let (ok2, out2) = workspace::run_tests(&self.project_root, &self.cfg.test_cmd).await?;
-            if ok2 {
+            let result = workspace::run_tests(
+                &self.project_root,
+                &self.cfg.test_cmd,
+                self.cfg.max_test_output_bytes,
+                self.cfg.flaky_test_retries,
+                self.cfg.test_timeout(),
+                self.cfg.display_test_output,
+            )
+            .await?;
+            if result.passed {
+                match self.lint_failure().await? {
+                    Some(lint_out) if self.cfg.lint_is_hard_gate => {
+                        warn!("Implementor attempt {attempt} passes tests but fails lint_cmd");
+                        rejection = Some(format!(
+                            "the tests pass but the linter reports problems:\n{lint_out}"
+                        ));
+                        record.implementor_failures = Some(workspace::truncate_output(
+                            &lint_out,
+                            RECENT_FAILURES_MAX_BYTES,
+                        ));
+                        lint_failure = Some(lint_out);
+                        continue;
+                    }
+                    Some(_) => warn!("lint_cmd failed; proceeding since lint_is_hard_gate is off"),
+                    None => {}
+                }
+                record.test_counts = result.counts;
                 impl_success = true;
                 break;
             }
-            last_fail_output = out2;
+            record.implementor_failures = Some(workspace::truncate_output(
+                &self.cfg.test_failures(&result),
+                RECENT_FAILURES_MAX_BYTES,
+            ));
+            last_failure = result;
+            lint_failure = None;
             warn!(
                 "Implementor attempt {} failed; retrying if attempts remain",
                 attempt
             );
         }
-
         if !impl_success {
-            warn!(
-                "All implementor attempts failed; preserving attempts and resetting to tester commit"
-            );
-            let branch_name = format!(
-                "attempts/implementor-{}",
-                chrono::Utc::now().format("%Y%m%d%H%M%S")
-            );
-            let _ = vcs::create_branch_at_head(&self.project_root, &branch_name).await; // best effort
-            vcs::reset_hard_to(&self.project_root, &tester_head).await?;
-            // End this cycle here; next cycle will try again from a clean tester state
-            return Ok(());
+            match &lint_failure {
+                Some(lint_out) => {
+                    self.annotate_failures("Implementor could not satisfy lint_cmd", lint_out)
+                }
+                None => self.annotate_failures(
+                    "Implementor could not make tests pass",
+                    &last_failure.output(),
+                ),
+            }
         }
-        info!("Tests green");
+        finish_phase(Phase::Implementor, phase_started);
+        Ok(impl_success)
+    }
 
+    /// Commit the refactoring; returns whether tests still pass, with their output
+    #[instrument(
+        name = "rgr.refactorer",
+        skip_all,
+        fields(provider.model = %self.cfg.refactorer.provider.model, duration_ms = Empty)
+    )]
+    async fn refactor_phase(&self, record: &mut CycleRecord) -> Result<(bool, String)> {
+        let phase_started = Instant::now();
         info!(
             "Starting Refactor step (model {})",
             &self.cfg.refactorer.provider.model
         );
-        let context3 = workspace::collect_context(&self.project_root, self.cfg.max_context_bytes)?;
+        let context3 = self.collect_context(Phase::Refactorer).await?;
         let ref_instr = self.build_refactorer_instructions();
-        let patch3 = self
-            .refactorer
-            .generate_patch("refactorer", &context3, &ref_instr)
+        let mut patch3 = self
+            .generate(Phase::Refactorer, &context3, &ref_instr)
             .await?;
-        let touched3 = workspace::apply_patch(&self.project_root, &patch3).await?;
-        vcs::commit_paths(
-            &self.project_root,
-            &touched3,
-            patch3
+        log_notes(Phase::Refactorer, &patch3);
+        if let Some(errors) = self.compile_failure(&patch3).await? {
+            warn!("Refactor does not compile; asking the refactorer once more");
+            let retry_instr = format!(
+                "{ref_instr}\n\nYour previous patch was rejected: it does not compile:\n{errors}"
+            );
+            patch3 = self
+                .generate(Phase::Refactorer, &context3, &retry_instr)
+                .await?;
+            log_notes(Phase::Refactorer, &patch3);
+            if self.compile_failure(&patch3).await?.is_some() {
+                info!("Refactor skipped: the retried patch does not compile either");
+                finish_phase(Phase::Refactorer, phase_started);
+                return Ok((true, String::new()));
+            }
+        }
+        workspace::validate_patch(&self.project_root, &patch3, &self.cfg.patch_policy())?;
+        let touched3 = self.apply_patch("refactorer", &patch3).await?;
+        if self.review_patch(Phase::Refactorer, &touched3).await? == Review::Discard {
+            info!("Refactor discarded during review");
+            finish_phase(Phase::Refactorer, phase_started);
+            return Ok((true, String::new()));
+        }
+        let changed = vcs::diff_paths(&self.project_root, &touched3)
+            .await?
+            .lines()
+            .filter(|l| {
+                (l.starts_with('+') && !l.starts_with("+++"))
+                    || (l.starts_with('-') && !l.starts_with("---"))
+            })
+            .count();
+        // An amend would leave the `-green` cycle branch on the commit it replaced
+        if self.cfg.amend_trivial_refactors
+            && !self.cfg.per_cycle_branch
+            && changed < TRIVIAL_REFACTOR_MAX_LINES
+        {
+            info!("Refactor changes {changed} lines; amending the green commit");
+            vcs::amend_last_commit(&self.project_root, &touched3, None).await?;
+            record.green_commit = Some(vcs::get_head_commit(&self.project_root).await?);
+        } else {
+            let msg = patch3
                 .commit_message
-                .as_deref()
-                .unwrap_or("refactor: improve design"),
+                .clone()
+                .unwrap_or_else(|| self.cfg.default_commit_message(Phase::Refactorer));
+            vcs::commit_paths(
+                &self.project_root,
+                &touched3,
+                &with_notes_trailer(&msg, &patch3),
+                &self.trailers(Phase::Refactorer, record.cycle_number, None),
+            )
+            .await?;
+        }
+
+        let result = workspace::run_tests(
+            &self.project_root,
+            &self.cfg.test_cmd,
+            self.cfg.max_test_output_bytes,
+            self.cfg.flaky_test_retries,
+            self.cfg.test_timeout(),
+            self.cfg.display_test_output,
         )
         .await?;
+        finish_phase(Phase::Refactorer, phase_started);
+        Ok((result.passed, result.output()))
+    }
 
-        let (ok3, out3) = workspace::run_tests(&self.project_root, &self.cfg.test_cmd).await?;
-        if !ok3 {
-            warn!("Refactor step broke tests, reverting commit");
-            vcs::reset_hard_head_minus_one(&self.project_root).await?;
-            return Err(anyhow!(
-                "Refactor step failed tests and was reverted. Output:\n{}",
-                out3
-            ));
+    /// Uncommitted edits would be swept into the LLM's commits, so flag them up front
+    async fn check_working_tree(&self) -> Result<()> {
+        let dirty = self.uncommitted_changes().await?;
+        if dirty.is_empty() {
+            return Ok(());
         }
-        info!("Refactor preserved green");
+        let advice = format!(
+            "working tree has uncommitted changes ({}); commit or stash them (`git stash -u`) before running a cycle",
+            dirty.join(", ")
+        );
+        if self.cfg.require_clean_tree {
+            return Err(anyhow!(advice));
+        }
+        warn!("{advice}");
         Ok(())
     }
 
-    fn build_tester_instructions(&self) -> String {
-        let mut instructions = String::new();
-        if let Some(system_prompt) = &self.cfg.tester.system_prompt {
-            instructions.push_str(system_prompt);
-            instructions.push_str("\n\n");
+    /// Uncommitted paths, apart from the state file rgr itself keeps updating
+    async fn uncommitted_changes(&self) -> Result<Vec<String>> {
+        Ok(vcs::uncommitted_changes(&self.project_root)
+            .await?
+            .into_iter()
+            .filter(|p| !p.ends_with(state::STATE_FILE))
+            .collect())
+    }
+
+    async fn collect_context(&self, phase: Phase) -> Result<String> {
+        let opts = self.cfg.context_options(phase);
+        match &self.cycle_base {
+            Some(base) if self.cfg.incremental_context => {
+                workspace::collect_incremental_context(&self.project_root, base, &opts).await
+            }
+            _ => workspace::collect_context(&self.project_root, &opts),
         }
-        instructions.push_str("Task: Add exactly one failing unit test (red) for the next small behavior in the kata. Do not modify implementation code. Output ONLY JSON of schema LlmPatch.");
-        instructions
     }
 
-    fn build_implementor_instructions(&self, failing_output: &str) -> String {
-        let mut instructions = String::new();
-        if let Some(system_prompt) = &self.cfg.implementor.system_prompt {
-            instructions.push_str(system_prompt);
-            instructions.push_str("\n\n");
+    /// For `interactive_phases`, show the applied patch and ask whether to commit, discard or edit it
+    async fn review_patch(&self, phase: Phase, touched: &[PathBuf]) -> Result<Review> {
+        if !self.cfg.interactive_phases.contains(&phase) {
+            return Ok(Review::Commit);
         }
-        instructions.push_str("Task: Make the test suite pass with the simplest change. Keep edits minimal and focused. Use baby steps. Output ONLY JSON (LlmPatch).\n\nTest failures to fix:\n");
-        instructions.push_str(failing_output);
-        instructions
+        if !std::io::stdin().is_terminal() {
+            warn!(
+                "Not reviewing the {} patch: stdin is not a terminal",
+                phase.as_str()
+            );
+            return Ok(Review::Commit);
+        }
+        loop {
+            println!("===== {} PATCH =====", phase.as_str().to_uppercase());
+            print!("{}", vcs::diff_paths(&self.project_root, touched).await?);
+            print!("Commit this patch? [y]es / [n]o (discard) / [e]dit: ");
+            std::io::stdout().flush()?;
+            let mut line = String::new();
+            std::io::stdin().read_line(&mut line)?;
+            match line.trim() {
+                "y" | "yes" => return Ok(Review::Commit),
+                "n" | "no" => {
+                    vcs::discard_paths(&self.project_root, touched).await?;
+                    return Ok(Review::Discard);
+                }
+                "e" | "edit" => {
+                    let editor = std::env::var("EDITOR").unwrap_or_else(|_| "vi".to_string());
+                    let mut parts = editor.split_whitespace();
+                    let program = parts.next().unwrap_or("vi");
+                    let status = tokio::process::Command::new(program)
+                        .args(parts)
+                        .args(touched)
+                        .current_dir(&self.project_root)
+                        .status()
+                        .await
+                        .with_context(|| format!("running editor {editor}"))?;
+                    if !status.success() {
+                        warn!("Editor exited with {status}");
+                    }
+                }
+                _ => {}
+            }
+        }
+    }
+
+    /// With `pre_commit_compile_check`, the compiler errors of `patch`, or `None` if it compiles
+    async fn compile_failure(&self, patch: &LlmPatch) -> Result<Option<String>> {
+        if !self.cfg.pre_commit_compile_check {
+            return Ok(None);
+        }
+        let checked = response_validator::validate_rust_patch(
+            &self.project_root,
+            patch,
+            self.cfg.apply_options(),
+        )
+        .await;
+        Ok(checked
+            .err()
+            .map(|e| workspace::truncate_output(&format!("{e:#}"), self.cfg.max_test_output_bytes)))
+    }
+
+    /// Output of `lint_cmd` when it is configured and fails
+    async fn lint_failure(&self) -> Result<Option<String>> {
+        let Some(cmd) = &self.cfg.lint_cmd else {
+            return Ok(None);
+        };
+        let (ok, out) = workspace::run_linter(&self.project_root, cmd).await?;
+        Ok((!ok).then(|| workspace::truncate_output(&out, self.cfg.max_test_output_bytes)))
+    }
+
+    fn trailers(
+        &self,
+        phase: Phase,
+        cycle_number: usize,
+        attempt: Option<usize>,
+    ) -> Vec<(&'static str, String)> {
+        let model = &self.cfg.role(phase).provider.model;
+        let mut trailers = vcs::annotate_cycle_metadata(phase, cycle_number, model, attempt);
+        if let Some(kata) = &self.cfg.kata_name {
+            trailers.push(("Rgr-Kata", kata.clone()));
+        }
+        trailers
+    }
+
+    fn annotate_failures(&self, title: &str, output: &str) {
+        let report = self.cfg.test_runner.parser().parse(output);
+        self.annotations.test_failures(title, &report, output);
+    }
+
+    #[instrument(name = "rgr.hook", skip(self, cmd), fields(duration_ms = Empty))]
+    async fn run_hook(&self, hook: &str, cmd: &str) -> Result<()> {
+        let started = Instant::now();
+        info!("Hook started: {cmd}");
+        let (ok, out) = workspace::run_shell(&self.project_root, cmd).await?;
+        Span::current().record("duration_ms", started.elapsed().as_millis() as u64);
+        info!(success = ok, "Hook completed");
+        if !ok {
+            return Err(anyhow!("{hook} hook `{cmd}` failed:\n{out}"));
+        }
+        Ok(())
+    }
+
+    /// Ask the phase's provider for a patch, counting requests, errors and spend
+    async fn generate(&self, phase: Phase, context: &str, instructions: &str) -> Result<LlmPatch> {
+        let provider = match phase {
+            Phase::Tester => &self.tester,
+            Phase::Implementor => &self.implementor,
+            Phase::Refactorer => &self.refactorer,
+        };
+        let kind = self.cfg.role(phase).provider.kind.as_str();
+        metrics::counter!("rgr_api_requests_total", "provider" => kind).increment(1);
+        let result = provider
+            .generate_patch(phase.as_str(), context, instructions)
+            .await;
+        match &result {
+            Ok(patch) => {
+                if let Some(usage) = patch.usage {
+                    let model = &self.cfg.role(phase).provider.model;
+                    self.cost.lock().unwrap().record(model, usage);
+                }
+            }
+            Err(e) => {
+                metrics::counter!("rgr_api_errors_total", "kind" => api_error_kind(e)).increment(1);
+            }
+        }
+        result
+    }
+
+    async fn apply_patch(&self, label: &str, patch: &LlmPatch) -> Result<Vec<PathBuf>> {
+        if !self.cfg.keep_backups {
+            return workspace::apply_patch(&self.project_root, patch, self.cfg.apply_options())
+                .await;
+        }
+        let backup_dir = self.project_root.join(workspace::BACKUP_DIR).join(format!(
+            "{}-{label}",
+            chrono::Utc::now().format("%Y%m%d%H%M%S")
+        ));
+        workspace::backup_and_apply(
+            &self.project_root,
+            patch,
+            &backup_dir,
+            self.cfg.apply_options(),
+        )
+        .await
+    }
+
+    /// Gitignore the backup directory in a commit of its own, so no phase commit picks it up
+    async fn ignore_backups(&self) -> Result<()> {
+        let entry = format!("{}/", workspace::BACKUP_DIR);
+        if workspace::ensure_gitignored(&self.project_root, &entry).await? {
+            vcs::commit_paths(
+                &self.project_root,
+                &[self.project_root.join(".gitignore")],
+                &format!("chore: ignore {entry}"),
+                &[],
+            )
+            .await?;
+        }
+        Ok(())
+    }
+
+    /// One past the highest cycle number already used by a tag or cycle branch
+    async fn next_cycle_number(&self) -> Result<usize> {
+        let tags = vcs::list_tags(&self.project_root, &format!("{CYCLE_TAG_PREFIX}*")).await?;
+        let mut next = tags
+            .iter()
+            .filter_map(|t| t.strip_prefix(CYCLE_TAG_PREFIX)?.parse::<usize>().ok())
+            .max()
+            .unwrap_or(0)
+            + 1;
+        if self.cfg.per_cycle_branch {
+            while vcs::branch_exists(&self.project_root, &cycle_branch(next, "-tester")).await? {
+                next += 1;
+            }
+        }
+        Ok(next)
+    }
+
+    /// Hard reset to `target`, removing the untracked files the cycle created
+    async fn reset_cycle_to(&self, target: &str) -> Result<()> {
+        vcs::reset_hard_to(&self.project_root, target, Some(&self.cycle_untracked)).await
+    }
+
+    /// The roles' `request_log_dir`s inside the project, relative to it
+    fn request_log_dirs(&self) -> Vec<PathBuf> {
+        [Phase::Tester, Phase::Implementor, Phase::Refactorer]
+            .into_iter()
+            .filter_map(|phase| self.cfg.role(phase).provider.request_log_dir.clone())
+            .filter_map(|dir| {
+                dir.canonicalize()
+                    .or_else(|_| std::path::absolute(&dir))
+                    .ok()
+            })
+            .filter_map(|dir| Some(dir.strip_prefix(&self.project_root).ok()?.to_path_buf()))
+            .collect()
+    }
+
+    async fn start_cycle_branch(&self, cycle_number: usize, suffix: &str) -> Result<()> {
+        if self.cfg.per_cycle_branch {
+            vcs::checkout_new_branch(&self.project_root, &cycle_branch(cycle_number, suffix))
+                .await?;
+        }
+        Ok(())
+    }
+
+    async fn merge_cycle_branch(
+        &self,
+        main_branch: Option<&str>,
+        cycle_number: usize,
+    ) -> Result<()> {
+        if let Some(main_branch) = main_branch {
+            let branch = cycle_branch(cycle_number, "-refactor");
+            vcs::checkout_branch(&self.project_root, main_branch).await?;
+            vcs::merge_branch_no_ff(&self.project_root, &branch, &format!("Merge {branch}"))
+                .await?;
+        }
+        Ok(())
+    }
+
+    async fn build_tester_instructions(&self) -> String {
+        let existing = existing_tests(&self.project_root, &self.cfg).await;
+        self.cfg.tester_instructions(
+            count_tests(&self.project_root),
+            &existing,
+            &recent_struggles(
+                self.main_root.as_deref().unwrap_or(&self.project_root),
+                &self.cfg,
+            ),
+        )
+    }
+
+    fn build_implementor_instructions(&self, failing: &TestResult) -> String {
+        self.cfg.implementor_instructions(failing)
     }
 
     fn build_refactorer_instructions(&self) -> String {
-        let mut instructions = String::new();
-        if let Some(system_prompt) = &self.cfg.refactorer.system_prompt {
-            instructions.push_str(system_prompt);
-            instructions.push_str("\n\n");
+        self.cfg.refactorer_instructions()
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Review {
+    Commit,
+    Discard,
+}
+
+/// Record a phase's duration in metrics and on the current phase span
+fn finish_phase(phase: Phase, started: Instant) {
+    let elapsed = started.elapsed();
+    metrics::histogram!("rgr_phase_duration_seconds", "phase" => phase.as_str())
+        .record(elapsed.as_secs_f64());
+    Span::current().record("duration_ms", elapsed.as_millis() as u64);
+}
+
+fn api_error_kind(e: &anyhow::Error) -> &'static str {
+    if let Some(e) = e.downcast_ref::<reqwest::Error>() {
+        if e.is_timeout() {
+            "timeout"
+        } else if e.is_status() {
+            "http_status"
+        } else if e.is_decode() {
+            "decode"
+        } else {
+            "http"
         }
-        instructions.push_str("Task: Refactor to improve clarity, remove duplication, and prepare for change. Don't change behavior. After edits, all tests must still pass. Keep steps small. Output ONLY JSON (LlmPatch).");
-        instructions
+    } else if e.downcast_ref::<serde_json::Error>().is_some() {
+        "parse"
+    } else {
+        "other"
+    }
+}
+
+/// Branch for one phase of a cycle when `per_cycle_branch` is on:
+/// `rgr/cycle-NNNN-tester`, `rgr/cycle-NNNN-green`, then `rgr/cycle-NNNN-refactor` (the
+/// cycle's tag is plain `rgr/cycle-NNNN`)
+fn cycle_branch(cycle_number: usize, suffix: &str) -> String {
+    format!("{CYCLE_TAG_PREFIX}{cycle_number:04}{suffix}")
+}
+
+fn log_notes(phase: Phase, patch: &LlmPatch) {
+    if let Some(notes) = &patch.notes {
+        info!(role = phase.as_str(), notes = %notes, "LLM notes");
+    }
+}
+
+/// Append the patch notes as an `Rgr-Notes` git trailer (multi-line notes
+/// use trailer continuation lines)
+fn with_notes_trailer(message: &str, patch: &LlmPatch) -> String {
+    match patch.notes.as_deref().map(str::trim) {
+        Some(notes) if !notes.is_empty() => {
+            format!("{message}\n\nRgr-Notes: {}", notes.replace('\n', "\n "))
+        }
+        _ => message.to_string(),
+    }
+}
+
+/// Names of the tests the project already has, per `list_tests_cmd`; empty if it can't be run
+async fn existing_tests(project_root: &Path, cfg: &OrchestratorConfig) -> Vec<String> {
+    let Some(cmd) = cfg.list_tests_cmd(project_root) else {
+        return Vec::new();
+    };
+    workspace::list_tests(project_root, cmd)
+        .await
+        .unwrap_or_else(|e| {
+            warn!("Could not list existing tests: {e:#}");
+            Vec::new()
+        })
+}
+
+/// Of the last `tester_recent_failure_cycles` cycles, those where an implementor attempt failed
+fn recent_struggles(project_root: &Path, cfg: &OrchestratorConfig) -> Vec<CycleRecord> {
+    if cfg.tester_recent_failure_cycles == 0 {
+        return Vec::new();
+    }
+    let mut records = state::load_records(project_root).unwrap_or_else(|e| {
+        warn!("Could not read {}: {e:#}", state::STATE_FILE);
+        Vec::new()
+    });
+    let recent = records.split_off(
+        records
+            .len()
+            .saturating_sub(cfg.tester_recent_failure_cycles),
+    );
+    recent
+        .into_iter()
+        .filter(|r| r.implementor_failures.is_some())
+        .collect()
+}
+
+/// Existing test count for the tester prompt; only Rust `#[test]` functions are counted
+fn count_tests(project_root: &Path) -> Option<usize> {
+    if workspace::detect_language(project_root) != workspace::Language::Rust {
+        return None;
+    }
+    workspace::count_test_functions(project_root).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn from_snapshot_takes_its_config_and_head() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path().to_path_buf();
+        vcs::ensure_repo(&root, &[]).await.unwrap();
+        let mut cfg = OrchestratorConfig::example();
+        cfg.kata_name = Some("Bowling Game".into());
+        let orch = Orchestrator::new(root.clone(), cfg).await.unwrap();
+        let snapshot = orch.snapshot().await.unwrap();
+        std::fs::write(root.join("lib.rs"), "").unwrap();
+        vcs::commit_paths(&root, &[root.join("lib.rs")], "after the snapshot", &[])
+            .await
+            .unwrap();
+
+        let restored = Orchestrator::from_snapshot(root.clone(), &snapshot)
+            .await
+            .unwrap();
+
+        assert_eq!(restored.cfg.kata_name.as_deref(), Some("Bowling Game"));
+        assert_eq!(vcs::get_head_commit(&root).await.ok(), snapshot.head);
+        assert!(!root.join("lib.rs").exists());
+    }
+
+    #[tokio::test]
+    async fn phase_commits_use_commit_message_prefix() {
+        let repo = tempfile::tempdir().unwrap();
+        let patches = tempfile::tempdir().unwrap();
+        let root = repo.path().to_path_buf();
+        for (role, file, content) in [
+            ("tester", "tests/expected.txt", "done"),
+            ("implementor", "done.txt", "done"),
+            ("refactorer", "done.txt", "done\n"),
+        ] {
+            let patch = serde_json::json!({
+                "files": [{"path": file, "mode": "rewrite", "content": content}]
+            });
+            std::fs::write(
+                patches.path().join(format!("0000-{role}.json")),
+                patch.to_string(),
+            )
+            .unwrap();
+        }
+        let mut cfg = OrchestratorConfig::example();
+        for role in [&mut cfg.tester, &mut cfg.implementor, &mut cfg.refactorer] {
+            let provider = &mut role.provider;
+            provider.kind = providers::ProviderKind::LocalFile;
+            provider.patch_dir = Some(patches.path().to_path_buf());
+        }
+        cfg.test_cmd = "test -f done.txt".into();
+        cfg.commit_message_prefix = HashMap::from([
+            (Phase::Tester, "test(kata):".to_string()),
+            (Phase::Implementor, "feat(kata):".to_string()),
+            (Phase::Refactorer, "refactor(kata):".to_string()),
+        ]);
+        let mut orch = Orchestrator::new(root.clone(), cfg).await.unwrap();
+
+        let summary = orch.red_green_refactor_cycle().await.unwrap();
+
+        assert!(summary.success);
+        for (commit, subject) in [
+            (summary.tester_commit, "test(kata): add failing test"),
+            (
+                summary.green_commit,
+                "feat(kata): make tests pass (attempt 1)",
+            ),
+            (summary.refactor_commit, "refactor(kata): improve design"),
+        ] {
+            assert_eq!(
+                vcs::commit_subject(&root, &commit.unwrap()).await.unwrap(),
+                subject
+            );
+        }
+    }
+
+    #[test]
+    fn default_commit_message_uses_conventional_prefixes() {
+        let cfg = OrchestratorConfig::example();
+        assert_eq!(
+            cfg.default_commit_message(Phase::Tester),
+            "test: add failing test"
+        );
+        assert_eq!(
+            cfg.default_commit_message(Phase::Implementor),
+            "feat: make tests pass"
+        );
+        assert_eq!(
+            cfg.default_commit_message(Phase::Refactorer),
+            "refactor: improve design"
+        );
+    }
+
+    #[test]
+    fn default_commit_message_honours_commit_message_prefix() {
+        let mut cfg = OrchestratorConfig::example();
+        cfg.commit_message_prefix
+            .insert(Phase::Implementor, "feat(kata):".into());
+        assert_eq!(
+            cfg.default_commit_message(Phase::Implementor),
+            "feat(kata): make tests pass"
+        );
+        assert_eq!(
+            cfg.default_commit_message(Phase::Tester),
+            "test: add failing test"
+        );
     }
 }
@@ -0,0 +1,185 @@
+use super::{
+    LlmPatch, LlmProvider, ProviderConfig, TokenUsage, build_user_prompt, parse_patch,
+    request_log::{LoggedRequest, RequestLog},
+};
+use anyhow::{Context, Result, anyhow};
+use async_trait::async_trait;
+use serde::{Deserialize, Serialize};
+use tracing::{debug, trace, warn};
+
+const ANTHROPIC_VERSION: &str = "2023-06-01";
+/// Room for the patch itself; the thinking budget is added on top
+const MAX_OUTPUT_TOKENS: u32 = 8192;
+
+pub struct AnthropicProvider {
+    cfg: ProviderConfig,
+    client: reqwest::Client,
+    base: String,
+    api_key: String,
+}
+
+impl AnthropicProvider {
+    pub fn new(cfg: ProviderConfig) -> Result<Self> {
+        let client = reqwest::Client::builder().build()?;
+        let base = cfg
+            .base_url
+            .clone()
+            .unwrap_or_else(|| "https://api.anthropic.com".to_string());
+        let env_key = cfg
+            .api_key_env
+            .clone()
+            .unwrap_or_else(|| "ANTHROPIC_API_KEY".to_string());
+        let api_key =
+            std::env::var(&env_key).with_context(|| format!("missing env var {env_key}"))?;
+        if cfg.seed.is_some() {
+            debug!("Anthropic provider ignores seed");
+        }
+        if cfg.thinking_budget_tokens.is_some() && !supports_thinking(&cfg.model) {
+            warn!(
+                "thinking_budget_tokens is ignored: {} does not support extended thinking",
+                cfg.model
+            );
+        }
+        Ok(Self {
+            cfg,
+            client,
+            base,
+            api_key,
+        })
+    }
+}
+
+/// Extended thinking arrived with Claude 3.7 Sonnet; older 3.x models reject it
+fn supports_thinking(model: &str) -> bool {
+    !model.starts_with("claude-3-") || model.starts_with("claude-3-7")
+}
+
+#[derive(Debug, Serialize)]
+struct MessagesReq<'a> {
+    model: &'a str,
+    max_tokens: u32,
+    system: &'a str,
+    messages: Vec<Message<'a>>,
+    /// Must be left at its default when thinking is enabled
+    #[serde(skip_serializing_if = "Option::is_none")]
+    temperature: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    thinking: Option<Thinking>,
+}
+
+#[derive(Debug, Serialize)]
+struct Message<'a> {
+    role: &'a str,
+    content: &'a str,
+}
+
+#[derive(Debug, Serialize)]
+struct Thinking {
+    r#type: &'static str,
+    budget_tokens: u32,
+}
+
+#[derive(Debug, Deserialize)]
+struct MessagesResp {
+    content: Vec<ContentBlock>,
+    usage: Option<Usage>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum ContentBlock {
+    Text {
+        text: String,
+    },
+    Thinking {
+        thinking: String,
+    },
+    #[serde(other)]
+    Other,
+}
+
+#[derive(Debug, Deserialize)]
+struct Usage {
+    #[serde(default)]
+    input_tokens: u64,
+    #[serde(default)]
+    output_tokens: u64,
+}
+
+#[async_trait]
+impl LlmProvider for AnthropicProvider {
+    async fn generate_patch(
+        &self,
+        role: &str,
+        context: &str,
+        instructions: &str,
+    ) -> Result<LlmPatch> {
+        let url = format!("{}/v1/messages", self.base.trim_end_matches('/'));
+        let user = build_user_prompt(role, context, instructions);
+        let budget = self
+            .cfg
+            .thinking_budget_tokens
+            .filter(|_| supports_thinking(&self.cfg.model));
+        let system = self.cfg.system_prompt();
+        let req = MessagesReq {
+            model: &self.cfg.model,
+            max_tokens: MAX_OUTPUT_TOKENS + budget.unwrap_or(0),
+            system: &system,
+            messages: vec![Message {
+                role: "user",
+                content: &user,
+            }],
+            temperature: budget.is_none().then_some(0.2),
+            thinking: budget.map(|budget_tokens| Thinking {
+                r#type: "enabled",
+                budget_tokens,
+            }),
+        };
+        let log = RequestLog::start(
+            self.cfg.request_log_dir.as_deref(),
+            &LoggedRequest {
+                role: role.to_string(),
+                model: self.cfg.model.clone(),
+                temperature: req.temperature.unwrap_or(1.0),
+                system_prompt: system.clone(),
+                user_prompt: user.clone(),
+            },
+        );
+        let resp = self
+            .client
+            .post(&url)
+            .header("x-api-key", &self.api_key)
+            .header("anthropic-version", ANTHROPIC_VERSION)
+            .json(&req)
+            .send()
+            .await?
+            .error_for_status()?;
+        let raw = resp.text().await?;
+        let parsed = serde_json::from_str::<MessagesResp>(&raw);
+        if let Some(log) = &log {
+            let text = parsed.as_ref().ok().and_then(|b| first_text(&b.content));
+            log.finish(&raw, text);
+        }
+        let body = parsed.with_context(|| format!("failed to parse Anthropic response: {raw}"))?;
+        for block in &body.content {
+            if let ContentBlock::Thinking { thinking } = block {
+                trace!(role, thinking = %thinking, "Model thinking");
+            }
+        }
+        // Thinking blocks never reach the patch parser, only the final answer does
+        let text = first_text(&body.content).ok_or_else(|| anyhow!("no text content"))?;
+        let mut patch = parse_patch(text)?;
+        patch.usage = body.usage.map(|u| TokenUsage {
+            input_tokens: u.input_tokens,
+            output_tokens: u.output_tokens,
+        });
+        Ok(patch)
+    }
+}
+
+fn first_text(content: &[ContentBlock]) -> Option<&str> {
+    content.iter().find_map(|b| match b {
+        ContentBlock::Text { text } => Some(text.as_str()),
+        _ => None,
+    })
+}
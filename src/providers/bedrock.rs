@@ -0,0 +1,108 @@
+use super::{
+    LlmPatch, LlmProvider, ProviderConfig, TokenUsage, build_user_prompt, parse_patch,
+    request_log::{LoggedRequest, RequestLog},
+};
+use anyhow::{Context, Result, anyhow};
+use async_trait::async_trait;
+use aws_sdk_bedrockruntime::Client;
+use aws_sdk_bedrockruntime::types::{
+    ContentBlock, ConversationRole, ConverseOutput, InferenceConfiguration, Message,
+    SystemContentBlock,
+};
+use tokio::sync::OnceCell;
+
+/// Models hosted on AWS Bedrock (Claude, Llama, Titan, ...) through the Converse API.
+/// Credentials come from the standard AWS chain (env vars, profile, instance metadata).
+pub struct BedrockProvider {
+    cfg: ProviderConfig,
+    model_id: String,
+    client: OnceCell<Client>,
+}
+
+impl BedrockProvider {
+    pub fn new(cfg: ProviderConfig) -> Result<Self> {
+        let model_id = cfg
+            .bedrock_model_id
+            .clone()
+            .unwrap_or_else(|| cfg.model.clone());
+        Ok(Self {
+            cfg,
+            model_id,
+            client: OnceCell::new(),
+        })
+    }
+
+    /// Loading the AWS config is async, so the client is built on first use
+    async fn client(&self) -> &Client {
+        self.client
+            .get_or_init(|| async {
+                let mut loader = aws_config::defaults(aws_config::BehaviorVersion::latest());
+                if let Some(region) = &self.cfg.aws_region {
+                    loader = loader.region(aws_config::Region::new(region.clone()));
+                }
+                Client::new(&loader.load().await)
+            })
+            .await
+    }
+}
+
+#[async_trait]
+impl LlmProvider for BedrockProvider {
+    async fn generate_patch(
+        &self,
+        role: &str,
+        context: &str,
+        instructions: &str,
+    ) -> Result<LlmPatch> {
+        let user = build_user_prompt(role, context, instructions);
+        let temperature = 0.2;
+        let log = RequestLog::start(
+            self.cfg.request_log_dir.as_deref(),
+            &LoggedRequest {
+                role: role.to_string(),
+                model: self.model_id.clone(),
+                temperature,
+                system_prompt: self.cfg.system_prompt(),
+                user_prompt: user.clone(),
+            },
+        );
+        let message = Message::builder()
+            .role(ConversationRole::User)
+            .content(ContentBlock::Text(user))
+            .build()?;
+        let resp = self
+            .client()
+            .await
+            .converse()
+            .model_id(&self.model_id)
+            .system(SystemContentBlock::Text(self.cfg.system_prompt()))
+            .messages(message)
+            .inference_config(
+                InferenceConfiguration::builder()
+                    .temperature(temperature)
+                    .build(),
+            )
+            .send()
+            .await
+            .with_context(|| format!("Bedrock converse call to {} failed", self.model_id))?;
+        let text = match resp.output() {
+            Some(ConverseOutput::Message(message)) => {
+                message.content().iter().find_map(|block| match block {
+                    ContentBlock::Text(text) => Some(text.as_str()),
+                    _ => None,
+                })
+            }
+            _ => None,
+        };
+        if let Some(log) = &log {
+            log.finish(&format!("{resp:?}"), text);
+        }
+        let text = text.ok_or_else(|| anyhow!("no text content"))?;
+        let mut patch = parse_patch(text)?;
+        patch.usage = resp.usage().map(|u| TokenUsage {
+            input_tokens: u.input_tokens().max(0) as u64,
+            output_tokens: u.output_tokens().max(0) as u64,
+        });
+        Ok(patch)
+    }
+}
@@ -0,0 +1,243 @@
+use super::{
+    LlmPatch, LlmProvider, ProviderConfig, TokenUsage, build_user_prompt, parse_patch,
+    request_log::{LoggedRequest, RequestLog},
+};
+use anyhow::{Context, Result, anyhow};
+use async_trait::async_trait;
+use serde::{Deserialize, Serialize};
+use tracing::debug;
+
+/// Cohere Chat API v2 (Command R / R+ / A models)
+pub struct CohereProvider {
+    cfg: ProviderConfig,
+    client: reqwest::Client,
+    base: String,
+    api_key: String,
+}
+
+impl CohereProvider {
+    pub fn new(cfg: ProviderConfig) -> Result<Self> {
+        let env_key = cfg
+            .api_key_env
+            .clone()
+            .unwrap_or_else(|| "COHERE_API_KEY".to_string());
+        let api_key =
+            std::env::var(&env_key).with_context(|| format!("missing env var {env_key}"))?;
+        Self::with_api_key(cfg, api_key)
+    }
+
+    fn with_api_key(cfg: ProviderConfig, api_key: String) -> Result<Self> {
+        let client = reqwest::Client::builder().build()?;
+        let base = cfg
+            .base_url
+            .clone()
+            .unwrap_or_else(|| "https://api.cohere.com".to_string());
+        if cfg.seed.is_some() {
+            debug!("Cohere provider ignores seed");
+        }
+        Ok(Self {
+            cfg,
+            client,
+            base,
+            api_key,
+        })
+    }
+}
+
+#[derive(Debug, Serialize)]
+struct ChatReq<'a> {
+    model: &'a str,
+    /// v2 replaced v1's `preamble` with a leading `system` message
+    messages: Vec<Message<'a>>,
+    temperature: f32,
+}
+
+#[derive(Debug, Serialize)]
+struct Message<'a> {
+    role: &'a str,
+    content: &'a str,
+}
+
+#[derive(Debug, Deserialize)]
+struct ChatResp {
+    message: RespMessage,
+    usage: Option<Usage>,
+}
+
+#[derive(Debug, Deserialize)]
+struct RespMessage {
+    #[serde(default)]
+    content: Vec<ContentItem>,
+}
+
+#[derive(Debug, Deserialize)]
+struct ContentItem {
+    text: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct Usage {
+    billed_units: Option<BilledUnits>,
+}
+
+#[derive(Debug, Deserialize)]
+struct BilledUnits {
+    #[serde(default)]
+    input_tokens: f64,
+    #[serde(default)]
+    output_tokens: f64,
+}
+
+#[async_trait]
+impl LlmProvider for CohereProvider {
+    async fn generate_patch(
+        &self,
+        role: &str,
+        context: &str,
+        instructions: &str,
+    ) -> Result<LlmPatch> {
+        let url = format!("{}/v2/chat", self.base.trim_end_matches('/'));
+        let user = build_user_prompt(role, context, instructions);
+        let system = self.cfg.system_prompt();
+        let req = ChatReq {
+            model: &self.cfg.model,
+            messages: vec![
+                Message {
+                    role: "system",
+                    content: &system,
+                },
+                Message {
+                    role: "user",
+                    content: &user,
+                },
+            ],
+            temperature: 0.2,
+        };
+        let log = RequestLog::start(
+            self.cfg.request_log_dir.as_deref(),
+            &LoggedRequest {
+                role: role.to_string(),
+                model: self.cfg.model.clone(),
+                temperature: req.temperature,
+                system_prompt: system.clone(),
+                user_prompt: user.clone(),
+            },
+        );
+        let resp = self
+            .client
+            .post(&url)
+            .bearer_auth(&self.api_key)
+            .json(&req)
+            .send()
+            .await?
+            .error_for_status()?;
+        let raw = resp.text().await?;
+        let parsed = serde_json::from_str::<ChatResp>(&raw);
+        if let Some(log) = &log {
+            let text = parsed.as_ref().ok().and_then(first_text);
+            log.finish(&raw, text);
+        }
+        let body = parsed.with_context(|| format!("failed to parse Cohere response: {raw}"))?;
+        let text = first_text(&body).ok_or_else(|| anyhow!("no text content"))?;
+        let mut patch = parse_patch(text)?;
+        patch.usage = body.usage.and_then(|u| u.billed_units).map(|b| TokenUsage {
+            input_tokens: b.input_tokens as u64,
+            output_tokens: b.output_tokens as u64,
+        });
+        Ok(patch)
+    }
+}
+
+fn first_text(body: &ChatResp) -> Option<&str> {
+    body.message.content.iter().find_map(|c| c.text.as_deref())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use wiremock::matchers::{body_partial_json, header, method, path};
+    use wiremock::{Mock, MockServer, ResponseTemplate};
+
+    fn provider(server: &MockServer) -> CohereProvider {
+        let cfg = ProviderConfig {
+            model: "command-r-plus".into(),
+            base_url: Some(server.uri()),
+            ..Default::default()
+        };
+        CohereProvider::with_api_key(cfg, "test-key".into()).unwrap()
+    }
+
+    #[tokio::test]
+    async fn sends_a_v2_chat_request_and_reads_the_first_text_item() {
+        let server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .and(path("/v2/chat"))
+            .and(header("authorization", "Bearer test-key"))
+            .and(body_partial_json(serde_json::json!({
+                "model": "command-r-plus",
+                "messages": [{ "role": "system" }, { "role": "user" }],
+            })))
+            .respond_with(ResponseTemplate::new(200).set_body_json(serde_json::json!({
+                "message": {
+                    "role": "assistant",
+                    "content": [{
+                        "type": "text",
+                        "text": r#"{"files":[{"path":"src/lib.rs","mode":"append","content":"// x\n"}]}"#,
+                    }],
+                },
+                "usage": { "billed_units": { "input_tokens": 12, "output_tokens": 34 } },
+            })))
+            .expect(1)
+            .mount(&server)
+            .await;
+
+        let patch = provider(&server)
+            .generate_patch("tester", "context", "instructions")
+            .await
+            .unwrap();
+        assert_eq!(patch.files.len(), 1);
+        assert_eq!(patch.files[0].path, "src/lib.rs");
+        assert_eq!(
+            patch.usage,
+            Some(TokenUsage {
+                input_tokens: 12,
+                output_tokens: 34,
+            })
+        );
+    }
+
+    #[tokio::test]
+    async fn reports_a_response_without_text() {
+        let server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .and(path("/v2/chat"))
+            .respond_with(
+                ResponseTemplate::new(200)
+                    .set_body_json(serde_json::json!({ "message": { "content": [] } })),
+            )
+            .mount(&server)
+            .await;
+
+        let err = provider(&server)
+            .generate_patch("tester", "context", "instructions")
+            .await
+            .unwrap_err();
+        assert!(err.to_string().contains("no text content"), "{err:#}");
+    }
+
+    #[tokio::test]
+    async fn fails_on_an_http_error() {
+        let server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .respond_with(ResponseTemplate::new(401))
+            .mount(&server)
+            .await;
+
+        assert!(
+            provider(&server)
+                .generate_patch("tester", "context", "instructions")
+                .await
+                .is_err()
+        );
+    }
+}
@@ -0,0 +1,107 @@
+use super::{EditMode, LlmPatch, LlmProvider};
+use anyhow::{Result, anyhow};
+use async_trait::async_trait;
+use futures::stream::{FuturesUnordered, StreamExt};
+use tracing::{info, warn};
+
+/// Queries several providers concurrently and returns the patch that at least
+/// `quorum` of them agree on. Without agreement, the fastest successful patch wins.
+pub struct ConsensusProvider {
+    providers: Vec<Box<dyn LlmProvider>>,
+    quorum: usize,
+}
+
+impl ConsensusProvider {
+    pub fn new(providers: Vec<Box<dyn LlmProvider>>, quorum: usize) -> Result<Self> {
+        if providers.is_empty() {
+            return Err(anyhow!(
+                "consensus provider needs at least one consensus_providers entry"
+            ));
+        }
+        Ok(Self { providers, quorum })
+    }
+}
+
+/// Normalized view of a patch used for comparison: files sorted by path,
+/// trailing whitespace and line-ending differences ignored
+fn patch_key(patch: &LlmPatch) -> Vec<(String, EditMode, String)> {
+    let mut key: Vec<_> = patch
+        .files
+        .iter()
+        .map(|f| {
+            let content = f
+                .content
+                .lines()
+                .map(str::trim_end)
+                .collect::<Vec<_>>()
+                .join("\n");
+            (
+                f.path.trim_start_matches("./").to_string(),
+                f.mode.clone(),
+                content.trim_end().to_string(),
+            )
+        })
+        .collect();
+    key.sort();
+    key
+}
+
+#[async_trait]
+impl LlmProvider for ConsensusProvider {
+    async fn generate_patch(
+        &self,
+        role: &str,
+        context: &str,
+        instructions: &str,
+    ) -> Result<LlmPatch> {
+        let mut pending: FuturesUnordered<_> = self
+            .providers
+            .iter()
+            .enumerate()
+            .map(|(i, p)| async move { (i, p.generate_patch(role, context, instructions).await) })
+            .collect();
+
+        // Successful patches in completion order, so index 0 is the fastest
+        let mut patches: Vec<LlmPatch> = Vec::new();
+        let mut errors = Vec::new();
+        while let Some((i, result)) = pending.next().await {
+            match result {
+                Ok(patch) => patches.push(patch),
+                Err(e) => {
+                    warn!("consensus provider #{i} failed: {e:#}");
+                    errors.push(format!("#{i}: {e:#}"));
+                }
+            }
+        }
+        if patches.is_empty() {
+            return Err(anyhow!(
+                "all consensus providers failed: {}",
+                errors.join("; ")
+            ));
+        }
+
+        let keys: Vec<_> = patches.iter().map(patch_key).collect();
+        let (best, votes) = (0..keys.len())
+            .map(|i| (i, keys.iter().filter(|k| **k == keys[i]).count()))
+            .fold((0, 0), |acc, cur| if cur.1 > acc.1 { cur } else { acc });
+        let winner = if votes >= self.quorum {
+            info!(
+                "consensus reached: {votes}/{} providers agree",
+                self.providers.len()
+            );
+            best
+        } else {
+            warn!(
+                "no consensus (best {votes}/{}, quorum {}); using fastest response",
+                self.providers.len(),
+                self.quorum
+            );
+            0
+        };
+        // Every call was paid for, so the winner carries the combined usage
+        let usage = patches.iter().filter_map(|p| p.usage).reduce(|a, b| a + b);
+        let mut patch = patches.swap_remove(winner);
+        patch.usage = usage;
+        Ok(patch)
+    }
+}
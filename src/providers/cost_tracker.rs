@@ -0,0 +1,119 @@
+use super::TokenUsage;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use tracing::debug;
+
+/// Published per-token prices, in USD per million tokens
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, JsonSchema)]
+pub struct TokenPricing {
+    pub input_per_million_usd: f64,
+    pub output_per_million_usd: f64,
+}
+
+impl TokenPricing {
+    pub const fn new(input_per_million_usd: f64, output_per_million_usd: f64) -> Self {
+        Self {
+            input_per_million_usd,
+            output_per_million_usd,
+        }
+    }
+
+    pub fn cost_usd(&self, usage: TokenUsage) -> f64 {
+        (usage.input_tokens as f64 * self.input_per_million_usd
+            + usage.output_tokens as f64 * self.output_per_million_usd)
+            / 1_000_000.0
+    }
+}
+
+/// List prices for commonly used models; extend or override via `model_pricing` in the config
+pub fn default_pricing() -> HashMap<String, TokenPricing> {
+    [
+        ("gpt-4o", TokenPricing::new(2.50, 10.00)),
+        ("gpt-4o-mini", TokenPricing::new(0.15, 0.60)),
+        ("gpt-4.1", TokenPricing::new(2.00, 8.00)),
+        ("gpt-4.1-mini", TokenPricing::new(0.40, 1.60)),
+        ("o3-mini", TokenPricing::new(1.10, 4.40)),
+        ("gemini-1.5-pro", TokenPricing::new(1.25, 5.00)),
+        ("gemini-1.5-flash", TokenPricing::new(0.075, 0.30)),
+        ("gemini-2.0-flash", TokenPricing::new(0.10, 0.40)),
+        ("claude-3-5-sonnet-latest", TokenPricing::new(3.00, 15.00)),
+        ("deepseek-chat", TokenPricing::new(0.27, 1.10)),
+        ("deepseek-reasoner", TokenPricing::new(0.55, 2.19)),
+    ]
+    .into_iter()
+    .map(|(model, pricing)| (model.to_string(), pricing))
+    .collect()
+}
+
+/// The estimated total spend went over `budget_limit_usd`
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct BudgetExceededError {
+    pub spent_usd: f64,
+    pub limit_usd: f64,
+}
+
+impl std::fmt::Display for BudgetExceededError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "budget exceeded: spent ${:.4} of the ${:.4} limit",
+            self.spent_usd, self.limit_usd
+        )
+    }
+}
+
+impl std::error::Error for BudgetExceededError {}
+
+/// Accumulates the estimated spend of provider calls, overall and for the current cycle
+#[derive(Debug, Clone)]
+pub struct CostTracker {
+    pricing: HashMap<String, TokenPricing>,
+    total_usd: f64,
+    cycle_usd: f64,
+}
+
+impl CostTracker {
+    pub fn new(pricing: HashMap<String, TokenPricing>) -> Self {
+        Self {
+            pricing,
+            total_usd: 0.0,
+            cycle_usd: 0.0,
+        }
+    }
+
+    /// Add the cost of one call; models without known pricing count as free
+    pub fn record(&mut self, model: &str, usage: TokenUsage) -> f64 {
+        let Some(pricing) = self.pricing.get(model) else {
+            debug!("no pricing for model {model}; not counting its cost");
+            return 0.0;
+        };
+        let cost = pricing.cost_usd(usage);
+        self.total_usd += cost;
+        self.cycle_usd += cost;
+        cost
+    }
+
+    pub fn start_cycle(&mut self) {
+        self.cycle_usd = 0.0;
+    }
+
+    pub fn total_cost_usd(&self) -> f64 {
+        self.total_usd
+    }
+
+    pub fn cycle_cost_usd(&self) -> f64 {
+        self.cycle_usd
+    }
+
+    /// Fails once the total spend is over `limit` USD
+    pub fn check_budget(&self, limit: f64) -> Result<(), BudgetExceededError> {
+        if self.total_usd > limit {
+            return Err(BudgetExceededError {
+                spent_usd: self.total_usd,
+                limit_usd: limit,
+            });
+        }
+        Ok(())
+    }
+}
@@ -1,7 +1,11 @@
-use super::{LlmPatch, LlmProvider, ProviderConfig, extract_json_object};
+use super::{
+    LlmPatch, LlmProvider, ProviderConfig, TokenUsage, build_user_prompt, parse_patch,
+    request_log::{LoggedRequest, RequestLog},
+};
 use anyhow::{Context, Result, anyhow};
 use async_trait::async_trait;
 use serde::{Deserialize, Serialize};
+use tracing::debug;
 
 pub struct GeminiProvider {
     cfg: ProviderConfig,
@@ -23,6 +27,9 @@ impl GeminiProvider {
             .unwrap_or_else(|| "GEMINI_API_KEY".to_string());
         let api_key =
             std::env::var(&env_key).with_context(|| format!("missing env var {env_key}"))?;
+        if cfg.seed.is_some() {
+            debug!("Gemini provider ignores seed");
+        }
         Ok(Self {
             cfg,
             client,
@@ -44,16 +51,30 @@ struct Content<'a> {
 #[derive(Debug, Serialize)]
 struct GenReq<'a> {
     contents: Vec<Content<'a>>,
-    generation_config: GenCfg,
+    generation_config: GenCfg<'a>,
 }
 #[derive(Debug, Serialize)]
-struct GenCfg {
+struct GenCfg<'a> {
     temperature: f32,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    response_mime_type: Option<&'static str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    response_schema: Option<&'a serde_json::Value>,
 }
 
 #[derive(Debug, Deserialize)]
 struct GenResp {
     candidates: Vec<Cand>,
+    #[serde(rename = "usageMetadata")]
+    usage_metadata: Option<UsageMetadata>,
+}
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct UsageMetadata {
+    #[serde(default)]
+    prompt_token_count: u64,
+    #[serde(default)]
+    candidates_token_count: u64,
 }
 #[derive(Debug, Deserialize)]
 struct Cand {
@@ -82,17 +103,33 @@ impl LlmProvider for GeminiProvider {
             self.cfg.model,
             self.api_key
         );
-        let sys = "You are a code-modifying agent. Respond ONLY with a valid JSON object matching schema LlmPatch { files:[{path, mode: 'rewrite'|'append', content}], commit_message?, notes? }. No prose.";
-        let user = format!(
-            "Role: {role}\nInstructions:\n{instructions}\n\nProject context (truncated):\n{context}"
-        );
+        let sys = &self.cfg.system_prompt();
+        // Constrained JSON output arrived with Gemini 1.5
+        let json_mode = self.cfg.json_mode.unwrap_or_else(|| {
+            !self.cfg.model.starts_with("gemini-1.0") && self.cfg.model != "gemini-pro"
+        });
+        let user = build_user_prompt(role, context, instructions);
         let req = GenReq {
             contents: vec![Content {
                 role: "user",
                 parts: vec![ContentPart { text: sys }, ContentPart { text: &user }],
             }],
-            generation_config: GenCfg { temperature: 0.2 },
+            generation_config: GenCfg {
+                temperature: 0.2,
+                response_mime_type: json_mode.then_some("application/json"),
+                response_schema: self.cfg.response_schema.as_ref().filter(|_| json_mode),
+            },
         };
+        let log = RequestLog::start(
+            self.cfg.request_log_dir.as_deref(),
+            &LoggedRequest {
+                role: role.to_string(),
+                model: self.cfg.model.clone(),
+                temperature: req.generation_config.temperature,
+                system_prompt: sys.to_string(),
+                user_prompt: user.clone(),
+            },
+        );
         let resp = self
             .client
             .post(&url)
@@ -100,17 +137,29 @@ impl LlmProvider for GeminiProvider {
             .send()
             .await?
             .error_for_status()?;
-        let body: GenResp = resp.json().await?;
-        let text = body
-            .candidates
-            .iter()
-            .flat_map(|c| c.content.parts.iter())
-            .filter_map(|p| p.text.as_ref())
-            .next()
-            .ok_or_else(|| anyhow!("no candidates"))?;
-        let json_str = extract_json_object(text).unwrap_or(text);
-        let patch: LlmPatch = serde_json::from_str(json_str)
-            .with_context(|| format!("failed to parse model JSON: {json_str}"))?;
+        let raw = resp.text().await?;
+        let parsed = serde_json::from_str::<GenResp>(&raw);
+        if let Some(log) = &log {
+            let text = parsed.as_ref().ok().and_then(|b| first_text(&b.candidates));
+            log.finish(&raw, text);
+        }
+        let body = parsed.with_context(|| format!("failed to parse Gemini response: {raw}"))?;
+        let text = first_text(&body.candidates).ok_or_else(|| anyhow!("no candidates"))?;
+        let mut patch = match serde_json::from_str(text) {
+            Ok(patch) if json_mode => patch,
+            _ => parse_patch(text)?,
+        };
+        patch.usage = body.usage_metadata.map(|u| TokenUsage {
+            input_tokens: u.prompt_token_count,
+            output_tokens: u.candidates_token_count,
+        });
         Ok(patch)
     }
 }
+
+fn first_text(candidates: &[Cand]) -> Option<&str> {
+    candidates
+        .iter()
+        .flat_map(|c| c.content.parts.iter())
+        .find_map(|p| p.text.as_deref())
+}
@@ -0,0 +1,75 @@
+use super::openai::OpenAiProvider;
+use super::{LlmPatch, LlmProvider, ProviderConfig};
+use anyhow::{Result, anyhow};
+use async_trait::async_trait;
+use serde::Deserialize;
+use tracing::debug;
+
+/// Groq's API is OpenAI-compatible; this wrapper adds Groq defaults and
+/// turns its rate-limit responses into readable errors.
+pub struct GroqProvider {
+    inner: OpenAiProvider,
+}
+
+impl GroqProvider {
+    pub fn new(mut cfg: ProviderConfig) -> Result<Self> {
+        cfg.base_url
+            .get_or_insert_with(|| "https://api.groq.com/openai/v1".to_string());
+        cfg.api_key_env
+            .get_or_insert_with(|| "GROQ_API_KEY".to_string());
+        Ok(Self {
+            inner: OpenAiProvider::new(cfg)?,
+        })
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct ErrorBody {
+    error: ErrorDetail,
+}
+#[derive(Debug, Deserialize)]
+struct ErrorDetail {
+    #[serde(rename = "type")]
+    kind: Option<String>,
+    message: Option<String>,
+}
+
+#[async_trait]
+impl LlmProvider for GroqProvider {
+    async fn generate_patch(
+        &self,
+        role: &str,
+        context: &str,
+        instructions: &str,
+    ) -> Result<LlmPatch> {
+        let (resp, log) = self.inner.send_chat(role, context, instructions).await?;
+        let header = |name: &str| {
+            resp.headers()
+                .get(name)
+                .and_then(|v| v.to_str().ok())
+                .map(String::from)
+        };
+        let remaining = header("x-ratelimit-remaining-requests");
+        let retry_after = header("retry-after");
+        if let Some(remaining) = &remaining {
+            debug!("Groq remaining requests: {remaining}");
+        }
+
+        let status = resp.status();
+        if !status.is_success() {
+            let body = resp.text().await.unwrap_or_default();
+            if let Ok(err) = serde_json::from_str::<ErrorBody>(&body)
+                && err.error.kind.as_deref() == Some("rate_limit_exceeded")
+            {
+                return Err(anyhow!(
+                    "Groq rate limit exceeded (remaining requests: {}, retry after: {}s): {}",
+                    remaining.as_deref().unwrap_or("unknown"),
+                    retry_after.as_deref().unwrap_or("unknown"),
+                    err.error.message.unwrap_or_default()
+                ));
+            }
+            return Err(anyhow!("Groq request failed with {status}: {body}"));
+        }
+        self.inner.patch_from_response(resp, log).await
+    }
+}
@@ -0,0 +1,70 @@
+use super::openai::OpenAiProvider;
+use super::{LlmPatch, LlmProvider, ProviderConfig};
+use anyhow::{Result, anyhow};
+use async_trait::async_trait;
+use serde::Deserialize;
+
+/// A LiteLLM proxy speaks the OpenAI API for whatever models it fronts; this wrapper
+/// adds the proxy defaults and turns its budget errors into readable ones.
+pub struct LiteLlmProvider {
+    inner: OpenAiProvider,
+}
+
+impl LiteLlmProvider {
+    pub fn new(mut cfg: ProviderConfig) -> Result<Self> {
+        cfg.base_url
+            .get_or_insert_with(|| "http://localhost:4000".to_string());
+        let env_key = cfg
+            .api_key_env
+            .clone()
+            .unwrap_or_else(|| "LITELLM_API_KEY".to_string());
+        // A proxy without a master key accepts any key
+        let api_key = std::env::var(env_key).unwrap_or_default();
+        Ok(Self {
+            inner: OpenAiProvider::with_api_key(cfg, api_key)?,
+        })
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct ErrorBody {
+    error: ErrorDetail,
+}
+#[derive(Debug, Deserialize)]
+struct ErrorDetail {
+    #[serde(rename = "type")]
+    kind: Option<String>,
+    message: Option<String>,
+}
+
+#[async_trait]
+impl LlmProvider for LiteLlmProvider {
+    async fn generate_patch(
+        &self,
+        role: &str,
+        context: &str,
+        instructions: &str,
+    ) -> Result<LlmPatch> {
+        let (resp, log) = self.inner.send_chat(role, context, instructions).await?;
+        let status = resp.status();
+        if !status.is_success() {
+            let body = resp.text().await.unwrap_or_default();
+            if let Ok(err) = serde_json::from_str::<ErrorBody>(&body) {
+                let message = err.error.message.unwrap_or_default();
+                match err.error.kind.as_deref() {
+                    Some("budget_exceeded") => {
+                        return Err(anyhow!("LiteLLM budget exceeded: {message}"));
+                    }
+                    Some("auth_error") => {
+                        return Err(anyhow!(
+                            "LiteLLM rejected the API key (check api_key_env): {message}"
+                        ));
+                    }
+                    _ => {}
+                }
+            }
+            return Err(anyhow!("LiteLLM request failed with {status}: {body}"));
+        }
+        self.inner.patch_from_response(resp, log).await
+    }
+}
@@ -0,0 +1,51 @@
+use super::{LlmPatch, LlmProvider, ProviderConfig};
+use anyhow::{Context, Result, anyhow};
+use async_trait::async_trait;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use tracing::{info, warn};
+
+/// Replays pre-authored patches from `{dir}/{call_index:04}-{role}.json`, one file per call,
+/// for offline demos and scripted kata walkthroughs
+pub struct LocalFileProvider {
+    dir: PathBuf,
+    call_index: AtomicUsize,
+}
+
+impl LocalFileProvider {
+    pub fn new(cfg: ProviderConfig) -> Result<Self> {
+        let dir = cfg
+            .patch_dir
+            .ok_or_else(|| anyhow!("local_file provider needs patch_dir"))?;
+        Ok(Self {
+            dir,
+            call_index: AtomicUsize::new(0),
+        })
+    }
+}
+
+#[async_trait]
+impl LlmProvider for LocalFileProvider {
+    async fn generate_patch(
+        &self,
+        role: &str,
+        _context: &str,
+        _instructions: &str,
+    ) -> Result<LlmPatch> {
+        let index = self.call_index.fetch_add(1, Ordering::SeqCst);
+        let path = self.dir.join(format!("{index:04}-{role}.json"));
+        let s = match tokio::fs::read_to_string(&path).await {
+            Ok(s) => s,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                warn!(
+                    "No scripted patch at {}; using an empty patch",
+                    path.display()
+                );
+                return Ok(LlmPatch::default());
+            }
+            Err(e) => return Err(e).with_context(|| format!("reading {}", path.display())),
+        };
+        info!("Using scripted patch {}", path.display());
+        serde_json::from_str(&s).with_context(|| format!("parsing {}", path.display()))
+    }
+}
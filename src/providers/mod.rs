@@ -1,19 +1,71 @@
-use anyhow::Result;
+use anyhow::{Context, Result};
+use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
 
+pub mod anthropic;
+#[cfg(feature = "bedrock")]
+pub mod bedrock;
+pub mod cohere;
+pub mod consensus;
+pub mod cost_tracker;
 pub mod gemini;
+pub mod groq;
+pub mod litellm;
+pub mod local_file;
 pub mod mock;
 pub mod openai;
+pub mod openai_batch;
+#[cfg(unix)]
+pub mod pipe;
+pub mod request_log;
+pub mod response_validator;
+pub mod together;
+pub mod xai;
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+pub use request_log::replay_from_log;
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize, JsonSchema)]
 #[serde(rename_all = "snake_case")]
 pub enum ProviderKind {
     OpenAi,
+    OpenAiBatch,
     Gemini,
+    Groq,
+    Xai,
+    TogetherAi,
+    Anthropic,
+    Bedrock,
+    Cohere,
+    LiteLlm,
+    LocalFile,
+    Pipe,
+    Consensus,
+    #[default]
     Mock,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+impl ProviderKind {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ProviderKind::OpenAi => "open_ai",
+            ProviderKind::OpenAiBatch => "open_ai_batch",
+            ProviderKind::Gemini => "gemini",
+            ProviderKind::Groq => "groq",
+            ProviderKind::Xai => "xai",
+            ProviderKind::TogetherAi => "together_ai",
+            ProviderKind::Anthropic => "anthropic",
+            ProviderKind::Bedrock => "bedrock",
+            ProviderKind::Cohere => "cohere",
+            ProviderKind::LiteLlm => "lite_llm",
+            ProviderKind::LocalFile => "local_file",
+            ProviderKind::Pipe => "pipe",
+            ProviderKind::Consensus => "consensus",
+            ProviderKind::Mock => "mock",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize, JsonSchema)]
 pub struct ProviderConfig {
     pub kind: ProviderKind,
     pub model: String,
@@ -27,13 +79,85 @@ pub struct ProviderConfig {
     pub api_key_header: Option<String>,
     /// Optional API key prefix value (defaults to "Bearer ", set to "" for raw keys)
     pub api_key_prefix: Option<String>,
+    /// Save every prompt and raw response here as `{timestamp}-{role}-{request,response}.json`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub request_log_dir: Option<std::path::PathBuf>,
+    /// Ask for JSON-only output (OpenAI `response_format: json_object`, Gemini
+    /// `response_mime_type: application/json`); unset = decide by model
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub json_mode: Option<bool>,
+    /// JSON Schema the Gemini response must follow (used with `json_mode`)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub response_schema: Option<serde_json::Value>,
+    /// Treat the model as an OpenAI reasoning model (no system message or temperature,
+    /// `max_completion_tokens`); unset = any `o<digit>` model, e.g. `o1-mini`, `o3-mini`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub is_reasoning_model: Option<bool>,
+    /// Output token limit for OpenAI-compatible providers (sent as `max_tokens`, or
+    /// `max_completion_tokens` for reasoning models)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_output_tokens: Option<u32>,
+    /// `low`, `medium` or `high`: how long an OpenAI reasoning model may think; ignored
+    /// for other models
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub reasoning_effort: Option<String>,
+    /// Have OpenAI-compatible providers return the patch as an `apply_patch` tool call
+    /// whose arguments follow the `LlmPatch` schema; unset = decide by model. Only used with
+    /// the `llm_patch_json` instruction format
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub use_tool_calling: Option<bool>,
+    /// How often `kind: open_ai_batch` checks on a submitted batch (default 60)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub batch_poll_interval_secs: Option<u64>,
+    /// Anthropic extended thinking budget (Claude 3.7 Sonnet and later); thinking is logged at trace level
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub thinking_budget_tokens: Option<u32>,
+    /// Stream the response (OpenAI-compatible providers) to surface tokens as they arrive
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub stream: bool,
+    /// AWS region for `kind: bedrock` (defaults to the AWS config/env region)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub aws_region: Option<String>,
+    /// Bedrock model id or ARN for `kind: bedrock` (defaults to `model`)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub bedrock_model_id: Option<String>,
+    /// Sampling seed for more reproducible responses (forwarded by OpenAI-compatible providers only)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub seed: Option<u64>,
+    /// Response format the system prompt asks for; filled in from `role_instruction_format`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub instruction_format: Option<InstructionFormat>,
+    /// Request tags passed through as `metadata` (LiteLLM logs them with spend, e.g. `{tags: [rgr]}`)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub metadata: Option<serde_json::Value>,
+    /// Directory of pre-authored `{NNNN}-{role}.json` patches for `kind: local_file`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub patch_dir: Option<std::path::PathBuf>,
+    /// Named pipe the prompt is written to for `kind: pipe` (created if missing)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub pipe_path: Option<std::path::PathBuf>,
+    /// Named pipe the patch is read back from (defaults to `<pipe_path>.response`)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub pipe_response_path: Option<std::path::PathBuf>,
+    /// Give up on a `kind: pipe` exchange after this long (default 600)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub pipe_timeout_secs: Option<u64>,
+    /// Inner providers queried concurrently when `kind: consensus`
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub consensus_providers: Vec<ProviderConfig>,
+    /// Number of identical patches needed to win a consensus vote (defaults to a majority)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub consensus_quorum: Option<usize>,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
 pub struct RoleProviderConfig {
     pub provider: ProviderConfig,
     /// Optional system prompt addon specific to your project
     pub system_prompt: Option<String>,
+    /// Context budget for this role, overriding the global `max_context_bytes`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_context_bytes: Option<usize>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -42,15 +166,22 @@ pub struct FileEdit {
     pub path: String,
     /// How to apply the content
     pub mode: EditMode,
-    /// Full new content (for Rewrite) or appended content (for Append)
+    /// Full new content (Rewrite), appended content (Append), inserted lines
+    /// (InsertAfterPattern) or the file's unified diff hunks (Patch)
     pub content: String,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
 #[serde(rename_all = "snake_case")]
 pub enum EditMode {
     Rewrite,
     Append,
+    /// Insert `content` on the line after the first line containing `pattern`
+    InsertAfterPattern {
+        pattern: String,
+    },
+    /// Apply `content`, the `@@` hunks of a unified diff, to the file
+    Patch,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize, Default)]
@@ -58,6 +189,26 @@ pub struct LlmPatch {
     pub files: Vec<FileEdit>,
     pub commit_message: Option<String>,
     pub notes: Option<String>,
+    /// Token usage reported by the API for the call that produced this patch
+    #[serde(skip)]
+    pub usage: Option<TokenUsage>,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct TokenUsage {
+    pub input_tokens: u64,
+    pub output_tokens: u64,
+}
+
+impl std::ops::Add for TokenUsage {
+    type Output = TokenUsage;
+
+    fn add(self, other: TokenUsage) -> TokenUsage {
+        TokenUsage {
+            input_tokens: self.input_tokens + other.input_tokens,
+            output_tokens: self.output_tokens + other.output_tokens,
+        }
+    }
 }
 
 #[async_trait::async_trait]
@@ -76,25 +227,357 @@ impl ProviderFactory {
     pub fn build(cfg: &ProviderConfig) -> Result<Box<dyn LlmProvider>> {
         match cfg.kind {
             ProviderKind::OpenAi => Ok(Box::new(openai::OpenAiProvider::new(cfg.clone())?)),
+            ProviderKind::OpenAiBatch => Ok(Box::new(openai_batch::OpenAiBatchProvider::new(
+                cfg.clone(),
+            )?)),
             ProviderKind::Gemini => Ok(Box::new(gemini::GeminiProvider::new(cfg.clone())?)),
+            ProviderKind::Groq => Ok(Box::new(groq::GroqProvider::new(cfg.clone())?)),
+            ProviderKind::Xai => Ok(Box::new(xai::XaiProvider::new(cfg.clone())?)),
+            ProviderKind::TogetherAi => {
+                Ok(Box::new(together::TogetherAiProvider::new(cfg.clone())?))
+            }
+            ProviderKind::Anthropic => {
+                Ok(Box::new(anthropic::AnthropicProvider::new(cfg.clone())?))
+            }
+            #[cfg(feature = "bedrock")]
+            ProviderKind::Bedrock => Ok(Box::new(bedrock::BedrockProvider::new(cfg.clone())?)),
+            #[cfg(not(feature = "bedrock"))]
+            ProviderKind::Bedrock => Err(anyhow::anyhow!(
+                "kind: bedrock requires rgr to be built with the `bedrock` feature"
+            )),
+            ProviderKind::Cohere => Ok(Box::new(cohere::CohereProvider::new(cfg.clone())?)),
+            ProviderKind::LiteLlm => Ok(Box::new(litellm::LiteLlmProvider::new(cfg.clone())?)),
+            ProviderKind::LocalFile => {
+                Ok(Box::new(local_file::LocalFileProvider::new(cfg.clone())?))
+            }
+            #[cfg(unix)]
+            ProviderKind::Pipe => Ok(Box::new(pipe::PipeProvider::new(cfg.clone())?)),
+            #[cfg(not(unix))]
+            ProviderKind::Pipe => Err(anyhow::anyhow!(
+                "kind: pipe relies on named pipes, which are only supported on Unix"
+            )),
+            ProviderKind::Consensus => {
+                let providers = cfg
+                    .consensus_providers
+                    .iter()
+                    .map(Self::build)
+                    .collect::<Result<Vec<_>>>()?;
+                let quorum = cfg.consensus_quorum.unwrap_or(providers.len() / 2 + 1);
+                Ok(Box::new(consensus::ConsensusProvider::new(
+                    providers, quorum,
+                )?))
+            }
             ProviderKind::Mock => Ok(Box::new(mock::MockProvider)),
         }
     }
+
+    /// Like `build`, but a provider that can't be built (missing API key, no network
+    /// configuration, ...) is replaced by the mock provider instead of failing the run
+    pub fn build_with_mock_fallback(cfg: &ProviderConfig) -> Box<dyn LlmProvider> {
+        Self::build(cfg).unwrap_or_else(|e| {
+            tracing::warn!(
+                "Falling back to the mock provider for {} ({}): {e:#}",
+                cfg.kind.as_str(),
+                cfg.model
+            );
+            Box::new(mock::MockProvider)
+        })
+    }
 }
 
 // Shared helpers for provider implementations
+
+/// System prompt sent to every provider alongside the role instructions
+pub const SYSTEM_PROMPT: &str = "You are a code-modifying agent. Respond ONLY with a valid JSON object matching schema LlmPatch { files:[{path, mode: 'rewrite'|'append'|{insert_after_pattern:{pattern}}, content}], commit_message?, notes? }. No prose.";
+
+pub const UNIFIED_DIFF_SYSTEM_PROMPT: &str = "You are a code-modifying agent. Respond ONLY with a unified diff against the project files: `--- a/<path>` and `+++ b/<path>` headers (`--- /dev/null` for new files) followed by `@@` hunks with 3 lines of context, optionally preceded by one `Commit-Message: <message>` line. No prose.";
+
+/// JSON Schema of `LlmPatch`, for APIs that constrain the response to a schema
+pub fn llm_patch_json_schema() -> serde_json::Value {
+    serde_json::json!({
+        "type": "object",
+        "properties": {
+            "files": {
+                "type": "array",
+                "items": {
+                    "type": "object",
+                    "properties": {
+                        "path": {
+                            "type": "string",
+                            "description": "Path relative to the project root",
+                        },
+                        "mode": {
+                            "anyOf": [
+                                { "type": "string", "enum": ["rewrite", "append", "patch"] },
+                                {
+                                    "type": "object",
+                                    "properties": {
+                                        "insert_after_pattern": {
+                                            "type": "object",
+                                            "properties": { "pattern": { "type": "string" } },
+                                            "required": ["pattern"],
+                                        },
+                                    },
+                                    "required": ["insert_after_pattern"],
+                                },
+                            ],
+                        },
+                        "content": { "type": "string" },
+                    },
+                    "required": ["path", "mode", "content"],
+                },
+            },
+            "commit_message": { "type": "string" },
+            "notes": { "type": "string" },
+        },
+        "required": ["files"],
+    })
+}
+
+/// The response format the system prompt and each role's instructions ask for
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(from = "InstructionFormatRepr", into = "InstructionFormatRepr")]
+pub enum InstructionFormat {
+    /// `LlmPatch` JSON, as described by `SYSTEM_PROMPT`
+    #[default]
+    LlmPatchJson,
+    /// A unified diff, applied file by file with `EditMode::Patch`
+    UnifiedDiff,
+    /// Your own description of the response, in place of the `LlmPatch` schema. Responses
+    /// are still parsed as `LlmPatch` JSON or a unified diff.
+    Custom(String),
+}
+
+/// `InstructionFormat` as written in config files: a name, or `{custom: ...}` (serde_yaml
+/// would otherwise expect a `!custom` tag)
+#[derive(Serialize, Deserialize, JsonSchema)]
+#[serde(untagged)]
+enum InstructionFormatRepr {
+    Named(NamedInstructionFormat),
+    Custom { custom: String },
+}
+
+#[derive(Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+enum NamedInstructionFormat {
+    LlmPatchJson,
+    UnifiedDiff,
+}
+
+impl JsonSchema for InstructionFormat {
+    fn schema_name() -> String {
+        "InstructionFormat".to_string()
+    }
+
+    /// The schema of how config files write it
+    fn json_schema(generator: &mut schemars::r#gen::SchemaGenerator) -> schemars::schema::Schema {
+        let mut schema = InstructionFormatRepr::json_schema(generator).into_object();
+        schema.metadata().description =
+            Some("`llm_patch_json`, `unified_diff` or `{custom: <description>}`".into());
+        schema.into()
+    }
+}
+
+impl From<InstructionFormatRepr> for InstructionFormat {
+    fn from(repr: InstructionFormatRepr) -> Self {
+        match repr {
+            InstructionFormatRepr::Named(NamedInstructionFormat::LlmPatchJson) => {
+                InstructionFormat::LlmPatchJson
+            }
+            InstructionFormatRepr::Named(NamedInstructionFormat::UnifiedDiff) => {
+                InstructionFormat::UnifiedDiff
+            }
+            InstructionFormatRepr::Custom { custom } => InstructionFormat::Custom(custom),
+        }
+    }
+}
+
+impl From<InstructionFormat> for InstructionFormatRepr {
+    fn from(format: InstructionFormat) -> Self {
+        match format {
+            InstructionFormat::LlmPatchJson => {
+                InstructionFormatRepr::Named(NamedInstructionFormat::LlmPatchJson)
+            }
+            InstructionFormat::UnifiedDiff => {
+                InstructionFormatRepr::Named(NamedInstructionFormat::UnifiedDiff)
+            }
+            InstructionFormat::Custom(custom) => InstructionFormatRepr::Custom { custom },
+        }
+    }
+}
+
+impl InstructionFormat {
+    pub fn system_prompt(&self) -> String {
+        match self {
+            InstructionFormat::LlmPatchJson => SYSTEM_PROMPT.to_string(),
+            InstructionFormat::UnifiedDiff => UNIFIED_DIFF_SYSTEM_PROMPT.to_string(),
+            InstructionFormat::Custom(format) => {
+                format!("You are a code-modifying agent. {format}")
+            }
+        }
+    }
+
+    /// The closing "output only ..." sentence of each role's instructions
+    pub fn output_instruction(&self) -> &'static str {
+        match self {
+            InstructionFormat::LlmPatchJson => "Output ONLY JSON (LlmPatch).",
+            InstructionFormat::UnifiedDiff => "Output ONLY a unified diff.",
+            InstructionFormat::Custom(_) => {
+                "Output ONLY a response in the format the system prompt describes."
+            }
+        }
+    }
+}
+
+impl ProviderConfig {
+    /// System prompt for `instruction_format`
+    pub fn system_prompt(&self) -> String {
+        self.instruction_format.as_ref().map_or_else(
+            || SYSTEM_PROMPT.to_string(),
+            InstructionFormat::system_prompt,
+        )
+    }
+}
+
+pub fn build_user_prompt(role: &str, context: &str, instructions: &str) -> String {
+    format!(
+        "Role: {role}\nInstructions:\n{instructions}\n\nProject context (truncated):\n{context}"
+    )
+}
+
+/// Rough token estimate (~4 bytes per token), good enough for budgeting prompts
+pub fn estimate_tokens(s: &str) -> usize {
+    s.len().div_ceil(4)
+}
+
+/// Parse a patch from model output: the first JSON object (fences stripped) first, then a
+/// unified diff, then the raw text. A top-level array of patches is merged into one.
+pub fn parse_patch(text: &str) -> Result<LlmPatch> {
+    let text = text.trim();
+    // An array only counts when it opens before any object (which would be its first element)
+    let body = strip_markdown_fences(text);
+    if body
+        .find('[')
+        .is_some_and(|arr| body.find('{').is_none_or(|obj| arr < obj))
+        && let Some(json_str) = extract_json_array(text)
+        && let Ok(patches) = serde_json::from_str::<Vec<LlmPatch>>(json_str)
+    {
+        return Ok(merge_patches(patches));
+    }
+    if let Some(json_str) = extract_json_object(text)
+        && let Ok(patch) = serde_json::from_str(json_str)
+    {
+        return Ok(patch);
+    }
+    // Fence lines are skipped by the diff parser, and the commit message may sit outside them
+    if let Some(patch) = parse_unified_diff(text) {
+        return Ok(patch);
+    }
+    serde_json::from_str(text).with_context(|| format!("failed to parse model JSON: {text}"))
+}
+
+/// A unified diff as one `EditMode::Patch` edit per file, named by its `+++` header; a
+/// `Commit-Message:` line becomes the commit message. `None` when there is no file header.
+pub fn parse_unified_diff(text: &str) -> Option<LlmPatch> {
+    let mut patch = LlmPatch::default();
+    let mut lines = text.lines().peekable();
+    while let Some(line) = lines.next() {
+        if let Some(message) = line.strip_prefix("Commit-Message:") {
+            patch
+                .commit_message
+                .get_or_insert_with(|| message.trim().to_string());
+            continue;
+        }
+        if line.starts_with("--- ")
+            && let Some(new) = lines.peek().and_then(|l| l.strip_prefix("+++ "))
+        {
+            // `+++ b/src/lib.rs\t2024-01-01 ...`: drop the timestamp and git's `b/` prefix
+            let path = new.split('\t').next().unwrap_or_default().trim();
+            let path = path.strip_prefix("b/").unwrap_or(path).to_string();
+            lines.next();
+            patch.files.push(FileEdit {
+                path,
+                mode: EditMode::Patch,
+                content: String::new(),
+            });
+            continue;
+        }
+        if let Some(edit) = patch.files.last_mut()
+            && (line.is_empty()
+                || line.starts_with("@@")
+                || line.starts_with([' ', '+', '-', '\\']))
+        {
+            edit.content.push_str(line);
+            edit.content.push('\n');
+        }
+    }
+    (!patch.files.is_empty()).then_some(patch)
+}
+
+/// Inner text of the first ```` ``` ```` fenced block (any language tag), or `s` if there is none
+pub fn strip_markdown_fences(s: &str) -> &str {
+    let Some(open) = s.find("```") else {
+        return s;
+    };
+    let after = &s[open + 3..];
+    // Skip the info string (e.g. `json`) on the opening fence line
+    let body = after.find('\n').map_or("", |nl| &after[nl + 1..]);
+    match body.find("```") {
+        Some(close) => &body[..close],
+        None => body,
+    }
+}
+
+/// Files of all patches in order; the first commit message wins and notes are joined
+fn merge_patches(patches: Vec<LlmPatch>) -> LlmPatch {
+    let mut merged = LlmPatch::default();
+    let mut notes = Vec::new();
+    for patch in patches {
+        merged.files.extend(patch.files);
+        if merged.commit_message.is_none() {
+            merged.commit_message = patch.commit_message;
+        }
+        notes.extend(patch.notes);
+    }
+    if !notes.is_empty() {
+        merged.notes = Some(notes.join("\n"));
+    }
+    merged
+}
+
 pub fn extract_json_object(s: &str) -> Option<&str> {
-    // naive extraction of first top-level JSON object
+    extract_delimited(s, b'{', b'}')
+}
+
+/// Like `extract_json_object`, for the first top-level `[...]` array
+pub fn extract_json_array(s: &str) -> Option<&str> {
+    extract_delimited(s, b'[', b']')
+}
+
+fn extract_delimited(s: &str, open: u8, close: u8) -> Option<&str> {
+    // first top-level open..close span, ignoring delimiters inside string literals
+    let s = strip_markdown_fences(s);
     let bytes = s.as_bytes();
     let mut depth = 0isize;
     let mut start = None;
+    let mut in_string = false;
+    let mut escaped = false;
     for (i, &b) in bytes.iter().enumerate() {
-        if b == b'{' {
+        if in_string {
+            match b {
+                _ if escaped => escaped = false,
+                b'\\' => escaped = true,
+                b'"' => in_string = false,
+                _ => {}
+            }
+        } else if b == b'"' && start.is_some() {
+            in_string = true;
+        } else if b == open {
             if depth == 0 {
                 start = Some(i);
             }
             depth += 1;
-        } else if b == b'}' {
+        } else if b == close && depth > 0 {
             depth -= 1;
             if depth == 0
                 && let Some(st) = start
@@ -105,3 +588,64 @@ pub fn extract_json_object(s: &str) -> Option<&str> {
     }
     None
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn extracts_an_empty_array() {
+        assert_eq!(extract_json_array("[]"), Some("[]"));
+        assert_eq!(extract_json_array("  [ ]\n"), Some("[ ]"));
+    }
+
+    #[test]
+    fn extracts_the_outermost_of_nested_arrays() {
+        assert_eq!(
+            extract_json_array(r#"[[1, [2]], ["]"], 3]"#),
+            Some(r#"[[1, [2]], ["]"], 3]"#)
+        );
+    }
+
+    #[test]
+    fn extracts_an_array_embedded_in_prose() {
+        let text = "Here are both patches: [{\"files\": []}, {\"files\": []}] as requested.";
+        assert_eq!(
+            extract_json_array(text),
+            Some(r#"[{"files": []}, {"files": []}]"#)
+        );
+        assert_eq!(extract_json_array("no array here"), None);
+    }
+
+    #[test]
+    fn parses_an_empty_array_as_an_empty_patch() {
+        let patch = parse_patch("[]").unwrap();
+        assert!(patch.files.is_empty());
+        assert!(patch.commit_message.is_none());
+    }
+
+    #[test]
+    fn merges_an_array_of_patches_embedded_in_prose() {
+        let text = r#"Sure, here you go:
+```json
+[
+  {"files": [{"path": "tests/a.rs", "mode": "rewrite", "content": "[1, 2]"}], "commit_message": "test: a"},
+  {"files": [{"path": "src/a.rs", "mode": "append", "content": "]"}], "notes": "closing bracket"}
+]
+```
+Let me know if you need anything else."#;
+        let patch = parse_patch(text).unwrap();
+        let paths: Vec<&str> = patch.files.iter().map(|f| f.path.as_str()).collect();
+        assert_eq!(paths, ["tests/a.rs", "src/a.rs"]);
+        assert_eq!(patch.files[0].content, "[1, 2]");
+        assert_eq!(patch.commit_message.as_deref(), Some("test: a"));
+        assert_eq!(patch.notes.as_deref(), Some("closing bracket"));
+    }
+
+    #[test]
+    fn an_object_before_any_array_is_a_single_patch() {
+        let text = r#"{"files": [{"path": "src/a.rs", "mode": "rewrite", "content": "x"}]}"#;
+        let patch = parse_patch(text).unwrap();
+        assert_eq!(patch.files.len(), 1);
+    }
+}
@@ -1,8 +1,13 @@
-use super::{LlmPatch, LlmProvider, ProviderConfig, extract_json_object};
+use super::{
+    InstructionFormat, LlmPatch, LlmProvider, ProviderConfig, TokenUsage, build_user_prompt,
+    llm_patch_json_schema, parse_patch,
+    request_log::{LoggedRequest, RequestLog},
+};
 use anyhow::{Context, Result, anyhow};
 use async_trait::async_trait;
 use reqwest::header::CONTENT_TYPE;
 use serde::{Deserialize, Serialize};
+use tracing::{debug, trace};
 
 pub struct OpenAiProvider {
     cfg: ProviderConfig,
@@ -13,17 +18,22 @@ pub struct OpenAiProvider {
 
 impl OpenAiProvider {
     pub fn new(cfg: ProviderConfig) -> Result<Self> {
-        let client = reqwest::Client::builder().build()?;
-        let base = cfg
-            .base_url
-            .clone()
-            .unwrap_or_else(|| "https://api.openai.com/v1".to_string());
         let env_key = cfg
             .api_key_env
             .clone()
             .unwrap_or_else(|| "OPENAI_API_KEY".to_string());
         let api_key =
             std::env::var(&env_key).with_context(|| format!("missing env var {env_key}"))?;
+        Self::with_api_key(cfg, api_key)
+    }
+
+    /// For wrappers that resolve the key themselves (or need none)
+    pub(crate) fn with_api_key(cfg: ProviderConfig, api_key: String) -> Result<Self> {
+        let client = reqwest::Client::builder().build()?;
+        let base = cfg
+            .base_url
+            .clone()
+            .unwrap_or_else(|| "https://api.openai.com/v1".to_string());
         Ok(Self {
             cfg,
             client,
@@ -37,7 +47,77 @@ impl OpenAiProvider {
 struct ChatReq<'a> {
     model: &'a str,
     messages: Vec<Message<'a>>,
-    temperature: f32,
+    /// Reasoning models only accept the default
+    #[serde(skip_serializing_if = "Option::is_none")]
+    temperature: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    max_tokens: Option<u32>,
+    /// Replaces `max_tokens` for reasoning models
+    #[serde(skip_serializing_if = "Option::is_none")]
+    max_completion_tokens: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    reasoning_effort: Option<&'a str>,
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    stream: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    response_format: Option<ResponseFormat>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    tools: Vec<serde_json::Value>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    tool_choice: Option<serde_json::Value>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    seed: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    metadata: Option<&'a serde_json::Value>,
+}
+
+#[derive(Debug, Serialize)]
+struct ResponseFormat {
+    r#type: &'static str,
+}
+
+/// Models known to accept `response_format: json_object`
+fn supports_json_mode(model: &str) -> bool {
+    let model = model.to_lowercase();
+    if let Some(rest) = model.strip_prefix("gpt-3.5-turbo") {
+        // Only the 1106 snapshot and later; the bare alias now points at 0125
+        return !["-0301", "-0613", "-16k-0613"].contains(&rest);
+    }
+    [
+        "gpt-4o",
+        "gpt-4.1",
+        "gpt-4-turbo",
+        "gpt-4-1106",
+        "gpt-4-0125",
+        "gpt-5",
+        "o1",
+        "o3",
+        "o4",
+        "deepseek",
+    ]
+    .iter()
+    .any(|prefix| model.starts_with(prefix))
+}
+
+/// Name of the single tool offered when tool calling is on
+const PATCH_TOOL: &str = "apply_patch";
+
+/// Models known to support function calling (the `o1` previews and `o1-mini` don't)
+fn supports_tool_calling(model: &str) -> bool {
+    let model = model.to_lowercase();
+    if model.starts_with("o1-preview") || model.starts_with("o1-mini") {
+        return false;
+    }
+    ["gpt-4", "gpt-3.5-turbo", "gpt-5", "o1", "o3", "o4"]
+        .iter()
+        .any(|prefix| model.starts_with(prefix))
+}
+
+/// OpenAI's `o1`/`o3`/`o4` reasoning models, also behind a `provider/` prefix (LiteLLM)
+fn is_reasoning_model(model: &str) -> bool {
+    let model = model.rsplit('/').next().unwrap_or(model).to_lowercase();
+    let mut chars = model.chars();
+    chars.next() == Some('o') && chars.next().is_some_and(|c| c.is_ascii_digit())
 }
 
 #[derive(Debug, Serialize)]
@@ -49,6 +129,14 @@ struct Message<'a> {
 #[derive(Debug, Deserialize)]
 struct ChatResp {
     choices: Vec<Choice>,
+    usage: Option<Usage>,
+}
+#[derive(Debug, Deserialize)]
+struct Usage {
+    #[serde(default)]
+    prompt_tokens: u64,
+    #[serde(default)]
+    completion_tokens: u64,
 }
 #[derive(Debug, Deserialize)]
 struct Choice {
@@ -56,64 +144,342 @@ struct Choice {
 }
 #[derive(Debug, Deserialize)]
 struct ChoiceMessage {
-    content: String,
+    /// Null when the model answers with tool calls
+    #[serde(default)]
+    content: Option<String>,
+    #[serde(default)]
+    tool_calls: Vec<ToolCall>,
+}
+#[derive(Debug, Deserialize)]
+struct ToolCall {
+    function: FunctionCall,
+}
+#[derive(Debug, Deserialize)]
+struct FunctionCall {
+    arguments: String,
 }
 
-#[async_trait]
-impl LlmProvider for OpenAiProvider {
-    async fn generate_patch(
-        &self,
-        role: &str,
-        context: &str,
-        instructions: &str,
-    ) -> Result<LlmPatch> {
-        let url = format!("{}/chat/completions", self.base.trim_end_matches('/'));
-        let sys = "You are a code-modifying agent. Respond ONLY with a valid JSON object matching schema LlmPatch { files:[{path, mode: 'rewrite'|'append', content}], commit_message?, notes? }. No prose.";
-        let user = format!(
-            "Role: {role}\nInstructions:\n{instructions}\n\nProject context (truncated):\n{context}"
-        );
-        let req = ChatReq {
-            model: &self.cfg.model,
-            messages: vec![
-                Message {
-                    role: "system",
-                    content: sys,
-                },
-                Message {
-                    role: "user",
-                    content: &user,
-                },
-            ],
-            temperature: 0.2,
-        };
+/// One server-sent event of a streamed chat completion
+#[derive(Debug, Deserialize)]
+struct ChatChunk {
+    choices: Vec<ChunkChoice>,
+}
+#[derive(Debug, Deserialize)]
+struct ChunkChoice {
+    delta: ChunkDelta,
+}
+#[derive(Debug, Deserialize)]
+struct ChunkDelta {
+    content: Option<String>,
+    #[serde(default)]
+    tool_calls: Vec<ChunkToolCall>,
+}
+/// Tool call arguments arrive as JSON fragments, to be concatenated
+#[derive(Debug, Deserialize)]
+struct ChunkToolCall {
+    #[serde(default)]
+    index: usize,
+    function: Option<ChunkFunction>,
+}
+#[derive(Debug, Deserialize)]
+struct ChunkFunction {
+    arguments: Option<String>,
+}
+
+impl OpenAiProvider {
+    fn reasoning(&self) -> bool {
+        self.cfg
+            .is_reasoning_model
+            .unwrap_or_else(|| is_reasoning_model(&self.cfg.model))
+    }
 
-        // Build API key header with optional custom name and prefix
+    fn json_mode(&self) -> bool {
+        self.cfg
+            .json_mode
+            .unwrap_or_else(|| supports_json_mode(&self.cfg.model))
+    }
+
+    /// Tool calls only carry `LlmPatch` JSON, so other instruction formats go without
+    fn tool_calling(&self) -> bool {
+        matches!(
+            self.cfg.instruction_format,
+            None | Some(InstructionFormat::LlmPatchJson)
+        ) && self
+            .cfg
+            .use_tool_calling
+            .unwrap_or_else(|| supports_tool_calling(&self.cfg.model))
+    }
+
+    /// The `apply_patch` tool's arguments are the patch; a model that answered in prose
+    /// instead still gets its content parsed
+    fn parse_reply(&self, content: &str, tool_arguments: Option<&str>) -> Result<LlmPatch> {
+        match tool_arguments {
+            Some(arguments) => serde_json::from_str(arguments)
+                .with_context(|| format!("invalid {PATCH_TOOL} arguments: {arguments}")),
+            None => self.parse_content(content),
+        }
+    }
+
+    /// The whole reply is the patch in JSON mode; otherwise dig it out of the text
+    fn parse_content(&self, content: &str) -> Result<LlmPatch> {
+        if self.json_mode()
+            && let Ok(patch) = serde_json::from_str(content)
+        {
+            return Ok(patch);
+        }
+        parse_patch(content)
+    }
+
+    pub(crate) fn client(&self) -> &reqwest::Client {
+        &self.client
+    }
+
+    /// `path` under the API base URL, e.g. `chat/completions`
+    pub(crate) fn endpoint(&self, path: &str) -> String {
+        format!("{}/{path}", self.base.trim_end_matches('/'))
+    }
+
+    /// Add the API key header, with the configured name and prefix
+    pub(crate) fn authorized(&self, req: reqwest::RequestBuilder) -> reqwest::RequestBuilder {
         let header_name = self
             .cfg
             .api_key_header
             .as_deref()
             .unwrap_or("Authorization");
         let prefix = self.cfg.api_key_prefix.as_deref().unwrap_or("Bearer ");
-        let header_value = format!("{}{}", prefix, self.api_key);
+        req.header(header_name, format!("{}{}", prefix, self.api_key))
+    }
 
+    /// Send the chat completion request and hand back the raw response, so
+    /// OpenAI-compatible wrappers can inspect status and headers themselves
+    pub(crate) async fn send_chat(
+        &self,
+        role: &str,
+        context: &str,
+        instructions: &str,
+    ) -> Result<(reqwest::Response, Option<RequestLog>)> {
+        let (body, logged) = self.chat_body(role, context, instructions)?;
+        let log = RequestLog::start(self.cfg.request_log_dir.as_deref(), &logged);
         let resp = self
-            .client
-            .post(&url)
-            .header(header_name, header_value)
+            .authorized(self.client.post(self.endpoint("chat/completions")))
             .header(CONTENT_TYPE, "application/json")
-            .json(&req)
+            .json(&body)
             .send()
-            .await?
-            .error_for_status()?;
-        let body: ChatResp = resp.json().await?;
-        let content = body
+            .await?;
+        Ok((resp, log))
+    }
+
+    /// The chat completion request body, and what to log of it
+    pub(crate) fn chat_body(
+        &self,
+        role: &str,
+        context: &str,
+        instructions: &str,
+    ) -> Result<(serde_json::Value, LoggedRequest)> {
+        let sys = &self.cfg.system_prompt();
+        let reasoning = self.reasoning();
+        if !reasoning && self.cfg.reasoning_effort.is_some() {
+            debug!(
+                "Ignoring reasoning_effort: {} is not a reasoning model",
+                self.cfg.model
+            );
+        }
+        let mut user = build_user_prompt(role, context, instructions);
+        let mut messages = Vec::new();
+        if reasoning {
+            // Reasoning models reject the system role
+            user = format!("{sys}\n\n{user}");
+        } else {
+            messages.push(Message {
+                role: "system",
+                content: sys,
+            });
+        }
+        messages.push(Message {
+            role: "user",
+            content: &user,
+        });
+        let tool_calling = self.tool_calling();
+        let req = ChatReq {
+            model: &self.cfg.model,
+            messages,
+            temperature: (!reasoning).then_some(0.2),
+            max_tokens: self.cfg.max_output_tokens.filter(|_| !reasoning),
+            max_completion_tokens: self.cfg.max_output_tokens.filter(|_| reasoning),
+            reasoning_effort: self.cfg.reasoning_effort.as_deref().filter(|_| reasoning),
+            stream: self.cfg.stream,
+            response_format: (self.json_mode() && !tool_calling).then_some(ResponseFormat {
+                r#type: "json_object",
+            }),
+            tools: if tool_calling {
+                vec![serde_json::json!({
+                    "type": "function",
+                    "function": {
+                        "name": PATCH_TOOL,
+                        "description": "Apply the patch to the project",
+                        "parameters": llm_patch_json_schema(),
+                    },
+                })]
+            } else {
+                Vec::new()
+            },
+            tool_choice: tool_calling.then(
+                || serde_json::json!({ "type": "function", "function": { "name": PATCH_TOOL } }),
+            ),
+            seed: self.cfg.seed,
+            metadata: self.cfg.metadata.as_ref(),
+        };
+        let logged = LoggedRequest {
+            role: role.to_string(),
+            model: self.cfg.model.clone(),
+            temperature: req.temperature.unwrap_or(1.0),
+            system_prompt: sys.to_string(),
+            user_prompt: user.clone(),
+        };
+        Ok((serde_json::to_value(&req)?, logged))
+    }
+
+    pub(crate) async fn patch_from_response(
+        &self,
+        resp: reqwest::Response,
+        log: Option<RequestLog>,
+    ) -> Result<LlmPatch> {
+        if self.cfg.stream {
+            let (content, tool_arguments) = read_stream(resp).await?;
+            let reply = tool_arguments.as_deref().unwrap_or(&content);
+            if let Some(log) = &log {
+                log.finish(reply, Some(reply));
+            }
+            return self.parse_reply(&content, tool_arguments.as_deref());
+        }
+        let body = resp.text().await?;
+        self.patch_from_body(&body, log.as_ref())
+    }
+
+    /// The patch in a (non-streamed) chat completion response body
+    pub(crate) fn patch_from_body(&self, body: &str, log: Option<&RequestLog>) -> Result<LlmPatch> {
+        let parsed = serde_json::from_str::<ChatResp>(body);
+        if let Some(log) = log {
+            let message = parsed.as_ref().ok().and_then(|r| r.choices.first());
+            let reply = message.and_then(|c| match c.message.tool_calls.first() {
+                Some(call) => Some(call.function.arguments.as_str()),
+                None => c.message.content.as_deref(),
+            });
+            log.finish(body, reply);
+        }
+        let parsed = parsed.with_context(|| format!("failed to parse chat response: {body}"))?;
+        let usage = parsed.usage.map(|u| TokenUsage {
+            input_tokens: u.prompt_tokens,
+            output_tokens: u.completion_tokens,
+        });
+        let message = parsed
             .choices
-            .first()
-            .map(|c| c.message.content.as_str())
+            .into_iter()
+            .next()
+            .map(|c| c.message)
             .ok_or_else(|| anyhow!("no choices"))?;
-        let json_str = extract_json_object(content).unwrap_or(content);
-        let patch: LlmPatch = serde_json::from_str(json_str)
-            .with_context(|| format!("failed to parse model JSON: {json_str}"))?;
+        let tool_arguments = message
+            .tool_calls
+            .into_iter()
+            .next()
+            .map(|call| call.function.arguments);
+        let mut patch = self.parse_reply(
+            message.content.as_deref().unwrap_or_default(),
+            tool_arguments.as_deref(),
+        )?;
+        patch.usage = usage;
         Ok(patch)
     }
 }
+
+#[async_trait]
+impl LlmProvider for OpenAiProvider {
+    async fn generate_patch(
+        &self,
+        role: &str,
+        context: &str,
+        instructions: &str,
+    ) -> Result<LlmPatch> {
+        let (resp, log) = self.send_chat(role, context, instructions).await?;
+        self.patch_from_response(resp.error_for_status()?, log)
+            .await
+    }
+}
+
+/// Accumulate `delta.content` from an SSE chat completion stream, along with the
+/// arguments of the first tool call if the model made one
+async fn read_stream(mut resp: reqwest::Response) -> Result<(String, Option<String>)> {
+    let mut pending = Vec::new();
+    let mut content = String::new();
+    let mut tool_arguments: Option<String> = None;
+    while let Some(bytes) = resp.chunk().await? {
+        pending.extend_from_slice(&bytes);
+        while let Some(pos) = pending.iter().position(|&b| b == b'\n') {
+            let line: Vec<u8> = pending.drain(..=pos).collect();
+            let line = String::from_utf8_lossy(&line);
+            let Some(data) = line.trim().strip_prefix("data:") else {
+                continue;
+            };
+            let data = data.trim();
+            if data == "[DONE]" {
+                return Ok((content, tool_arguments));
+            }
+            let chunk: ChatChunk = serde_json::from_str(data)
+                .with_context(|| format!("failed to parse stream event: {data}"))?;
+            for delta in chunk.choices.into_iter().map(|c| c.delta) {
+                if let Some(text) = delta.content {
+                    trace!("{text}");
+                    content.push_str(&text);
+                }
+                let fragments = delta
+                    .tool_calls
+                    .into_iter()
+                    .filter(|call| call.index == 0)
+                    .filter_map(|call| call.function?.arguments);
+                for fragment in fragments {
+                    trace!("{fragment}");
+                    tool_arguments.get_or_insert_default().push_str(&fragment);
+                }
+            }
+        }
+    }
+    Ok((content, tool_arguments))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request_body(json_mode: Option<bool>) -> serde_json::Value {
+        let cfg = ProviderConfig {
+            model: "gpt-4o".into(),
+            json_mode,
+            use_tool_calling: Some(false),
+            ..Default::default()
+        };
+        let provider = OpenAiProvider::with_api_key(cfg, "key".into()).unwrap();
+        provider
+            .chat_body("tester", "context", "instructions")
+            .unwrap()
+            .0
+    }
+
+    #[test]
+    fn json_mode_sets_response_format() {
+        let body = request_body(Some(true));
+        assert_eq!(
+            body["response_format"],
+            serde_json::json!({ "type": "json_object" })
+        );
+    }
+
+    #[test]
+    fn json_mode_is_detected_from_the_model() {
+        assert_eq!(request_body(None)["response_format"]["type"], "json_object");
+    }
+
+    #[test]
+    fn disabled_json_mode_omits_response_format() {
+        assert!(request_body(Some(false)).get("response_format").is_none());
+    }
+}
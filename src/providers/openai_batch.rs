@@ -0,0 +1,229 @@
+use super::openai::OpenAiProvider;
+use super::{LlmPatch, LlmProvider, ProviderConfig};
+use anyhow::{Context, Result, anyhow};
+use async_trait::async_trait;
+use reqwest::header::CONTENT_TYPE;
+use serde::Deserialize;
+use std::time::Duration;
+use tracing::{debug, info};
+
+const DEFAULT_POLL_INTERVAL_SECS: u64 = 60;
+const MULTIPART_BOUNDARY: &str = "rgr-batch-boundary-7d1f2a";
+
+/// Sends each request through OpenAI's Batch API, which costs half as much but may take up
+/// to 24 hours: the request is uploaded as a one-line JSONL file, the batch polled until it
+/// ends and the patch read from its output file. Everything else behaves like `open_ai`.
+pub struct OpenAiBatchProvider {
+    inner: OpenAiProvider,
+    poll_interval: Duration,
+}
+
+/// Where a submitted batch stands
+#[derive(Debug, Deserialize)]
+pub struct BatchStatus {
+    pub id: String,
+    /// `validating`, `in_progress`, `finalizing`, `completed`, `failed`, `expired`,
+    /// `cancelling` or `cancelled`
+    pub status: String,
+    output_file_id: Option<String>,
+    error_file_id: Option<String>,
+    errors: Option<BatchErrors>,
+}
+
+#[derive(Debug, Deserialize)]
+struct BatchErrors {
+    #[serde(default)]
+    data: Vec<BatchError>,
+}
+
+#[derive(Debug, Deserialize)]
+struct BatchError {
+    message: String,
+}
+
+/// One line of a batch output (or error) file
+#[derive(Debug, Deserialize)]
+struct BatchOutputLine {
+    response: Option<BatchResponse>,
+    error: Option<BatchError>,
+}
+
+#[derive(Debug, Deserialize)]
+struct BatchResponse {
+    status_code: u16,
+    body: serde_json::Value,
+}
+
+#[derive(Debug, Deserialize)]
+struct Created {
+    id: String,
+}
+
+impl BatchStatus {
+    pub fn is_finished(&self) -> bool {
+        matches!(
+            self.status.as_str(),
+            "completed" | "failed" | "expired" | "cancelled"
+        )
+    }
+}
+
+impl OpenAiBatchProvider {
+    pub fn new(mut cfg: ProviderConfig) -> Result<Self> {
+        // A batch answers in one piece
+        cfg.stream = false;
+        let poll_interval = Duration::from_secs(
+            cfg.batch_poll_interval_secs
+                .unwrap_or(DEFAULT_POLL_INTERVAL_SECS),
+        );
+        Ok(Self {
+            inner: OpenAiProvider::new(cfg)?,
+            poll_interval,
+        })
+    }
+
+    /// Upload the chat request and start a batch for it; returns the batch id
+    pub async fn submit(&self, role: &str, context: &str, instructions: &str) -> Result<String> {
+        let (body, _) = self.inner.chat_body(role, context, instructions)?;
+        let line = serde_json::json!({
+            "custom_id": format!("rgr-{role}"),
+            "method": "POST",
+            "url": "/v1/chat/completions",
+            "body": body,
+        });
+        let upload = format!(
+            "--{MULTIPART_BOUNDARY}\r\n\
+             Content-Disposition: form-data; name=\"purpose\"\r\n\r\n\
+             batch\r\n\
+             --{MULTIPART_BOUNDARY}\r\n\
+             Content-Disposition: form-data; name=\"file\"; filename=\"rgr-{role}.jsonl\"\r\n\
+             Content-Type: application/jsonl\r\n\r\n\
+             {line}\n\r\n\
+             --{MULTIPART_BOUNDARY}--\r\n"
+        );
+        let file: Created = self
+            .inner
+            .authorized(self.inner.client().post(self.inner.endpoint("files")))
+            .header(
+                CONTENT_TYPE,
+                format!("multipart/form-data; boundary={MULTIPART_BOUNDARY}"),
+            )
+            .body(upload)
+            .send()
+            .await?
+            .error_for_status()
+            .context("uploading the batch input file")?
+            .json()
+            .await?;
+        let batch: Created = self
+            .inner
+            .authorized(self.inner.client().post(self.inner.endpoint("batches")))
+            .json(&serde_json::json!({
+                "input_file_id": file.id,
+                "endpoint": "/v1/chat/completions",
+                "completion_window": "24h",
+                "metadata": { "rgr_role": role },
+            }))
+            .send()
+            .await?
+            .error_for_status()
+            .context("creating the batch")?
+            .json()
+            .await?;
+        info!("Submitted batch {}", batch.id);
+        Ok(batch.id)
+    }
+
+    pub async fn status(&self, batch_id: &str) -> Result<BatchStatus> {
+        let url = self.inner.endpoint(&format!("batches/{batch_id}"));
+        Ok(self
+            .inner
+            .authorized(self.inner.client().get(url))
+            .send()
+            .await?
+            .error_for_status()
+            .with_context(|| format!("checking batch {batch_id}"))?
+            .json()
+            .await?)
+    }
+
+    /// The patch of a batch that has ended; `None` while it is still running
+    pub async fn retrieve(&self, batch_id: &str) -> Result<Option<LlmPatch>> {
+        let status = self.status(batch_id).await?;
+        if !status.is_finished() {
+            return Ok(None);
+        }
+        self.results(status).await.map(Some)
+    }
+
+    /// The patch in the output of a finished batch, or why there is none
+    pub async fn results(&self, status: BatchStatus) -> Result<LlmPatch> {
+        let batch_id = &status.id;
+        let file_id = match (&status.output_file_id, &status.error_file_id) {
+            (Some(output), _) => output,
+            (None, Some(errors)) => errors,
+            (None, None) => {
+                let reasons: Vec<String> = status
+                    .errors
+                    .map(|e| e.data.into_iter().map(|e| e.message).collect())
+                    .unwrap_or_default();
+                return Err(anyhow!(
+                    "batch {batch_id} {} without output: {}",
+                    status.status,
+                    reasons.join("; ")
+                ));
+            }
+        };
+        let url = self.inner.endpoint(&format!("files/{file_id}/content"));
+        let content = self
+            .inner
+            .authorized(self.inner.client().get(url))
+            .send()
+            .await?
+            .error_for_status()
+            .with_context(|| format!("downloading the results of batch {batch_id}"))?
+            .text()
+            .await?;
+        let line = content
+            .lines()
+            .find(|l| !l.trim().is_empty())
+            .ok_or_else(|| anyhow!("batch {batch_id} has an empty result file"))?;
+        let line: BatchOutputLine = serde_json::from_str(line)
+            .with_context(|| format!("failed to parse batch result: {line}"))?;
+        match (line.response, line.error) {
+            (_, Some(error)) => Err(anyhow!("batch {batch_id} failed: {}", error.message)),
+            (Some(response), None) if response.status_code == 200 => {
+                self.inner.patch_from_body(&response.body.to_string(), None)
+            }
+            (Some(response), None) => Err(anyhow!(
+                "batch {batch_id} request failed with status {}: {}",
+                response.status_code,
+                response.body
+            )),
+            (None, None) => Err(anyhow!("batch {batch_id} result has no response")),
+        }
+    }
+}
+
+#[async_trait]
+impl LlmProvider for OpenAiBatchProvider {
+    async fn generate_patch(
+        &self,
+        role: &str,
+        context: &str,
+        instructions: &str,
+    ) -> Result<LlmPatch> {
+        let batch_id = self.submit(role, context, instructions).await?;
+        info!(
+            "Waiting for batch {batch_id}, checking every {}s",
+            self.poll_interval.as_secs()
+        );
+        loop {
+            tokio::time::sleep(self.poll_interval).await;
+            if let Some(patch) = self.retrieve(&batch_id).await? {
+                return Ok(patch);
+            }
+            debug!("Batch {batch_id} still running");
+        }
+    }
+}
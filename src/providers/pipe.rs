@@ -0,0 +1,163 @@
+use super::{LlmPatch, LlmProvider, ProviderConfig, build_user_prompt, parse_patch};
+use anyhow::{Context, Result, anyhow};
+use async_trait::async_trait;
+use std::ffi::CString;
+use std::fs::{File, OpenOptions};
+use std::io::{ErrorKind, Read, Write};
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::{FileTypeExt, OpenOptionsExt};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use tracing::info;
+
+const DEFAULT_TIMEOUT_SECS: u64 = 600;
+/// The pipes are opened non-blocking and polled, so a missing peer can never wedge a thread
+const POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+/// Hands each prompt to an external tool over named pipes: the prompt is written to
+/// `pipe_path` (closed when done, so the tool reads until EOF) and the `LlmPatch` JSON is
+/// read back from `pipe_response_path` until the tool closes it
+pub struct PipeProvider {
+    request: PathBuf,
+    response: PathBuf,
+    timeout: Duration,
+    system_prompt: String,
+}
+
+impl PipeProvider {
+    pub fn new(cfg: ProviderConfig) -> Result<Self> {
+        let system_prompt = cfg.system_prompt();
+        let request = cfg
+            .pipe_path
+            .ok_or_else(|| anyhow!("pipe provider needs pipe_path"))?;
+        let response = cfg.pipe_response_path.unwrap_or_else(|| {
+            let mut path = request.clone().into_os_string();
+            path.push(".response");
+            path.into()
+        });
+        for path in [&request, &response] {
+            ensure_fifo(path)?;
+        }
+        Ok(Self {
+            request,
+            response,
+            timeout: Duration::from_secs(cfg.pipe_timeout_secs.unwrap_or(DEFAULT_TIMEOUT_SECS)),
+            system_prompt,
+        })
+    }
+
+    async fn exchange(&self, prompt: &str) -> Result<String> {
+        // Open the response end first, so a tool that opens it non-blocking finds a reader
+        let response = open_nonblocking(&self.response, false)
+            .with_context(|| format!("opening {}", self.response.display()))?;
+        self.write_request(prompt).await?;
+        read_until_closed(response)
+            .await
+            .with_context(|| format!("reading {}", self.response.display()))
+    }
+
+    async fn write_request(&self, prompt: &str) -> Result<()> {
+        let mut file = loop {
+            match open_nonblocking(&self.request, true) {
+                Ok(file) => break file,
+                // No reader on the pipe yet
+                Err(e) if e.raw_os_error() == Some(libc::ENXIO) => {
+                    tokio::time::sleep(POLL_INTERVAL).await
+                }
+                Err(e) => {
+                    return Err(e).with_context(|| format!("opening {}", self.request.display()));
+                }
+            }
+        };
+        let mut bytes = prompt.as_bytes();
+        while !bytes.is_empty() {
+            match file.write(bytes) {
+                Ok(n) => bytes = &bytes[n..],
+                Err(e) if matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::Interrupted) => {
+                    tokio::time::sleep(POLL_INTERVAL).await
+                }
+                Err(e) => {
+                    return Err(e).with_context(|| format!("writing {}", self.request.display()));
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl LlmProvider for PipeProvider {
+    async fn generate_patch(
+        &self,
+        role: &str,
+        context: &str,
+        instructions: &str,
+    ) -> Result<LlmPatch> {
+        let prompt = format!(
+            "{}\n\n{}",
+            self.system_prompt,
+            build_user_prompt(role, context, instructions)
+        );
+        info!("Waiting for a response on {}", self.response.display());
+        let text = tokio::time::timeout(self.timeout, self.exchange(&prompt))
+            .await
+            .map_err(|_| {
+                anyhow!(
+                    "no response through {} within {}s",
+                    self.response.display(),
+                    self.timeout.as_secs()
+                )
+            })??;
+        parse_patch(&text)
+    }
+}
+
+/// Create the FIFO if it is missing; anything else at `path` is an error
+fn ensure_fifo(path: &Path) -> Result<()> {
+    match std::fs::metadata(path) {
+        Ok(meta) if meta.file_type().is_fifo() => Ok(()),
+        Ok(_) => Err(anyhow!("{} exists but is not a named pipe", path.display())),
+        Err(e) if e.kind() == ErrorKind::NotFound => {
+            let c_path = CString::new(path.as_os_str().as_bytes())?;
+            // SAFETY: `c_path` is a valid NUL-terminated string for the duration of the call
+            if unsafe { libc::mkfifo(c_path.as_ptr(), 0o600) } != 0 {
+                return Err(std::io::Error::last_os_error())
+                    .with_context(|| format!("creating named pipe {}", path.display()));
+            }
+            Ok(())
+        }
+        Err(e) => Err(e).with_context(|| format!("checking {}", path.display())),
+    }
+}
+
+fn open_nonblocking(path: &Path, write: bool) -> std::io::Result<File> {
+    OpenOptions::new()
+        .read(!write)
+        .write(write)
+        .custom_flags(libc::O_NONBLOCK)
+        .open(path)
+}
+
+/// Read until the writer closes the pipe. A read of 0 bytes means "no writer": before any
+/// writer showed up that is just waiting, afterwards it is the end of the response.
+async fn read_until_closed(mut file: File) -> Result<String> {
+    let mut buf = Vec::new();
+    let mut chunk = [0u8; 8192];
+    let mut writer_seen = false;
+    loop {
+        match file.read(&mut chunk) {
+            Ok(0) if writer_seen => break,
+            Ok(0) => tokio::time::sleep(POLL_INTERVAL).await,
+            Ok(n) => {
+                writer_seen = true;
+                buf.extend_from_slice(&chunk[..n]);
+            }
+            Err(e) if matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::Interrupted) => {
+                writer_seen = true;
+                tokio::time::sleep(POLL_INTERVAL).await
+            }
+            Err(e) => return Err(e.into()),
+        }
+    }
+    Ok(String::from_utf8_lossy(&buf).into_owned())
+}
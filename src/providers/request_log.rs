@@ -0,0 +1,79 @@
+use super::{LlmPatch, parse_patch};
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
+use tracing::warn;
+
+/// What was sent to the provider, saved as `{timestamp}-{role}-request.json`
+#[derive(Debug, Serialize, Deserialize)]
+pub struct LoggedRequest {
+    pub role: String,
+    pub model: String,
+    pub temperature: f32,
+    pub system_prompt: String,
+    pub user_prompt: String,
+}
+
+/// What came back, saved as `{timestamp}-{role}-response.json`
+#[derive(Debug, Serialize, Deserialize)]
+pub struct LoggedResponse {
+    /// Raw response body (for streamed responses, the concatenated deltas)
+    pub body: String,
+    /// Model text the patch was parsed from, when it could be extracted
+    pub content: Option<String>,
+}
+
+/// Handle tying a response to the request logged before it
+pub struct RequestLog {
+    stem: PathBuf,
+}
+
+impl RequestLog {
+    /// Write the request file when `dir` is set; logging problems are warned about, never fatal
+    pub fn start(dir: Option<&Path>, request: &LoggedRequest) -> Option<Self> {
+        let dir = dir?;
+        let stem = dir.join(format!(
+            "{}-{}",
+            chrono::Utc::now().format("%Y%m%dT%H%M%S%.3f"),
+            request.role
+        ));
+        let log = Self { stem };
+        if let Err(e) = log.write("request", request) {
+            warn!("Failed to write request log: {e:#}");
+        }
+        Some(log)
+    }
+
+    pub fn finish(&self, body: &str, content: Option<&str>) {
+        let response = LoggedResponse {
+            body: body.to_string(),
+            content: content.map(str::to_string),
+        };
+        if let Err(e) = self.write("response", &response) {
+            warn!("Failed to write response log: {e:#}");
+        }
+    }
+
+    fn write(&self, kind: &str, value: &impl Serialize) -> Result<()> {
+        if let Some(dir) = self.stem.parent() {
+            std::fs::create_dir_all(dir)?;
+        }
+        let path = PathBuf::from(format!("{}-{kind}.json", self.stem.display()));
+        std::fs::write(&path, serde_json::to_string_pretty(value)?)
+            .with_context(|| format!("writing {}", path.display()))
+    }
+}
+
+/// Re-derive the patch from a logged exchange; `path` may be the request or the response file
+pub fn replay_from_log(path: &Path) -> Result<LlmPatch> {
+    let path_s = path.to_string_lossy();
+    let response_path = match path_s.strip_suffix("-request.json") {
+        Some(stem) => PathBuf::from(format!("{stem}-response.json")),
+        None => path.to_path_buf(),
+    };
+    let s = std::fs::read_to_string(&response_path)
+        .with_context(|| format!("reading {}", response_path.display()))?;
+    let response: LoggedResponse =
+        serde_json::from_str(&s).with_context(|| format!("parsing {}", response_path.display()))?;
+    parse_patch(response.content.as_deref().unwrap_or(&response.body))
+}
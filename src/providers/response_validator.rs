@@ -0,0 +1,66 @@
+use super::LlmPatch;
+use crate::workspace::{self, ApplyOptions};
+use anyhow::{Context, Result, anyhow};
+use std::path::Path;
+use tokio::process::Command;
+use walkdir::WalkDir;
+
+/// Apply `patch` to a scratch copy of the project and `cargo check` it (tests included),
+/// so a patch that doesn't compile is caught before it is committed. The error carries the
+/// compiler output. Projects without a `Cargo.toml` pass unchecked.
+///
+/// Only `project_root` is copied, so a member of a larger Cargo workspace can't be checked.
+pub async fn validate_rust_patch(
+    project_root: &Path,
+    patch: &LlmPatch,
+    opts: ApplyOptions,
+) -> Result<()> {
+    if !project_root.join("Cargo.toml").exists() {
+        return Ok(());
+    }
+    let scratch = tempfile::tempdir()?;
+    copy_project(project_root, scratch.path()).context("copying the project for cargo check")?;
+    workspace::apply_patch(scratch.path(), patch, opts).await?;
+    let output = Command::new("cargo")
+        .args([
+            "check",
+            "--all-targets",
+            "--quiet",
+            "--message-format",
+            "short",
+        ])
+        .current_dir(scratch.path())
+        // Reuse the project's compiled dependencies; the copy builds under its own hash
+        .env("CARGO_TARGET_DIR", project_root.join("target"))
+        .output()
+        .await
+        .context("running cargo check")?;
+    if output.status.success() {
+        return Ok(());
+    }
+    Err(anyhow!(
+        "{}{}",
+        String::from_utf8_lossy(&output.stdout),
+        String::from_utf8_lossy(&output.stderr)
+    ))
+}
+
+/// Copy the project's files to `dest`, leaving out `.git` and build output
+fn copy_project(project_root: &Path, dest: &Path) -> Result<()> {
+    for entry in WalkDir::new(project_root).into_iter().filter_entry(|e| {
+        !(e.file_type().is_dir()
+            && (e.path().ends_with(".git")
+                || e.path().ends_with("target")
+                || e.path().ends_with("node_modules")))
+    }) {
+        let entry = entry?;
+        let rel = entry.path().strip_prefix(project_root)?;
+        let target = dest.join(rel);
+        if entry.file_type().is_dir() {
+            std::fs::create_dir_all(&target)?;
+        } else if entry.file_type().is_file() {
+            std::fs::copy(entry.path(), &target)?;
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,35 @@
+use super::openai::OpenAiProvider;
+use super::{LlmPatch, LlmProvider, ProviderConfig};
+use anyhow::Result;
+use async_trait::async_trait;
+
+/// Together AI serves open models (Llama 3, Mistral, CodeLlama) through an OpenAI-compatible
+/// API; this wrapper only fills in its defaults. Token usage is reported like OpenAI's, so
+/// `model_pricing` entries for Together models feed the cost tracker as usual.
+pub struct TogetherAiProvider {
+    inner: OpenAiProvider,
+}
+
+impl TogetherAiProvider {
+    pub fn new(mut cfg: ProviderConfig) -> Result<Self> {
+        cfg.base_url
+            .get_or_insert_with(|| "https://api.together.xyz/v1".to_string());
+        cfg.api_key_env
+            .get_or_insert_with(|| "TOGETHER_API_KEY".to_string());
+        Ok(Self {
+            inner: OpenAiProvider::new(cfg)?,
+        })
+    }
+}
+
+#[async_trait]
+impl LlmProvider for TogetherAiProvider {
+    async fn generate_patch(
+        &self,
+        role: &str,
+        context: &str,
+        instructions: &str,
+    ) -> Result<LlmPatch> {
+        self.inner.generate_patch(role, context, instructions).await
+    }
+}
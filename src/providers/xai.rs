@@ -0,0 +1,33 @@
+use super::openai::OpenAiProvider;
+use super::{LlmPatch, LlmProvider, ProviderConfig};
+use anyhow::Result;
+use async_trait::async_trait;
+
+/// xAI's Grok API is OpenAI-compatible; this wrapper only fills in xAI's defaults.
+pub struct XaiProvider {
+    inner: OpenAiProvider,
+}
+
+impl XaiProvider {
+    pub fn new(mut cfg: ProviderConfig) -> Result<Self> {
+        cfg.base_url
+            .get_or_insert_with(|| "https://api.x.ai/v1".to_string());
+        cfg.api_key_env
+            .get_or_insert_with(|| "GROK_API_KEY".to_string());
+        Ok(Self {
+            inner: OpenAiProvider::new(cfg)?,
+        })
+    }
+}
+
+#[async_trait]
+impl LlmProvider for XaiProvider {
+    async fn generate_patch(
+        &self,
+        role: &str,
+        context: &str,
+        instructions: &str,
+    ) -> Result<LlmPatch> {
+        self.inner.generate_patch(role, context, instructions).await
+    }
+}
@@ -0,0 +1,108 @@
+use crate::orchestrator::{OrchestratorSnapshot, Phase};
+use crate::test_runner::TestCounts;
+use anyhow::{Context, Result};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::path::Path;
+
+/// Per-project file recording cycle outcomes, kept next to the kata sources
+pub const STATE_FILE: &str = ".rgr-state.json";
+
+/// Outcome of one red-green-refactor cycle, appended to the state file
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CycleRecord {
+    pub cycle_number: usize,
+    pub started_at: DateTime<Utc>,
+    pub completed_at: Option<DateTime<Utc>>,
+    pub phase_reached: Phase,
+    pub tester_commit: Option<String>,
+    /// Last implementor commit, once tests pass
+    #[serde(default)]
+    pub green_commit: Option<String>,
+    pub refactor_commit: Option<String>,
+    pub implementor_attempts: usize,
+    /// Test failures after the last failed implementor attempt, if any attempt failed
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub implementor_failures: Option<String>,
+    pub success: bool,
+    /// `kata_name` at the time of the cycle
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub kata: Option<String>,
+    /// Test totals once the cycle went green
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub test_counts: Option<TestCounts>,
+}
+
+impl CycleRecord {
+    pub fn start(cycle_number: usize) -> Self {
+        Self {
+            cycle_number,
+            started_at: Utc::now(),
+            completed_at: None,
+            phase_reached: Phase::Tester,
+            tester_commit: None,
+            green_commit: None,
+            refactor_commit: None,
+            implementor_attempts: 0,
+            implementor_failures: None,
+            success: false,
+            kata: None,
+            test_counts: None,
+        }
+    }
+}
+
+/// The state file's contents: a plain array of records, or an object once a snapshot is saved
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct StateFile {
+    cycles: Vec<CycleRecord>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    snapshot: Option<OrchestratorSnapshot>,
+}
+
+fn load(project_root: &Path) -> Result<StateFile> {
+    let path = project_root.join(STATE_FILE);
+    if !path.exists() {
+        return Ok(StateFile::default());
+    }
+    let s =
+        std::fs::read_to_string(&path).with_context(|| format!("reading {}", path.display()))?;
+    if s.trim_start().starts_with('[') {
+        let cycles =
+            serde_json::from_str(&s).with_context(|| format!("parsing {}", path.display()))?;
+        return Ok(StateFile {
+            cycles,
+            snapshot: None,
+        });
+    }
+    serde_json::from_str(&s).with_context(|| format!("parsing {}", path.display()))
+}
+
+fn save(project_root: &Path, state: &StateFile) -> Result<()> {
+    let path = project_root.join(STATE_FILE);
+    let json = match &state.snapshot {
+        Some(_) => serde_json::to_string_pretty(state)?,
+        None => serde_json::to_string_pretty(&state.cycles)?,
+    };
+    std::fs::write(&path, json).with_context(|| format!("writing {}", path.display()))
+}
+
+pub fn load_records(project_root: &Path) -> Result<Vec<CycleRecord>> {
+    Ok(load(project_root)?.cycles)
+}
+
+pub fn append_record(project_root: &Path, record: &CycleRecord) -> Result<()> {
+    let mut state = load(project_root)?;
+    state.cycles.push(record.clone());
+    save(project_root, &state)
+}
+
+pub fn load_snapshot(project_root: &Path) -> Result<Option<OrchestratorSnapshot>> {
+    Ok(load(project_root)?.snapshot)
+}
+
+pub fn save_snapshot(project_root: &Path, snapshot: &OrchestratorSnapshot) -> Result<()> {
+    let mut state = load(project_root)?;
+    state.snapshot = Some(snapshot.clone());
+    save(project_root, &state)
+}
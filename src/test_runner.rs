@@ -0,0 +1,279 @@
+use anyhow::Result;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use std::path::Path;
+use std::time::SystemTime;
+
+/// Which test framework produced `test_cmd`'s output, so failures can be reported structurally
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum TestRunner {
+    #[default]
+    Cargo,
+    Pytest,
+    /// Only the exit code is meaningful; the raw output is passed through untouched
+    Generic,
+}
+
+impl TestRunner {
+    pub fn parser(self) -> &'static dyn TestResultParser {
+        match self {
+            TestRunner::Cargo => &CargoParser,
+            TestRunner::Pytest => &PytestParser,
+            TestRunner::Generic => &GenericParser,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct TestFailure {
+    pub name: String,
+    /// Assertion or panic message, as printed by the runner
+    pub message: String,
+    /// Source file of the failure, relative to the project root, when the runner reports it
+    pub file: Option<String>,
+    pub line: Option<u32>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct TestReport {
+    pub passed: Vec<String>,
+    pub failures: Vec<TestFailure>,
+}
+
+impl TestReport {
+    /// One bullet per failing test, suitable for a prompt
+    pub fn failure_summary(&self) -> String {
+        let mut summary = String::new();
+        for f in &self.failures {
+            summary.push_str(&format!("- {}", f.name));
+            if let (Some(file), Some(line)) = (&f.file, f.line) {
+                summary.push_str(&format!(" ({file}:{line})"));
+            }
+            if !f.message.is_empty() {
+                summary.push_str(":\n  ");
+                summary.push_str(&f.message.replace('\n', "\n  "));
+            }
+            summary.push('\n');
+        }
+        summary
+    }
+}
+
+/// Totals from libtest's `test result: ok. N passed; M failed; K ignored; ...` lines, summed
+/// over every test binary (unit, integration and doc tests each print one)
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TestCounts {
+    pub passed: usize,
+    pub failed: usize,
+    pub ignored: usize,
+}
+
+/// `None` when the output has no summary line, e.g. after a compile error or with another runner
+pub fn parse_test_counts(output: &str) -> Option<TestCounts> {
+    let mut counts: Option<TestCounts> = None;
+    for line in output.lines() {
+        let Some((_, totals)) = line
+            .trim_start()
+            .strip_prefix("test result: ")
+            .and_then(|l| l.split_once(". "))
+        else {
+            continue;
+        };
+        let counts = counts.get_or_insert_default();
+        for part in totals.split("; ") {
+            let Some((n, what)) = part.split_once(' ') else {
+                continue;
+            };
+            let Ok(n) = n.parse::<usize>() else {
+                continue;
+            };
+            match what {
+                "passed" => counts.passed += n,
+                "failed" => counts.failed += n,
+                "ignored" => counts.ignored += n,
+                _ => {}
+            }
+        }
+    }
+    counts
+}
+
+pub trait TestResultParser: Send + Sync {
+    fn parse(&self, output: &str) -> TestReport;
+}
+
+/// libtest output: `test name ... ok|FAILED` lines and `---- name stdout ----` failure blocks
+pub struct CargoParser;
+
+impl TestResultParser for CargoParser {
+    fn parse(&self, output: &str) -> TestReport {
+        let mut report = TestReport::default();
+        let mut current: Option<TestFailure> = None;
+        for line in output.lines() {
+            if let Some(name) = line
+                .strip_prefix("---- ")
+                .and_then(|l| l.strip_suffix(" stdout ----"))
+            {
+                report.failures.extend(current.take());
+                current = Some(TestFailure {
+                    name: name.to_string(),
+                    ..Default::default()
+                });
+                continue;
+            }
+            if let Some(failure) = current.as_mut() {
+                // The `failures:` list that follows the blocks ends the last one
+                if line == "failures:" || line.starts_with("test result:") {
+                    report.failures.extend(current.take());
+                } else if !line.starts_with("note: ") {
+                    if !failure.message.is_empty() {
+                        failure.message.push('\n');
+                    }
+                    failure.message.push_str(line);
+                }
+                continue;
+            }
+            if let Some(name) = line
+                .strip_prefix("test ")
+                .and_then(|l| l.strip_suffix(" ... ok"))
+            {
+                report.passed.push(name.to_string());
+            }
+        }
+        report.failures.extend(current);
+        for f in &mut report.failures {
+            // Backtraces (RUST_BACKTRACE=1) add noise without saying what failed
+            let message = f
+                .message
+                .split("stack backtrace:")
+                .next()
+                .unwrap_or_default();
+            f.message = message.trim().to_string();
+            if let Some((file, line)) = panic_location(&f.message) {
+                f.file = Some(file);
+                f.line = Some(line);
+            }
+        }
+        report
+    }
+}
+
+/// `file:line` from `panicked at src/lib.rs:4:25:` (or the pre-1.73 `panicked at 'msg', src/lib.rs:4:25`)
+fn panic_location(message: &str) -> Option<(String, u32)> {
+    let rest = &message[message.find("panicked at ")? + "panicked at ".len()..];
+    let rest = match rest.strip_prefix('\'') {
+        Some(quoted) => &quoted[quoted.find("', ")? + 3..],
+        None => rest,
+    };
+    let mut parts = rest.split(':');
+    let file = parts.next()?;
+    let line = parts.next()?.trim().parse().ok()?;
+    Some((file.to_string(), line))
+}
+
+/// pytest output: `FAILED path::test - message` summary lines and `-v` style `path::test PASSED` lines
+pub struct PytestParser;
+
+impl TestResultParser for PytestParser {
+    fn parse(&self, output: &str) -> TestReport {
+        let mut report = TestReport::default();
+        for line in output.lines().map(str::trim) {
+            if let Some(rest) = line.strip_prefix("FAILED ") {
+                let (name, message) = rest.split_once(" - ").unwrap_or((rest, ""));
+                let name = name.trim();
+                report.failures.push(TestFailure {
+                    name: name.to_string(),
+                    message: message.trim().to_string(),
+                    file: name.split_once("::").map(|(file, _)| file.to_string()),
+                    line: None,
+                });
+            } else if let Some(rest) = line.strip_prefix("PASSED ") {
+                report.passed.push(rest.trim().to_string());
+            } else if let Some((name, _)) = line.split_once(" PASSED") {
+                report.passed.push(name.trim().to_string());
+            }
+        }
+        report
+    }
+}
+
+/// Where `cargo nextest run --profile ci` writes its JUnit report, given
+/// `[profile.ci.junit] path = "junit.xml"` in `.config/nextest.toml`
+pub const NEXTEST_JUNIT_PATH: &str = "target/nextest/ci/junit.xml";
+
+/// The nextest JUnit report, if `cmd` runs nextest and the report was written at or after
+/// `since` (an older file belongs to a previous run)
+pub fn read_nextest_report(
+    project_root: &Path,
+    cmd: &str,
+    since: SystemTime,
+) -> Option<TestReport> {
+    if !cmd.contains("nextest") {
+        return None;
+    }
+    let path = project_root.join(NEXTEST_JUNIT_PATH);
+    let modified = std::fs::metadata(&path).and_then(|m| m.modified()).ok()?;
+    if modified < since {
+        return None;
+    }
+    let xml = std::fs::read_to_string(&path).ok()?;
+    match parse_junit(&xml) {
+        Ok(report) => Some(report),
+        Err(e) => {
+            tracing::warn!("Ignoring unreadable {}: {e}", path.display());
+            None
+        }
+    }
+}
+
+/// JUnit XML: every `<testcase>` passes unless it holds a `<failure>` or `<error>`, whose text
+/// (or `message` attribute) carries the panic
+pub fn parse_junit(xml: &str) -> Result<TestReport> {
+    let doc = roxmltree::Document::parse(xml)?;
+    let mut report = TestReport::default();
+    for case in doc.descendants().filter(|n| n.has_tag_name("testcase")) {
+        let name = case.attribute("name").unwrap_or_default().to_string();
+        let Some(failure) = case
+            .children()
+            .find(|n| n.has_tag_name("failure") || n.has_tag_name("error"))
+        else {
+            if !case.children().any(|n| n.has_tag_name("skipped")) {
+                report.passed.push(name);
+            }
+            continue;
+        };
+        let text = failure.text().unwrap_or_default().trim();
+        let message = if text.is_empty() {
+            failure.attribute("message").unwrap_or_default()
+        } else {
+            text
+        };
+        let message = message
+            .split("stack backtrace:")
+            .next()
+            .unwrap_or_default()
+            .trim()
+            .to_string();
+        // nextest keeps the panic in the captured stderr and only a summary in the failure
+        let location = panic_location(&message).or_else(|| {
+            let stderr = case.children().find(|n| n.has_tag_name("system-err"))?;
+            panic_location(stderr.text()?)
+        });
+        report.failures.push(TestFailure {
+            name,
+            message,
+            file: location.as_ref().map(|(file, _)| file.clone()),
+            line: location.map(|(_, line)| line),
+        });
+    }
+    Ok(report)
+}
+
+pub struct GenericParser;
+
+impl TestResultParser for GenericParser {
+    fn parse(&self, _output: &str) -> TestReport {
+        TestReport::default()
+    }
+}
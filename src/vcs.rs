@@ -1,8 +1,13 @@
-use anyhow::{Result, anyhow};
+use crate::orchestrator::Phase;
+use crate::state;
+use anyhow::{Context, Result, anyhow};
+use serde::Serialize;
+use std::io::Write;
 use std::path::{Path, PathBuf};
 use tokio::process::Command;
+use tracing::info;
 
-async fn run_git(project_root: &Path, args: &[&str]) -> Result<(bool, String)> {
+pub(crate) async fn run_git(project_root: &Path, args: &[&str]) -> Result<(bool, String)> {
     let output = Command::new("git")
         .args(args)
         .current_dir(project_root)
@@ -14,46 +19,253 @@ async fn run_git(project_root: &Path, args: &[&str]) -> Result<(bool, String)> {
     Ok((output.status.success(), text))
 }
 
-pub async fn ensure_repo(project_root: &Path) -> Result<()> {
-    let git_dir = project_root.join(".git");
-    if git_dir.exists() {
-        return Ok(());
+/// Make sure `project_root` is inside a conflict-free repo with a commit identity, applying
+/// `git_config` (local config, in order) before anything is committed
+pub async fn ensure_repo(project_root: &Path, git_config: &[(&str, &str)]) -> Result<()> {
+    // Workspace members live inside the workspace's repo, so only init outside any work tree
+    let Ok(repo_root) = get_repo_root(project_root).await else {
+        return init_with_initial_commit(project_root, git_config).await;
+    };
+    if repo_root != project_root.canonicalize()? {
+        info!(
+            "Using the git repository at {} (project is a subdirectory)",
+            repo_root.display()
+        );
+    }
+    // Any patch would be built on top of the conflict markers
+    if has_merge_conflicts(project_root).await? {
+        return Err(anyhow!(
+            "Resolve merge conflicts in {} before running red-green-refactor",
+            conflicted_files(project_root).await?.join(", ")
+        ));
+    }
+    set_local_git_configs(&repo_root, git_config).await?;
+    ensure_git_user_configured(&repo_root).await
+}
+
+/// Whether a merge, rebase or cherry-pick left unresolved conflicts in the index
+pub async fn has_merge_conflicts(project_root: &Path) -> Result<bool> {
+    Ok(!conflicted_files(project_root).await?.is_empty())
+}
+
+async fn conflicted_files(project_root: &Path) -> Result<Vec<String>> {
+    let (ok, out) = run_git(project_root, &["diff", "--name-only", "--diff-filter=U"]).await?;
+    if !ok {
+        return Err(anyhow!("git diff --diff-filter=U failed: {}", out));
     }
+    Ok(out.lines().map(str::to_string).collect())
+}
+
+/// Top of the work tree containing `project_root`, which may be a subdirectory of it (e.g. a
+/// Cargo workspace member). Git commands run from `project_root` still act on this repo, and
+/// `--relative`/`-- .` keep their paths scoped to the project.
+pub async fn get_repo_root(project_root: &Path) -> Result<PathBuf> {
+    let (ok, out) = run_git(project_root, &["rev-parse", "--show-toplevel"]).await?;
+    if !ok {
+        return Err(anyhow!(
+            "{} is not inside a git repository: {}",
+            project_root.display(),
+            out.trim()
+        ));
+    }
+    Ok(PathBuf::from(out.trim()).canonicalize()?)
+}
+
+/// Where `project_root` sits within its repo (empty at the top of the work tree), which is
+/// also where the project is found in any other worktree of that repo
+pub async fn project_path_in_repo(project_root: &Path) -> Result<PathBuf> {
+    let repo_root = get_repo_root(project_root).await?;
+    Ok(project_root
+        .canonicalize()?
+        .strip_prefix(&repo_root)
+        .map(Path::to_path_buf)
+        .unwrap_or_default())
+}
+
+pub const INITIAL_COMMIT_MESSAGE: &str = "chore: init repo for red-green-refactor";
+
+/// `git init` plus an empty first commit, so `HEAD` resolves from the very first cycle;
+/// `git_config` is applied in between so the commit already honours it (identity, signing)
+pub async fn init_with_initial_commit(
+    project_root: &Path,
+    git_config: &[(&str, &str)],
+) -> Result<()> {
     let (ok, out) = run_git(project_root, &["init"]).await?;
     if !ok {
         return Err(anyhow!("git init failed: {}", out));
     }
+    set_local_git_configs(project_root, git_config).await?;
+    ensure_git_user_configured(project_root).await?;
+    let (has_head, _) =
+        run_git(project_root, &["rev-parse", "--verify", "--quiet", "HEAD"]).await?;
+    if !has_head {
+        // Nothing is staged in a fresh repo, so the commit is empty
+        let (ok, out) = run_git(
+            project_root,
+            &["commit", "--allow-empty", "-m", INITIAL_COMMIT_MESSAGE],
+        )
+        .await?;
+        if !ok {
+            return Err(anyhow!("initial commit failed: {}", out));
+        }
+    }
     Ok(())
 }
 
-pub async fn commit_paths(project_root: &Path, paths: &[PathBuf], message: &str) -> Result<()> {
-    if !paths.is_empty() {
-        let output = {
-            let mut c = Command::new("git");
-            c.arg("add").arg("--");
-            for p in paths {
-                c.arg(p);
-            }
-            c.current_dir(project_root).output().await?
-        };
-        if !output.status.success() {
-            let mut text = String::new();
-            text.push_str(&String::from_utf8_lossy(&output.stdout));
-            text.push_str(&String::from_utf8_lossy(&output.stderr));
-            return Err(anyhow!("git add failed: {}", text));
+pub const DEFAULT_GIT_USER_NAME: &str = "Red-Green-Refactor Bot";
+pub const DEFAULT_GIT_USER_EMAIL: &str = "rgr@localhost";
+
+/// Give the repo a local commit identity when none is configured (fresh containers, CI),
+/// so `git commit` doesn't fail halfway through a cycle
+pub async fn ensure_git_user_configured(project_root: &Path) -> Result<()> {
+    for (key, default) in [
+        ("user.email", DEFAULT_GIT_USER_EMAIL),
+        ("user.name", DEFAULT_GIT_USER_NAME),
+    ] {
+        if get_git_config(project_root, key).await?.is_none() {
+            set_local_git_config(project_root, key, default).await?;
         }
     }
-    let (ok, out) = run_git(project_root, &["commit", "--allow-empty", "-m", message]).await?;
+    Ok(())
+}
+
+/// Effective value of a git config key (local, global or system), if set and non-blank
+async fn get_git_config(project_root: &Path, key: &str) -> Result<Option<String>> {
+    // `git config` exits with 1 when the key is unset
+    let (_, out) = run_git(project_root, &["config", "--get", key]).await?;
+    let value = out.trim();
+    Ok((!value.is_empty()).then(|| value.to_string()))
+}
+
+/// `git config --local key value` in the repo containing `project_root`
+pub async fn set_local_git_config(project_root: &Path, key: &str, value: &str) -> Result<()> {
+    let (ok, out) = run_git(project_root, &["config", "--local", key, value]).await?;
+    if !ok {
+        return Err(anyhow!("git config --local {} failed: {}", key, out));
+    }
+    Ok(())
+}
+
+async fn set_local_git_configs(project_root: &Path, git_config: &[(&str, &str)]) -> Result<()> {
+    for (key, value) in git_config {
+        set_local_git_config(project_root, key, value).await?;
+    }
+    Ok(())
+}
+
+/// `Rgr-*` trailers recording which cycle, phase and model produced a commit
+pub fn annotate_cycle_metadata(
+    phase: Phase,
+    cycle_number: usize,
+    model: &str,
+    attempt: Option<usize>,
+) -> Vec<(&'static str, String)> {
+    let mut trailers = vec![
+        ("Rgr-Phase", phase.as_str().to_string()),
+        ("Rgr-Cycle", cycle_number.to_string()),
+        ("Rgr-Model", model.to_string()),
+    ];
+    if let Some(attempt) = attempt {
+        trailers.push(("Rgr-Attempt", attempt.to_string()));
+    }
+    trailers
+}
+
+/// Stage `paths` and commit them, adding `trailers` as `Key: value` lines after the message
+pub async fn commit_paths(
+    project_root: &Path,
+    paths: &[PathBuf],
+    message: &str,
+    trailers: &[(&str, String)],
+) -> Result<()> {
+    stage_paths(project_root, paths).await?;
+    let trailers: Vec<String> = trailers
+        .iter()
+        .map(|(key, value)| format!("--trailer={key}: {value}"))
+        .collect();
+    let mut args = vec!["commit", "--allow-empty", "-m", message];
+    args.extend(trailers.iter().map(String::as_str));
+    let (ok, out) = run_git(project_root, &args).await?;
     if !ok {
         return Err(anyhow!("git commit failed: {}", out));
     }
     Ok(())
 }
 
-pub async fn reset_hard_head_minus_one(project_root: &Path) -> Result<()> {
-    let (ok, out) = run_git(project_root, &["reset", "--hard", "HEAD~1"]).await?;
+/// Stage `additional_paths` into the last commit, keeping its message unless `new_message`
+/// is given
+pub async fn amend_last_commit(
+    project_root: &Path,
+    additional_paths: &[PathBuf],
+    new_message: Option<&str>,
+) -> Result<()> {
+    stage_paths(project_root, additional_paths).await?;
+    let mut args = vec!["commit", "--amend", "--allow-empty"];
+    match new_message {
+        Some(message) => args.extend(["-m", message]),
+        None => args.push("--no-edit"),
+    }
+    let (ok, out) = run_git(project_root, &args).await?;
+    if !ok {
+        return Err(anyhow!("git commit --amend failed: {}", out));
+    }
+    Ok(())
+}
+
+async fn stage_paths(project_root: &Path, paths: &[PathBuf]) -> Result<()> {
+    if paths.is_empty() {
+        return Ok(());
+    }
+    let (ok, out) = run_git_with_paths(project_root, &["add"], paths).await?;
     if !ok {
-        return Err(anyhow!("git reset failed: {}", out));
+        return Err(anyhow!("git add failed: {}", out));
+    }
+    Ok(())
+}
+
+/// Untracked files under the project, relative to it; ignored files are left out
+pub async fn untracked_files(project_root: &Path) -> Result<Vec<PathBuf>> {
+    let (ok, out) = run_git(
+        project_root,
+        &["ls-files", "--others", "--exclude-standard", "-z"],
+    )
+    .await?;
+    if !ok {
+        return Err(anyhow!("git ls-files --others failed: {}", out));
+    }
+    Ok(out
+        .split('\0')
+        .filter(|p| !p.is_empty())
+        .map(PathBuf::from)
+        .collect())
+}
+
+/// Delete the untracked files that are not under one of the `keep` paths (files or
+/// directories relative to the project root), along with the directories this empties.
+/// The rgr state file, ignored files and nested repositories are never touched.
+async fn remove_untracked(project_root: &Path, keep: &[PathBuf]) -> Result<()> {
+    for rel in untracked_files(project_root).await? {
+        // Nested repositories are listed as `dir/`
+        if rel.to_string_lossy().ends_with('/')
+            || rel == Path::new(state::STATE_FILE)
+            || keep.iter().any(|k| rel.starts_with(k))
+        {
+            continue;
+        }
+        let path = project_root.join(&rel);
+        tokio::fs::remove_file(&path)
+            .await
+            .with_context(|| format!("removing {}", path.display()))?;
+        for dir in rel
+            .ancestors()
+            .skip(1)
+            .filter(|d| !d.as_os_str().is_empty())
+        {
+            // Fails, ending the walk, on the first directory that still has entries
+            if tokio::fs::remove_dir(project_root.join(dir)).await.is_err() {
+                break;
+            }
+        }
     }
     Ok(())
 }
@@ -66,14 +278,154 @@ pub async fn get_head_commit(project_root: &Path) -> Result<String> {
     Ok(out.trim().to_string())
 }
 
-pub async fn reset_hard_to(project_root: &Path, target: &str) -> Result<()> {
+/// Modified, staged and untracked paths under the project, per `git status --porcelain`
+pub async fn uncommitted_changes(project_root: &Path) -> Result<Vec<String>> {
+    let (ok, out) = run_git(project_root, &["status", "--porcelain", "--", "."]).await?;
+    if !ok {
+        return Err(anyhow!("git status failed: {}", out));
+    }
+    Ok(out
+        .lines()
+        .filter_map(|l| l.get(3..))
+        .map(str::to_string)
+        .collect())
+}
+
+pub async fn is_working_tree_clean(project_root: &Path) -> Result<bool> {
+    Ok(uncommitted_changes(project_root).await?.is_empty())
+}
+
+/// Lines added between `from` and the current HEAD, per `git diff --numstat`
+pub async fn lines_added_since(project_root: &Path, from: &str) -> Result<usize> {
+    let (ok, out) = run_git(project_root, &["diff", "--numstat", from, "HEAD"]).await?;
+    if !ok {
+        return Err(anyhow!("git diff --numstat {} HEAD failed: {}", from, out));
+    }
+    // Binary files report `-` and count as zero
+    Ok(out
+        .lines()
+        .filter_map(|l| l.split('\t').next()?.parse::<usize>().ok())
+        .sum())
+}
+
+/// Unified diff between `from` and the current HEAD
+pub async fn diff_since(project_root: &Path, from: &str) -> Result<String> {
+    let (ok, out) = run_git(project_root, &["diff", from, "HEAD"]).await?;
+    if !ok {
+        return Err(anyhow!("git diff {} HEAD failed: {}", from, out));
+    }
+    Ok(out)
+}
+
+/// Uncommitted diff of `paths` against HEAD, new files included
+pub async fn diff_paths(project_root: &Path, paths: &[PathBuf]) -> Result<String> {
+    // Intent-to-add makes untracked files show up in `git diff`; commit_paths stages them anyway
+    let (ok, out) = run_git_with_paths(project_root, &["add", "--intent-to-add"], paths).await?;
+    if !ok {
+        return Err(anyhow!("git add --intent-to-add failed: {}", out));
+    }
+    let (ok, out) = run_git_with_paths(project_root, &["diff"], paths).await?;
+    if !ok {
+        return Err(anyhow!("git diff failed: {}", out));
+    }
+    Ok(out)
+}
+
+/// Throw away uncommitted changes to `paths`: tracked files are restored from HEAD, new files
+/// and directories deleted
+pub async fn discard_paths(project_root: &Path, paths: &[PathBuf]) -> Result<()> {
+    for path in paths {
+        let (_, tracked) = run_git_with_paths(
+            project_root,
+            &["ls-tree", "--name-only", "HEAD"],
+            std::slice::from_ref(path),
+        )
+        .await?;
+        if tracked.trim().is_empty() {
+            run_git_with_paths(
+                project_root,
+                &["rm", "-r", "--cached", "--quiet", "--ignore-unmatch"],
+                std::slice::from_ref(path),
+            )
+            .await?;
+            if path.is_dir() {
+                std::fs::remove_dir_all(path)?;
+            } else if path.exists() {
+                std::fs::remove_file(path)?;
+            }
+        } else {
+            let (ok, out) = run_git_with_paths(
+                project_root,
+                &["checkout", "HEAD"],
+                std::slice::from_ref(path),
+            )
+            .await?;
+            if !ok {
+                return Err(anyhow!(
+                    "git checkout HEAD -- {} failed: {}",
+                    path.display(),
+                    out
+                ));
+            }
+        }
+    }
+    Ok(())
+}
+
+async fn run_git_with_paths(
+    project_root: &Path,
+    args: &[&str],
+    paths: &[PathBuf],
+) -> Result<(bool, String)> {
+    let mut all: Vec<&std::ffi::OsStr> = args.iter().map(|a| a.as_ref()).collect();
+    all.push("--".as_ref());
+    all.extend(paths.iter().map(|p| p.as_os_str()));
+    let output = Command::new("git")
+        .args(&all)
+        .current_dir(project_root)
+        .output()
+        .await?;
+    let mut text = String::new();
+    text.push_str(&String::from_utf8_lossy(&output.stdout));
+    text.push_str(&String::from_utf8_lossy(&output.stderr));
+    Ok((output.status.success(), text))
+}
+
+/// Files (relative to the project root) changed between `from` and the working tree
+pub async fn changed_files_since(project_root: &Path, from: &str) -> Result<Vec<String>> {
+    let (ok, out) = run_git(project_root, &["diff", "--name-only", "--relative", from]).await?;
+    if !ok {
+        return Err(anyhow!("git diff --name-only {} failed: {}", from, out));
+    }
+    Ok(out.lines().map(String::from).collect())
+}
+
+/// `git reset --hard target`; with `keep_untracked`, untracked files outside those paths
+/// (e.g. created by a patch or a test run) are removed too, so they cannot leak into the
+/// next cycle. Pass the files that were untracked before, so the user's own survive.
+pub async fn reset_hard_to(
+    project_root: &Path,
+    target: &str,
+    keep_untracked: Option<&[PathBuf]>,
+) -> Result<()> {
     let (ok, out) = run_git(project_root, &["reset", "--hard", target]).await?;
     if !ok {
         return Err(anyhow!("git reset --hard {} failed: {}", target, out));
     }
+    if let Some(keep) = keep_untracked {
+        remove_untracked(project_root, keep).await?;
+    }
     Ok(())
 }
 
+/// Drop the last commit, handling untracked files like `reset_hard_to`
+pub async fn reset_hard_head_minus_one(
+    project_root: &Path,
+    keep_untracked: Option<&[PathBuf]>,
+) -> Result<()> {
+    reset_hard_to(project_root, "HEAD~1", keep_untracked).await
+}
+
 pub async fn create_branch_at_head(project_root: &Path, name: &str) -> Result<()> {
     let (ok, out) = run_git(project_root, &["branch", name]).await?;
     if !ok {
@@ -81,3 +433,557 @@ pub async fn create_branch_at_head(project_root: &Path, name: &str) -> Result<()
     }
     Ok(())
 }
+
+pub async fn tag_head(project_root: &Path, name: &str) -> Result<()> {
+    let (ok, out) = run_git(project_root, &["tag", name]).await?;
+    if !ok {
+        return Err(anyhow!("git tag {} failed: {}", name, out));
+    }
+    Ok(())
+}
+
+pub async fn delete_tag(project_root: &Path, name: &str) -> Result<()> {
+    let (ok, out) = run_git(project_root, &["tag", "-d", name]).await?;
+    if !ok {
+        return Err(anyhow!("git tag -d {} failed: {}", name, out));
+    }
+    Ok(())
+}
+
+/// Whether `ancestor` is reachable from `rev` (a commit counts as its own ancestor)
+pub async fn is_ancestor(project_root: &Path, ancestor: &str, rev: &str) -> Result<bool> {
+    let (ok, _) = run_git(
+        project_root,
+        &["merge-base", "--is-ancestor", ancestor, rev],
+    )
+    .await?;
+    Ok(ok)
+}
+
+/// Subject line of `rev`'s commit message
+pub async fn commit_subject(project_root: &Path, rev: &str) -> Result<String> {
+    let (ok, out) = run_git(project_root, &["log", "-1", "--format=%s", rev]).await?;
+    if !ok {
+        return Err(anyhow!("git log {} failed: {}", rev, out));
+    }
+    Ok(out.trim().to_string())
+}
+
+/// Tags matching `pattern`, most recently created first
+pub async fn list_tags(project_root: &Path, pattern: &str) -> Result<Vec<String>> {
+    let (ok, out) = run_git(
+        project_root,
+        // Last --sort key is primary; refname breaks ties within the same second
+        &[
+            "tag",
+            "--list",
+            pattern,
+            "--sort=-refname",
+            "--sort=-creatordate",
+        ],
+    )
+    .await?;
+    if !ok {
+        return Err(anyhow!("git tag --list {} failed: {}", pattern, out));
+    }
+    Ok(out
+        .lines()
+        .map(str::trim)
+        .filter(|l| !l.is_empty())
+        .map(String::from)
+        .collect())
+}
+
+/// Apply `commit` onto HEAD; on conflicts git's output is returned and the cherry-pick is
+/// left in progress for manual resolution
+pub async fn cherry_pick(project_root: &Path, commit: &str) -> Result<()> {
+    let (ok, out) = run_git(project_root, &["cherry-pick", commit]).await?;
+    if ok {
+        return Ok(());
+    }
+    let (in_progress, _) = run_git(
+        project_root,
+        &["rev-parse", "--quiet", "--verify", "CHERRY_PICK_HEAD"],
+    )
+    .await?;
+    if in_progress {
+        return Err(anyhow!(
+            "git cherry-pick {} stopped on conflicts; resolve them and run `git cherry-pick --continue` (or `--abort`): {}",
+            commit,
+            out
+        ));
+    }
+    Err(anyhow!("git cherry-pick {} failed: {}", commit, out))
+}
+
+/// Move the commits `attempt_branch` added since it forked from `tester_sha`'s history onto
+/// `tester_sha`, leaving the rebased branch checked out. On conflicts git's output is
+/// returned and the rebase is left in progress for manual resolution.
+pub async fn rebase_attempts_onto_tester(
+    project_root: &Path,
+    attempt_branch: &str,
+    tester_sha: &str,
+) -> Result<()> {
+    let (ok, out) = run_git(project_root, &["merge-base", attempt_branch, tester_sha]).await?;
+    if !ok {
+        return Err(anyhow!(
+            "git merge-base {} {} failed: {}",
+            attempt_branch,
+            tester_sha,
+            out
+        ));
+    }
+    let fork_point = out.trim();
+    let (ok, out) = run_git(
+        project_root,
+        &["rebase", "--onto", tester_sha, fork_point, attempt_branch],
+    )
+    .await?;
+    if ok {
+        return Ok(());
+    }
+    let (in_progress, _) = run_git(
+        project_root,
+        &["rev-parse", "--quiet", "--verify", "REBASE_HEAD"],
+    )
+    .await?;
+    if in_progress {
+        return Err(anyhow!(
+            "rebasing {} onto {} stopped on conflicts; resolve them and run `git rebase --continue` (or `--abort`): {}",
+            attempt_branch,
+            tester_sha,
+            out
+        ));
+    }
+    Err(anyhow!(
+        "git rebase --onto {} {} failed: {}",
+        tester_sha,
+        attempt_branch,
+        out
+    ))
+}
+
+pub async fn stash_push(project_root: &Path, message: &str) -> Result<()> {
+    let (ok, out) = run_git(
+        project_root,
+        &["stash", "push", "--include-untracked", "-m", message],
+    )
+    .await?;
+    if !ok {
+        return Err(anyhow!("git stash failed: {}", out));
+    }
+    Ok(())
+}
+
+/// Name of the checked-out branch (works on an unborn branch too)
+pub async fn current_branch(project_root: &Path) -> Result<String> {
+    let (ok, out) = run_git(project_root, &["symbolic-ref", "--short", "HEAD"]).await?;
+    if !ok {
+        return Err(anyhow!("git symbolic-ref HEAD failed: {}", out));
+    }
+    Ok(out.trim().to_string())
+}
+
+pub async fn checkout_new_branch(project_root: &Path, name: &str) -> Result<()> {
+    let (ok, out) = run_git(project_root, &["checkout", "-b", name]).await?;
+    if !ok {
+        return Err(anyhow!("git checkout -b {} failed: {}", name, out));
+    }
+    Ok(())
+}
+
+pub async fn checkout_branch(project_root: &Path, name: &str) -> Result<()> {
+    let (ok, out) = run_git(project_root, &["checkout", name]).await?;
+    if !ok {
+        return Err(anyhow!("git checkout {} failed: {}", name, out));
+    }
+    Ok(())
+}
+
+pub async fn merge_branch_no_ff(project_root: &Path, name: &str, message: &str) -> Result<()> {
+    let (ok, out) = run_git(project_root, &["merge", "--no-ff", "-m", message, name]).await?;
+    if !ok {
+        return Err(anyhow!("git merge --no-ff {} failed: {}", name, out));
+    }
+    Ok(())
+}
+
+pub async fn delete_branch(project_root: &Path, name: &str) -> Result<()> {
+    let (ok, out) = run_git(project_root, &["branch", "-D", name]).await?;
+    if !ok {
+        return Err(anyhow!("git branch -D {} failed: {}", name, out));
+    }
+    Ok(())
+}
+
+/// Local branches matching a `git branch --list` pattern such as `attempts/*`
+pub async fn list_branches_by_pattern(project_root: &Path, pattern: &str) -> Result<Vec<String>> {
+    let (ok, out) = run_git(
+        project_root,
+        &["branch", "--list", "--format=%(refname:short)", pattern],
+    )
+    .await?;
+    if !ok {
+        return Err(anyhow!("git branch --list {} failed: {}", pattern, out));
+    }
+    Ok(out
+        .lines()
+        .map(str::trim)
+        .filter(|l| !l.is_empty())
+        .map(String::from)
+        .collect())
+}
+
+/// `(hash, subject)` of the commits on `rev` that HEAD does not have, oldest first
+/// (for an attempts branch: the failed implementor attempts after the tester commit)
+pub async fn commits_not_on_head(project_root: &Path, rev: &str) -> Result<Vec<(String, String)>> {
+    let range = format!("HEAD..{rev}");
+    let (ok, out) = run_git(
+        project_root,
+        &["log", "--reverse", "--format=%H%x09%s", &range],
+    )
+    .await?;
+    if !ok {
+        return Err(anyhow!("git log {} failed: {}", range, out));
+    }
+    Ok(out
+        .lines()
+        .filter_map(|l| l.split_once('\t'))
+        .map(|(hash, subject)| (hash.to_string(), subject.to_string()))
+        .collect())
+}
+
+/// The change a single commit made, as a patch
+pub async fn commit_diff(project_root: &Path, commit: &str) -> Result<String> {
+    let (ok, out) = run_git(project_root, &["show", "--format=", commit]).await?;
+    if !ok {
+        return Err(anyhow!("git show {} failed: {}", commit, out));
+    }
+    Ok(out)
+}
+
+/// Committer time of a ref's tip commit, as a Unix timestamp
+pub async fn commit_timestamp(project_root: &Path, rev: &str) -> Result<i64> {
+    let (ok, out) = run_git(project_root, &["log", "-1", "--format=%ct", rev]).await?;
+    if !ok {
+        return Err(anyhow!("git log -1 {} failed: {}", rev, out));
+    }
+    out.trim()
+        .parse()
+        .map_err(|e| anyhow!("unexpected git log output for {rev}: {e}"))
+}
+
+pub async fn branch_exists(project_root: &Path, name: &str) -> Result<bool> {
+    let (ok, _) = run_git(
+        project_root,
+        &[
+            "rev-parse",
+            "--verify",
+            "--quiet",
+            &format!("refs/heads/{name}"),
+        ],
+    )
+    .await?;
+    Ok(ok)
+}
+
+/// Check `branch` out in a new worktree at `project_root/name`, creating the branch at HEAD
+/// if needed, and return its path. A worktree left there by an earlier run is replaced, and
+/// `name` is added to `.git/info/exclude` so the main worktree stays clean.
+pub async fn git_worktree_create(project_root: &Path, name: &str, branch: &str) -> Result<PathBuf> {
+    let path = project_root.join(name);
+    if path.exists() {
+        git_worktree_remove(project_root, &path).await?;
+    }
+    let path_s = path.to_string_lossy();
+    let args: &[&str] = if branch_exists(project_root, branch).await? {
+        &["worktree", "add", &path_s, branch]
+    } else {
+        &["worktree", "add", "-b", branch, &path_s]
+    };
+    let (ok, out) = run_git(project_root, args).await?;
+    if !ok {
+        return Err(anyhow!(
+            "git worktree add {} failed: {}",
+            path.display(),
+            out
+        ));
+    }
+    let (ok, out) = run_git(project_root, &["rev-parse", "--git-path", "info/exclude"]).await?;
+    if !ok {
+        return Err(anyhow!("git rev-parse --git-path failed: {}", out));
+    }
+    let exclude = project_root.join(out.trim());
+    let excluded = std::fs::read_to_string(&exclude).unwrap_or_default();
+    // Anchored at the top of the work tree, which may be above the project
+    let mut entry = String::from("/");
+    for part in project_path_in_repo(project_root)
+        .await?
+        .join(name)
+        .components()
+    {
+        entry.push_str(&part.as_os_str().to_string_lossy());
+        entry.push('/');
+    }
+    if !excluded.lines().any(|line| line.trim() == entry) {
+        if let Some(dir) = exclude.parent() {
+            std::fs::create_dir_all(dir)?;
+        }
+        let mut file = std::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&exclude)?;
+        let sep = if excluded.is_empty() || excluded.ends_with('\n') {
+            ""
+        } else {
+            "\n"
+        };
+        writeln!(file, "{sep}{entry}")?;
+    }
+    Ok(path)
+}
+
+/// Delete the worktree at `path`, uncommitted changes included; its branch is kept
+pub async fn git_worktree_remove(project_root: &Path, path: &Path) -> Result<()> {
+    let path_s = path.to_string_lossy();
+    let (ok, out) = run_git(project_root, &["worktree", "remove", "--force", &path_s]).await?;
+    if !ok {
+        return Err(anyhow!(
+            "git worktree remove {} failed: {}",
+            path.display(),
+            out
+        ));
+    }
+    Ok(())
+}
+
+/// A commit made by one of the TDD roles, recognised by its conventional prefix
+#[derive(Debug, Clone, Serialize)]
+pub struct CommitSummary {
+    pub hash: String,
+    /// Committer date, ISO 8601
+    pub date: String,
+    pub subject: String,
+    pub phase: Phase,
+}
+
+/// Phase implied by a `test:` / `feat:` / `refactor:` subject (scopes like `feat(x):` allowed)
+pub fn phase_from_subject(subject: &str) -> Option<Phase> {
+    let kind = subject.split([':', '(', '!']).next()?;
+    if kind.len() == subject.len() {
+        return None;
+    }
+    match kind {
+        "test" => Some(Phase::Tester),
+        "feat" => Some(Phase::Implementor),
+        "refactor" => Some(Phase::Refactorer),
+        _ => None,
+    }
+}
+
+/// The most recent `limit` tester/implementor/refactorer commits reachable from HEAD, newest first
+pub async fn list_rgr_commits(project_root: &Path, limit: usize) -> Result<Vec<CommitSummary>> {
+    let (has_head, _) =
+        run_git(project_root, &["rev-parse", "--verify", "--quiet", "HEAD"]).await?;
+    if !has_head {
+        return Ok(Vec::new());
+    }
+    let (ok, out) = run_git(project_root, &["log", "--format=%H%x1f%cI%x1f%s"]).await?;
+    if !ok {
+        return Err(anyhow!("git log failed: {}", out));
+    }
+    Ok(out
+        .lines()
+        .filter_map(|line| {
+            let mut fields = line.splitn(3, '\x1f');
+            let (hash, date, subject) = (fields.next()?, fields.next()?, fields.next()?);
+            Some(CommitSummary {
+                hash: hash.to_string(),
+                date: date.to_string(),
+                subject: subject.to_string(),
+                phase: phase_from_subject(subject)?,
+            })
+        })
+        .take(limit)
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn temp_repo() -> tempfile::TempDir {
+        let dir = tempfile::tempdir().unwrap();
+        init_with_initial_commit(dir.path(), &[]).await.unwrap();
+        dir
+    }
+
+    async fn commit_count(root: &Path) -> usize {
+        let (ok, out) = run_git(root, &["rev-list", "--count", "HEAD"])
+            .await
+            .unwrap();
+        assert!(ok, "{out}");
+        out.trim().parse().unwrap()
+    }
+
+    #[tokio::test]
+    async fn ensure_repo_creates_the_initial_commit_once() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path();
+        ensure_repo(root, &[]).await.unwrap();
+        let head = get_head_commit(root).await.unwrap();
+        assert_eq!(
+            commit_subject(root, &head).await.unwrap(),
+            INITIAL_COMMIT_MESSAGE
+        );
+
+        ensure_repo(root, &[]).await.unwrap();
+
+        assert_eq!(get_head_commit(root).await.unwrap(), head);
+        assert_eq!(commit_count(root).await, 1);
+    }
+
+    #[tokio::test]
+    async fn ensure_repo_applies_git_config_before_the_initial_commit() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path();
+        ensure_repo(
+            root,
+            &[
+                ("user.name", "Kata Author"),
+                ("user.email", "kata@example.com"),
+            ],
+        )
+        .await
+        .unwrap();
+
+        let (ok, out) = run_git(root, &["log", "-1", "--format=%an <%ae>"])
+            .await
+            .unwrap();
+        assert!(ok, "{out}");
+        assert_eq!(out.trim(), "Kata Author <kata@example.com>");
+    }
+
+    #[tokio::test]
+    async fn worktree_of_a_project_in_a_subdirectory_is_excluded_where_it_lives() {
+        let repo = temp_repo().await;
+        let project = repo.path().join("kata");
+        std::fs::create_dir(&project).unwrap();
+        std::fs::write(project.join("lib.rs"), "").unwrap();
+        commit_paths(repo.path(), &[project.join("lib.rs")], "add kata", &[])
+            .await
+            .unwrap();
+
+        let worktree = git_worktree_create(&project, ".rgr-work", "rgr/work")
+            .await
+            .unwrap();
+
+        assert_eq!(
+            project_path_in_repo(&project).await.unwrap(),
+            Path::new("kata")
+        );
+        assert!(worktree.join("kata/lib.rs").exists());
+        let (ok, out) = run_git(repo.path(), &["status", "--porcelain"])
+            .await
+            .unwrap();
+        assert!(ok, "{out}");
+        assert_eq!(out, "");
+    }
+
+    #[tokio::test]
+    async fn ensure_repo_leaves_an_existing_repo_alone() {
+        let (repo, head, _) = repo_with_user_files().await;
+        let root = repo.path();
+
+        ensure_repo(root, &[]).await.unwrap();
+        ensure_repo(root, &[]).await.unwrap();
+
+        assert_eq!(get_head_commit(root).await.unwrap(), head);
+        assert_eq!(commit_count(root).await, 2);
+        assert!(root.join("notes.md").exists());
+    }
+
+    /// A repo with a committed file, an ignored file and an untracked file of the user's,
+    /// and the untracked files as they were before anything else was added
+    async fn repo_with_user_files() -> (tempfile::TempDir, String, Vec<PathBuf>) {
+        let repo = temp_repo().await;
+        let root = repo.path();
+        std::fs::write(root.join("tracked.txt"), "v1\n").unwrap();
+        std::fs::write(root.join(".gitignore"), "*.log\n").unwrap();
+        let paths = [root.join("tracked.txt"), root.join(".gitignore")];
+        commit_paths(root, &paths, "add tracked", &[])
+            .await
+            .unwrap();
+        std::fs::write(root.join("build.log"), "ignored\n").unwrap();
+        std::fs::write(root.join("notes.md"), "the user's\n").unwrap();
+        let head = get_head_commit(root).await.unwrap();
+        let untracked = untracked_files(root).await.unwrap();
+        (repo, head, untracked)
+    }
+
+    #[tokio::test]
+    async fn reset_removes_the_untracked_files_added_since() {
+        let (repo, head, untracked) = repo_with_user_files().await;
+        let root = repo.path();
+        assert_eq!(untracked, [PathBuf::from("notes.md")]);
+        std::fs::write(root.join("tracked.txt"), "v2\n").unwrap();
+        std::fs::create_dir_all(root.join("src/handlers")).unwrap();
+        std::fs::write(root.join("src/handlers/auth.rs"), "fn f() {}\n").unwrap();
+        std::fs::write(root.join("scratch.txt"), "left by a test run\n").unwrap();
+
+        reset_hard_to(root, &head, Some(&untracked)).await.unwrap();
+
+        assert_eq!(
+            std::fs::read_to_string(root.join("tracked.txt")).unwrap(),
+            "v1\n"
+        );
+        assert!(!root.join("src").exists());
+        assert!(!root.join("scratch.txt").exists());
+        assert!(root.join("notes.md").exists());
+        assert!(root.join("build.log").exists());
+    }
+
+    #[tokio::test]
+    async fn reset_head_minus_one_drops_the_commit_and_the_files_it_left() {
+        let (repo, head, untracked) = repo_with_user_files().await;
+        let root = repo.path();
+        std::fs::write(root.join("tracked.txt"), "v2\n").unwrap();
+        commit_paths(root, &[root.join("tracked.txt")], "change tracked", &[])
+            .await
+            .unwrap();
+        std::fs::write(root.join("scratch.txt"), "left by a test run\n").unwrap();
+
+        reset_hard_head_minus_one(root, Some(&untracked))
+            .await
+            .unwrap();
+
+        assert_eq!(get_head_commit(root).await.unwrap(), head);
+        assert!(!root.join("scratch.txt").exists());
+        assert!(root.join("notes.md").exists());
+    }
+
+    #[tokio::test]
+    async fn reset_keeps_everything_under_a_kept_directory() {
+        let (repo, head, mut keep) = repo_with_user_files().await;
+        let root = repo.path();
+        keep.push(PathBuf::from("logs"));
+        std::fs::create_dir_all(root.join("logs")).unwrap();
+        std::fs::write(root.join("logs/0001-tester.json"), "{}").unwrap();
+
+        reset_hard_to(root, &head, Some(&keep)).await.unwrap();
+
+        assert!(root.join("logs/0001-tester.json").exists());
+    }
+
+    #[tokio::test]
+    async fn reset_without_a_keep_list_leaves_untracked_files() {
+        let (repo, head, _) = repo_with_user_files().await;
+        let root = repo.path();
+        std::fs::write(root.join("scratch.txt"), "left by a test run\n").unwrap();
+
+        reset_hard_to(root, &head, None).await.unwrap();
+
+        assert!(root.join("scratch.txt").exists());
+        assert!(root.join("notes.md").exists());
+    }
+}
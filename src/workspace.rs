@@ -1,61 +1,687 @@
-use crate::providers::{EditMode, LlmPatch};
-use anyhow::Result;
-use std::path::{Path, PathBuf};
-use tokio::{fs, io::AsyncWriteExt, process::Command};
+use crate::providers::{EditMode, FileEdit, LlmPatch};
+use crate::test_runner::{
+    self, CargoParser, TestCounts, TestFailure, TestReport, TestResultParser,
+};
+use crate::vcs;
+use anyhow::{Context, Result, anyhow};
+use globset::{Glob, GlobSetBuilder};
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use similar::TextDiff;
+use std::collections::HashMap;
+use std::fmt;
+use std::io::{IsTerminal, Read};
+use std::path::{Component, Path, PathBuf};
+use std::process::Stdio;
+use std::time::{Duration, SystemTime};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWriteExt};
+use tokio::{fs, process::Command};
+use tracing::warn;
 use walkdir::WalkDir;
 
-pub fn collect_context(project_root: &Path, max_bytes: usize) -> Result<String> {
-    let mut buf = String::new();
-    let mut total = 0usize;
+/// Knobs controlling which files end up in the LLM context and in what order
+#[derive(Debug, Clone)]
+pub struct ContextOptions {
+    pub max_bytes: usize,
+    /// Per-file cap; longer files are cut at a line boundary and marked `[TRUNCATED]`
+    pub max_file_bytes: usize,
+    /// Per-file cap for files under `tests/`, which the LLM usually needs whole
+    pub max_test_file_bytes: usize,
+    /// Files matching an earlier prefix are collected first; the rest follow alphabetically
+    pub priority_prefixes: Vec<String>,
+    /// Drop comments from `.rs` files (see `strip_rust_comments`)
+    pub strip_comments: bool,
+    /// With `strip_comments`, still keep doc comments on `pub` items
+    pub keep_pub_doc_comments: bool,
+    /// File-name globs that are never sent, whatever else would include them
+    pub secret_exclude_patterns: Vec<String>,
+    /// Mark `.rs` files that don't parse with `SYNTAX_ERROR_MARKER` (one `rustfmt` run each)
+    pub annotate_syntax_errors: bool,
+    /// Open with a "Project hints" section listing `// TODO`-style comments
+    pub include_todos: bool,
+}
+
+/// Put under the header of a context file that doesn't parse
+pub const SYNTAX_ERROR_MARKER: &str = "[SYNTAX ERROR IN THIS FILE]";
+/// Most comments listed in the "Project hints" section
+const MAX_CONTEXT_HINTS: usize = 50;
+/// How much of a file `is_likely_binary` gets to look at
+const BINARY_SNIFF_BYTES: usize = 512;
+
+pub fn collect_context(project_root: &Path, opts: &ContextOptions) -> Result<String> {
+    let files = context_files(project_root, opts)?;
+    Ok(render_context(project_root, files, opts))
+}
+
+/// Like `collect_context`, but files changed since `since_commit` come first and the
+/// remaining budget goes to the most recently modified of the others
+pub async fn collect_incremental_context(
+    project_root: &Path,
+    since_commit: &str,
+    opts: &ContextOptions,
+) -> Result<String> {
+    let changed = vcs::changed_files_since(project_root, since_commit).await?;
+    let (changed, mut unchanged): (Vec<_>, Vec<_>) = context_files(project_root, opts)?
+        .into_iter()
+        .partition(|(_, rel_s)| changed.contains(rel_s));
+    unchanged.sort_by_cached_key(|(p, _)| {
+        std::cmp::Reverse(std::fs::metadata(p).and_then(|m| m.modified()).ok())
+    });
+    let files = changed.into_iter().chain(unchanged).collect();
+    Ok(render_context(project_root, files, opts))
+}
+
+/// Files eligible for the context, in priority order
+fn context_files(project_root: &Path, opts: &ContextOptions) -> Result<Vec<(PathBuf, String)>> {
+    let mut builder = GlobSetBuilder::new();
+    for pattern in &opts.secret_exclude_patterns {
+        builder.add(Glob::new(pattern).map_err(|e| {
+            anyhow!("invalid context_secret_exclude_patterns glob {pattern:?}: {e}")
+        })?);
+    }
+    let secret = builder.build()?;
+    let mut secrets = Vec::new();
+    let mut files = Vec::new();
     for entry in WalkDir::new(project_root)
         .into_iter()
+        .filter_entry(|e| {
+            !(e.file_type().is_dir()
+                && (e.path().ends_with(".git")
+                    || e.path().ends_with("target")
+                    || e.path().ends_with("node_modules")
+                    || e.path().ends_with(".ipynb_checkpoints")))
+        })
         .filter_map(|e| e.ok())
     {
-        let p = entry.path();
         if entry.file_type().is_dir() {
-            if p.ends_with(".git") || p.ends_with("target") || p.ends_with("node_modules") {
-                continue;
-            }
             continue;
         }
+        let p = entry.path();
         let rel = p.strip_prefix(project_root).unwrap_or(p);
-        let rel_s = rel.to_string_lossy();
+        let rel_s = rel.to_string_lossy().into_owned();
+        if secret.is_match(entry.file_name()) {
+            secrets.push(rel_s);
+            continue;
+        }
+        // The lockfile helps pin versions but is only worth it while small
+        let small_lockfile = rel_s == "Cargo.lock"
+            && entry
+                .metadata()
+                .is_ok_and(|m| m.len() <= opts.max_file_bytes as u64);
         let include = rel_s.ends_with(".rs")
             || rel_s.ends_with("Cargo.toml")
+            || small_lockfile
             || rel_s.starts_with("tests/")
             || rel_s.starts_with("src/")
             || rel_s.starts_with("benches/")
             || rel_s.starts_with("examples/")
             || rel_s.starts_with("README")
-            || rel_s.ends_with(".md");
-        if !include {
-            continue;
+            || [".md", ".txt", ".sql", ".graphql", ".ipynb"]
+                .iter()
+                .any(|ext| rel_s.ends_with(ext));
+        if include && !starts_with_binary(p) {
+            files.push((p.to_path_buf(), rel_s));
         }
-        let Ok(contents) = std::fs::read_to_string(p) else {
+    }
+    files.sort_by_cached_key(|(p, rel_s)| {
+        // Manifests come first so the model knows which crates it can use
+        let manifest = match p.file_name().and_then(|n| n.to_str()) {
+            Some("Cargo.toml") => 0,
+            Some("Cargo.lock") => 1,
+            _ => 2,
+        };
+        let priority = opts
+            .priority_prefixes
+            .iter()
+            .position(|prefix| rel_s.starts_with(prefix.as_str()))
+            .unwrap_or(opts.priority_prefixes.len());
+        (manifest, priority, rel_s.clone())
+    });
+    // Once per run is enough to let the user know, context is collected several times a cycle
+    static WARNED: std::sync::Once = std::sync::Once::new();
+    if !secrets.is_empty() {
+        WARNED.call_once(|| {
+            warn!(
+                "Never sending secret-looking files to the LLM: {}",
+                secrets.join(", ")
+            )
+        });
+    }
+    Ok(files)
+}
+
+/// Whether the file's first bytes look binary; unreadable files count as binary
+fn starts_with_binary(path: &Path) -> bool {
+    let mut head = Vec::with_capacity(BINARY_SNIFF_BYTES);
+    match std::fs::File::open(path) {
+        Ok(file) => match file.take(BINARY_SNIFF_BYTES as u64).read_to_end(&mut head) {
+            Ok(_) => is_likely_binary(&head),
+            Err(_) => true,
+        },
+        Err(_) => true,
+    }
+}
+
+/// The editors' heuristic: text files don't contain NUL bytes, most binary formats do
+/// within their first few hundred bytes
+pub fn is_likely_binary(first_bytes: &[u8]) -> bool {
+    first_bytes[..first_bytes.len().min(BINARY_SNIFF_BYTES)].contains(&0)
+}
+
+/// The dependency summary plus each file's (possibly truncated) contents, up to `max_bytes`
+fn render_context(
+    project_root: &Path,
+    files: Vec<(PathBuf, String)>,
+    opts: &ContextOptions,
+) -> String {
+    let mut buf = String::new();
+    let mut total = 0usize;
+    let edition = opts
+        .annotate_syntax_errors
+        .then(|| rust_edition(project_root));
+    // Hints go first, where the LLM pays the most attention
+    if opts.include_todos
+        && let Some(hints) = todo_hints(&files)
+        && hints.len() <= opts.max_bytes
+    {
+        total += hints.len();
+        buf.push_str(&hints);
+    }
+    if let Some(manifest) = cargo_dependency_manifest(project_root)
+        && total + manifest.len() <= opts.max_bytes
+    {
+        total += manifest.len();
+        buf.push_str(&manifest);
+    }
+    for (p, rel_s) in files {
+        let Ok(mut contents) = std::fs::read_to_string(&p) else {
             continue;
         };
-        let header = format!("\n===== FILE: {rel_s} =====\n");
+        let syntax_error = match &edition {
+            Some(edition) if rel_s.ends_with(".rs") => has_syntax_error(&contents, edition),
+            _ => false,
+        };
+        if opts.strip_comments && rel_s.ends_with(".rs") {
+            contents = strip_rust_comments(&contents, opts.keep_pub_doc_comments);
+        }
+        if rel_s.ends_with(".ipynb")
+            && let Some(cells) = notebook_cells(&contents)
+        {
+            contents = cells;
+        }
+        let is_test = rel_s.starts_with("tests/") || rel_s.contains("/tests/");
+        let max_file_bytes = if is_test {
+            opts.max_test_file_bytes
+        } else {
+            opts.max_file_bytes
+        };
+        if contents.len() > max_file_bytes {
+            let kept = truncate_at_line(&contents, max_file_bytes).len();
+            contents.truncate(kept);
+            if !contents.is_empty() && !contents.ends_with('\n') {
+                contents.push('\n');
+            }
+            contents.push_str("[TRUNCATED]\n");
+        }
+        let mut header = format!("\n===== FILE: {rel_s} =====\n");
+        if syntax_error {
+            header.push_str(SYNTAX_ERROR_MARKER);
+            header.push('\n');
+        }
         let needed = header.len() + contents.len();
-        if total + needed > max_bytes {
+        if total + needed > opts.max_bytes {
             break;
         }
         buf.push_str(&header);
         buf.push_str(&contents);
         total += needed;
     }
-    Ok(buf)
+    buf
 }
 
-pub async fn apply_patch(project_root: &Path, patch: &LlmPatch) -> Result<Vec<PathBuf>> {
-    let mut touched = Vec::new();
+/// The code and markdown cells of a Jupyter notebook, each under a `# %% [code]` or
+/// `# %% [markdown]` header, instead of the notebook JSON with its outputs and metadata;
+/// `None` when `json` isn't a notebook
+fn notebook_cells(json: &str) -> Option<String> {
+    let notebook: serde_json::Value = serde_json::from_str(json).ok()?;
+    let mut out = String::new();
+    for cell in notebook.get("cells")?.as_array()? {
+        let kind = cell.get("cell_type").and_then(|t| t.as_str());
+        let (Some(kind @ ("code" | "markdown")), Some(source)) = (kind, cell.get("source")) else {
+            continue;
+        };
+        // `source` is a list of lines (each keeping its newline) or a single string
+        let source = match source {
+            serde_json::Value::Array(lines) => lines.iter().filter_map(|l| l.as_str()).collect(),
+            serde_json::Value::String(s) => s.clone(),
+            _ => continue,
+        };
+        out.push_str(&format!("# %% [{kind}]\n"));
+        push_lines(&mut out, &source);
+        out.push('\n');
+    }
+    Some(out)
+}
+
+/// "Project hints" section listing the `// TODO`, `// FIXME`, `// HACK` and `// XXX`
+/// comments in `files` as `path:line: comment`; `None` when there are none
+fn todo_hints(files: &[(PathBuf, String)]) -> Option<String> {
+    let mut hints = Vec::new();
+    for (p, rel_s) in files {
+        let Ok(contents) = std::fs::read_to_string(p) else {
+            continue;
+        };
+        for (i, line) in contents.lines().enumerate() {
+            let Some((_, comment)) = line.split_once("//") else {
+                continue;
+            };
+            let comment = comment.trim_start_matches(['/', '!']).trim();
+            if ["TODO", "FIXME", "HACK", "XXX"]
+                .iter()
+                .any(|tag| comment.starts_with(tag))
+            {
+                hints.push(format!("{rel_s}:{}: {comment}", i + 1));
+            }
+        }
+    }
+    if hints.is_empty() {
+        return None;
+    }
+    let mut section = String::from("\n===== PROJECT HINTS =====\n");
+    for hint in hints.iter().take(MAX_CONTEXT_HINTS) {
+        section.push_str(hint);
+        section.push('\n');
+    }
+    if hints.len() > MAX_CONTEXT_HINTS {
+        section.push_str(&format!(
+            "... and {} more\n",
+            hints.len() - MAX_CONTEXT_HINTS
+        ));
+    }
+    Some(section)
+}
+
+/// The `edition` declared in the project's Cargo.toml, 2021 if there is none
+fn rust_edition(project_root: &Path) -> String {
+    let manifest = std::fs::read_to_string(project_root.join("Cargo.toml")).unwrap_or_default();
+    manifest
+        .lines()
+        .filter_map(|l| l.trim().strip_prefix("edition"))
+        .find_map(|rest| {
+            let value = rest.trim_start().strip_prefix('=')?.trim();
+            Some(value.trim_matches(['"', '\'']).to_string())
+        })
+        .unwrap_or_else(|| "2021".to_string())
+}
+
+/// Whether `source` fails to parse, per `rustfmt` reading it from stdin (so out-of-line
+/// modules aren't followed and nothing is written). Without rustfmt nothing is flagged.
+fn has_syntax_error(source: &str, edition: &str) -> bool {
+    let child = std::process::Command::new("rustfmt")
+        .args(["--edition", edition, "--emit", "stdout"])
+        .stdin(Stdio::piped())
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .spawn();
+    let mut child = match child {
+        Ok(child) => child,
+        Err(e) => {
+            static WARNED: std::sync::Once = std::sync::Once::new();
+            WARNED.call_once(|| warn!("Can't check context files for syntax errors: {e}"));
+            return false;
+        }
+    };
+    if let Some(mut stdin) = child.stdin.take() {
+        use std::io::Write;
+        // rustfmt reads all of stdin before writing anything, so this can't deadlock
+        let _ = stdin.write_all(source.as_bytes());
+    }
+    child.wait().is_ok_and(|status| !status.success())
+}
+
+/// Crates and their declared dependencies per `cargo metadata --no-deps`, so the model
+/// doesn't have to read them out of TOML; `None` outside cargo projects or if cargo fails
+fn cargo_dependency_manifest(project_root: &Path) -> Option<String> {
+    if !project_root.join("Cargo.toml").is_file() {
+        return None;
+    }
+    let output = std::process::Command::new("cargo")
+        .args(["metadata", "--no-deps", "--format-version", "1"])
+        .current_dir(project_root)
+        .output()
+        .ok()?;
+    if !output.status.success() {
+        return None;
+    }
+    let metadata: serde_json::Value = serde_json::from_slice(&output.stdout).ok()?;
+    let mut manifest = String::from("\n===== DEPENDENCIES MANIFEST =====\n");
+    for package in metadata["packages"].as_array()? {
+        manifest.push_str(&format!(
+            "{} {}\n",
+            package["name"].as_str().unwrap_or("?"),
+            package["version"].as_str().unwrap_or("?")
+        ));
+        for dep in package["dependencies"].as_array().into_iter().flatten() {
+            manifest.push_str(&format!(
+                "  {} {}",
+                dep["name"].as_str().unwrap_or("?"),
+                dep["req"].as_str().unwrap_or("*")
+            ));
+            if let Some(kind) = dep["kind"].as_str() {
+                manifest.push_str(&format!(" ({kind})"));
+            }
+            manifest.push('\n');
+        }
+    }
+    Some(manifest)
+}
+
+/// Number of `#[test]`-style functions across the project's `.rs` files
+pub fn count_test_functions(project_root: &Path) -> Result<usize> {
+    Ok(count_test_functions_by_file(project_root)?.values().sum())
+}
+
+/// `#[test]` / `#[tokio::test]` attribute count per `.rs` file (files without tests omitted)
+pub fn count_test_functions_by_file(project_root: &Path) -> Result<HashMap<PathBuf, usize>> {
+    let mut counts = HashMap::new();
+    for entry in WalkDir::new(project_root)
+        .into_iter()
+        .filter_entry(|e| {
+            !(e.file_type().is_dir()
+                && (e.path().ends_with(".git") || e.path().ends_with("target")))
+        })
+        .filter_map(|e| e.ok())
+    {
+        if !entry.file_type().is_file() || entry.path().extension().is_none_or(|x| x != "rs") {
+            continue;
+        }
+        let Ok(contents) = std::fs::read_to_string(entry.path()) else {
+            continue;
+        };
+        let count = contents
+            .lines()
+            .map(str::trim_start)
+            .filter(|l| l.starts_with("#[test]") || l.starts_with("#[tokio::test"))
+            .count();
+        if count > 0 {
+            let rel = entry
+                .path()
+                .strip_prefix(project_root)
+                .unwrap_or(entry.path());
+            counts.insert(rel.to_path_buf(), count);
+        }
+    }
+    Ok(counts)
+}
+
+/// Best-effort comment removal to save tokens: drops `//` comment lines and `/* ... */` blocks
+/// that start a line (comments after code are kept, so string literals are never touched).
+/// Doc comments (`///`, `/** */`) directly above a `pub` item survive when `keep_pub_docs`.
+pub fn strip_rust_comments(src: &str, keep_pub_docs: bool) -> String {
+    let lines: Vec<&str> = src.lines().collect();
+    let mut out = String::with_capacity(src.len());
+    let mut i = 0;
+    while i < lines.len() {
+        let trimmed = lines[i].trim_start();
+        let start = i;
+        let is_doc = if trimmed.starts_with("//") {
+            i += 1;
+            trimmed.starts_with("///") && !trimmed.starts_with("////")
+        } else if let Some(body) = trimmed.strip_prefix("/*") {
+            let mut rest = body;
+            while !rest.contains("*/") && i + 1 < lines.len() {
+                i += 1;
+                rest = lines[i];
+            }
+            i += 1;
+            body.starts_with('*') && !body.starts_with("**") && !body.starts_with("*/")
+        } else {
+            out.push_str(lines[i]);
+            out.push('\n');
+            i += 1;
+            continue;
+        };
+        if is_doc && keep_pub_docs && next_item_is_pub(&lines[i..]) {
+            for line in &lines[start..i] {
+                out.push_str(line);
+                out.push('\n');
+            }
+        }
+    }
+    out
+}
+
+/// Whether the first code line in `lines` (past comments and attributes) declares a `pub` item
+fn next_item_is_pub(lines: &[&str]) -> bool {
+    lines
+        .iter()
+        .map(|l| l.trim_start())
+        .find(|l| {
+            !(l.is_empty() || l.starts_with("//") || l.starts_with("/*") || l.starts_with("#["))
+        })
+        .is_some_and(|l| l.starts_with("pub"))
+}
+
+/// Longest prefix of `s` within `max_bytes`, cut after the last complete line when there is one
+pub fn truncate_at_line(s: &str, max_bytes: usize) -> &str {
+    if s.len() <= max_bytes {
+        return s;
+    }
+    let mut end = max_bytes;
+    while !s.is_char_boundary(end) {
+        end -= 1;
+    }
+    match s[..end].rfind('\n') {
+        Some(nl) => &s[..=nl],
+        None => &s[..end],
+    }
+}
+
+/// Rules an LLM patch must satisfy before it is applied
+#[derive(Debug, Clone)]
+pub struct PatchPolicy {
+    /// Globs (relative to the project root) the LLM may never write to
+    pub protected_files: Vec<String>,
+    /// Most files a single patch may touch (0 = unlimited)
+    pub max_files: usize,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ValidationError {
+    ProtectedFile { path: String },
+    TooManyFiles { got: usize, max: usize },
+    TestTooLarge { lines: usize, max: usize },
+    InvalidGlob { glob: String, reason: String },
+    InvalidPath { reason: String },
+}
+
+impl fmt::Display for ValidationError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ValidationError::ProtectedFile { path } => {
+                write!(f, "you are not allowed to modify {path}")
+            }
+            ValidationError::TooManyFiles { got, max } => write!(
+                f,
+                "it modified too many files ({got}, at most {max} allowed). Focus on the minimal change."
+            ),
+            ValidationError::TestTooLarge { lines, max } => write!(
+                f,
+                "it adds {lines} lines of test code (at most {max} allowed). Add exactly one small, focused test."
+            ),
+            ValidationError::InvalidGlob { glob, reason } => {
+                write!(f, "invalid protected_files glob {glob:?}: {reason}")
+            }
+            ValidationError::InvalidPath { reason } => write!(f, "{reason}"),
+        }
+    }
+}
+
+impl std::error::Error for ValidationError {}
+
+pub fn validate_patch(
+    project_root: &Path,
+    patch: &LlmPatch,
+    policy: &PatchPolicy,
+) -> Result<(), ValidationError> {
+    if policy.max_files > 0 && patch.files.len() > policy.max_files {
+        return Err(ValidationError::TooManyFiles {
+            got: patch.files.len(),
+            max: policy.max_files,
+        });
+    }
+    let mut builder = GlobSetBuilder::new();
+    for glob in &policy.protected_files {
+        let g = Glob::new(glob).map_err(|e| ValidationError::InvalidGlob {
+            glob: glob.clone(),
+            reason: e.to_string(),
+        })?;
+        builder.add(g);
+    }
+    let protected = builder.build().map_err(|e| ValidationError::InvalidGlob {
+        glob: policy.protected_files.join(", "),
+        reason: e.to_string(),
+    })?;
     for fe in &patch.files {
-        let path = project_root.join(&fe.path);
+        let path = validate_file_edit_path(project_root, &fe.path).map_err(|e| {
+            ValidationError::InvalidPath {
+                reason: e.to_string(),
+            }
+        })?;
+        if protected.is_match(&path) {
+            return Err(ValidationError::ProtectedFile {
+                path: fe.path.clone(),
+            });
+        }
+    }
+    Ok(())
+}
+
+/// Reject a tester patch adding more than `max_lines` new lines of test code (0 = unlimited).
+/// Stubs the tester adds outside the tests to make them compile don't count.
+pub fn validate_test_size(
+    project_root: &Path,
+    patch: &LlmPatch,
+    max_lines: usize,
+) -> Result<(), ValidationError> {
+    let lines = patch
+        .files
+        .iter()
+        .map(|fe| new_test_line_count(project_root, fe))
+        .sum();
+    if max_lines > 0 && lines > max_lines {
+        return Err(ValidationError::TestTooLarge {
+            lines,
+            max: max_lines,
+        });
+    }
+    Ok(())
+}
+
+/// Test code lines an edit adds that the file does not already have: every line of a file
+/// under `tests/`, elsewhere those from the `#[cfg(test)]` line on (the test module, last
+/// by convention). A diff that doesn't apply counts all of its `+` lines.
+fn new_test_line_count(project_root: &Path, fe: &FileEdit) -> usize {
+    let old = std::fs::read_to_string(project_root.join(&fe.path)).unwrap_or_default();
+    let appended = || {
+        let mut text = old.clone();
+        push_lines(&mut text, &fe.content);
+        text
+    };
+    let new = match &fe.mode {
+        EditMode::Rewrite => fe.content.clone(),
+        EditMode::Append => appended(),
+        EditMode::InsertAfterPattern { pattern } => {
+            insert_after_pattern(&old, pattern, &fe.content).unwrap_or_else(appended)
+        }
+        EditMode::Patch => match apply_unified_diff(&old, &fe.content) {
+            Ok(text) => text,
+            Err(_) => return fe.content.lines().filter(|l| l.starts_with('+')).count(),
+        },
+    };
+    let in_tests_dir = fe.path.starts_with("tests/") || fe.path.contains("/tests/");
+    let test_code = |text: &str| -> Vec<String> {
+        let lines: Vec<&str> = text.lines().collect();
+        let start = if in_tests_dir {
+            0
+        } else {
+            lines
+                .iter()
+                .position(|l| l.trim_start().starts_with("#[cfg(test)]"))
+                .unwrap_or(lines.len())
+        };
+        lines[start..].iter().map(|l| l.to_string()).collect()
+    };
+    let mut existing: HashMap<String, usize> = HashMap::new();
+    for line in test_code(&old) {
+        *existing.entry(line).or_default() += 1;
+    }
+    test_code(&new)
+        .into_iter()
+        .filter(|line| match existing.get_mut(line) {
+            Some(n) if *n > 0 => {
+                *n -= 1;
+                false
+            }
+            _ => true,
+        })
+        .count()
+}
+
+/// What `InsertAfterPattern` does when no line contains the pattern
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum MissingPattern {
+    #[default]
+    Fail,
+    Append,
+}
+
+/// How `apply_patch` writes edits
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ApplyOptions {
+    pub missing_pattern: MissingPattern,
+    /// Write `\r\n` in edit content as is instead of normalizing it to `\n`
+    pub preserve_crlf: bool,
+}
+
+pub async fn apply_patch(
+    project_root: &Path,
+    patch: &LlmPatch,
+    opts: ApplyOptions,
+) -> Result<Vec<PathBuf>> {
+    let mut touched = Vec::new();
+    let mut new_dirs = Vec::new();
+    for (rel, fe) in normalized_edits(project_root, patch)? {
+        let path = project_root.join(&rel);
         if let Some(parent) = path.parent() {
-            fs::create_dir_all(parent).await?;
+            // The outermost directory this edit creates, e.g. `src/handlers` for `src/handlers/auth.rs`
+            let mut created = None;
+            for dir in rel
+                .ancestors()
+                .skip(1)
+                .filter(|d| !d.as_os_str().is_empty())
+            {
+                if fs::try_exists(project_root.join(dir)).await? {
+                    break;
+                }
+                created = Some(project_root.join(dir));
+            }
+            // Every mode may create the file (`append` and `insert_after_pattern` included),
+            // so its directories are made up front, however deep the path
+            fs::create_dir_all(parent)
+                .await
+                .with_context(|| format!("creating {}", parent.display()))?;
+            new_dirs.extend(created);
         }
-        match fe.mode {
+        let content = if opts.preserve_crlf {
+            fe.content.clone()
+        } else {
+            fe.content.replace("\r\n", "\n")
+        };
+        match &fe.mode {
             EditMode::Rewrite => {
-                fs::write(&path, fe.content.as_bytes()).await?;
+                fs::write(&path, content.as_bytes()).await?;
             }
             EditMode::Append => {
                 let mut file = fs::OpenOptions::new()
@@ -63,33 +689,1003 @@ pub async fn apply_patch(project_root: &Path, patch: &LlmPatch) -> Result<Vec<Pa
                     .append(true)
                     .open(&path)
                     .await?;
-                file.write_all(fe.content.as_bytes()).await?;
+                file.write_all(content.as_bytes()).await?;
+                // A tokio file may still be writing when dropped
+                file.flush().await?;
+            }
+            EditMode::InsertAfterPattern { pattern } => {
+                let existing = fs::read_to_string(&path).await.unwrap_or_default();
+                let updated = match insert_after_pattern(&existing, pattern, &content) {
+                    Some(updated) => updated,
+                    None if opts.missing_pattern == MissingPattern::Append => {
+                        let mut updated = existing;
+                        push_lines(&mut updated, &content);
+                        updated
+                    }
+                    None => return Err(anyhow!("pattern {pattern:?} not found in {}", fe.path)),
+                };
+                fs::write(&path, updated).await?;
+            }
+            EditMode::Patch => {
+                let existing = fs::read_to_string(&path).await.unwrap_or_default();
+                let updated = apply_unified_diff(&existing, &content)
+                    .with_context(|| format!("applying the diff for {}", fe.path))?;
+                fs::write(&path, updated).await?;
             }
         }
         touched.push(path);
     }
+    // Stage new directories explicitly, but only those that ended up holding files,
+    // since git can't commit an empty directory
+    for dir in new_dirs {
+        let has_files = WalkDir::new(&dir)
+            .into_iter()
+            .filter_map(|e| e.ok())
+            .any(|e| e.file_type().is_file());
+        if has_files {
+            touched.push(dir);
+        }
+    }
     Ok(touched)
 }
 
-pub async fn run_tests(project_root: &Path, cmd: &str) -> Result<(bool, String)> {
-    // Run via shell to allow complex commands
-    #[cfg(target_os = "windows")]
-    let mut command = {
-        let mut c = Command::new("cmd");
-        c.arg("/C").arg(cmd);
-        c
+/// Apply the `@@` hunks of one file's unified diff to `original`. A hunk's context and
+/// removed lines must appear in the file; the closest match to the hunk's stated line wins,
+/// since models rarely get line numbers right.
+fn apply_unified_diff(original: &str, diff: &str) -> Result<String> {
+    let mut hunks: Vec<(usize, Vec<&str>, Vec<&str>)> = Vec::new();
+    for line in diff.lines() {
+        if let Some(header) = line.strip_prefix("@@") {
+            // `@@ -12,5 +12,7 @@`: only the old start line is used
+            let start = header
+                .split_whitespace()
+                .find_map(|range| range.strip_prefix('-'))
+                .and_then(|range| range.split(',').next())
+                .and_then(|start| start.parse().ok())
+                .unwrap_or(1);
+            hunks.push((start, Vec::new(), Vec::new()));
+            continue;
+        }
+        let Some((_, old, new)) = hunks.last_mut() else {
+            continue;
+        };
+        match line.split_at_checked(1) {
+            Some(("+", added)) => new.push(added),
+            Some(("-", removed)) => old.push(removed),
+            Some(("\\", _)) => {}
+            Some((_, context)) => {
+                old.push(context);
+                new.push(context);
+            }
+            // Blank context lines often lose their leading space
+            None => {
+                old.push("");
+                new.push("");
+            }
+        }
+    }
+    if hunks.is_empty() {
+        return Err(anyhow!("the diff has no @@ hunks"));
+    }
+
+    let mut lines: Vec<&str> = original.lines().collect();
+    // Hunks apply in order, each after the previous one
+    let mut cursor = 0;
+    let mut shift = 0isize;
+    for (start, old, new) in hunks {
+        let hint = (start.saturating_sub(1) as isize + shift).max(0) as usize;
+        let pos = if old.is_empty() {
+            hint.clamp(cursor, lines.len())
+        } else {
+            (cursor..=lines.len().saturating_sub(old.len()))
+                // A hunk longer than what is left of the file can't match anywhere
+                .filter(|&pos| {
+                    lines.get(pos..pos + old.len()).is_some_and(|window| {
+                        window
+                            .iter()
+                            .zip(&old)
+                            .all(|(a, b)| a.trim_end() == b.trim_end())
+                    })
+                })
+                .min_by_key(|&pos| pos.abs_diff(hint))
+                .ok_or_else(|| {
+                    anyhow!(
+                        "hunk at line {start} does not match the file:\n{}",
+                        old.join("\n")
+                    )
+                })?
+        };
+        shift += new.len() as isize - old.len() as isize;
+        cursor = pos + new.len();
+        lines.splice(pos..pos + old.len(), new);
+    }
+    let mut updated = lines.join("\n");
+    if !updated.is_empty() {
+        updated.push('\n');
+    }
+    Ok(updated)
+}
+
+/// A `FileEdit` path checked and normalized to a path relative to the project root, with
+/// `.` segments dropped. Absolute paths (`/x`, `C:\x`), `..` segments, NUL bytes and paths
+/// leading out of the project through a symlink are rejected; Windows-style `\` separators
+/// are accepted with a warning.
+pub fn validate_file_edit_path(project_root: &Path, path: &str) -> Result<PathBuf> {
+    if path.contains('\0') {
+        return Err(anyhow!("patch path {path:?} contains a NUL byte"));
+    }
+    let mut unified = path.to_string();
+    if path.contains('\\') {
+        warn!("Patch path {path:?} uses backslashes; treating them as `/`");
+        unified = path.replace('\\', "/");
+    }
+    let bytes = unified.as_bytes();
+    // Drive letters only count as a prefix on Windows, but a patch is never meant to carry one
+    if unified.starts_with('/')
+        || (bytes.len() >= 2 && bytes[0].is_ascii_alphabetic() && bytes[1] == b':')
+    {
+        return Err(anyhow!("patch path {path:?} must be relative"));
+    }
+    let mut normalized = PathBuf::new();
+    for component in Path::new(&unified).components() {
+        match component {
+            Component::Normal(part) => normalized.push(part),
+            Component::CurDir => {}
+            Component::ParentDir => {
+                return Err(anyhow!("patch path {path:?} must not contain `..`"));
+            }
+            Component::RootDir | Component::Prefix(_) => {
+                return Err(anyhow!("patch path {path:?} must be relative"));
+            }
+        }
+    }
+    if normalized.as_os_str().is_empty() {
+        return Err(anyhow!("patch path {path:?} names no file"));
+    }
+    // A symlink inside the project can still point outside of it
+    if let Ok(root) = project_root.canonicalize()
+        && let Some(existing) = project_root
+            .join(&normalized)
+            .ancestors()
+            .find(|p| p.exists())
+        && existing.canonicalize().is_ok_and(|p| !p.starts_with(&root))
+    {
+        return Err(anyhow!(
+            "patch path {path:?} leads out of the project through a symlink"
+        ));
+    }
+    Ok(normalized)
+}
+
+/// The patch's edits keyed by normalized path; when several name the same file
+/// (`src/lib.rs` and `./src/lib.rs`), only the last one is kept
+fn normalized_edits<'a>(
+    project_root: &Path,
+    patch: &'a LlmPatch,
+) -> Result<Vec<(PathBuf, &'a FileEdit)>> {
+    let mut edits: Vec<(PathBuf, &FileEdit)> = Vec::new();
+    for fe in &patch.files {
+        let path = validate_file_edit_path(project_root, &fe.path)?;
+        if let Some(i) = edits.iter().position(|(p, _)| *p == path) {
+            warn!(
+                "Patch edits {} more than once; keeping the last edit",
+                path.display()
+            );
+            edits.remove(i);
+        }
+        edits.push((path, fe));
+    }
+    Ok(edits)
+}
+
+/// Line diff between the content each patch gives its files (the last edit of a path wins),
+/// for comparing implementor attempts; a file only one patch touches shows as all `+` or `-`
+pub fn diff_patches(patch_a: &LlmPatch, patch_b: &LlmPatch) -> String {
+    let mut paths: Vec<&str> = Vec::new();
+    for fe in patch_a.files.iter().chain(&patch_b.files) {
+        if !paths.contains(&fe.path.as_str()) {
+            paths.push(&fe.path);
+        }
+    }
+    let content = |patch: &LlmPatch, path: &str| {
+        patch
+            .files
+            .iter()
+            .rev()
+            .find(|fe| fe.path == path)
+            .map(|fe| fe.content.clone())
     };
-    #[cfg(not(target_os = "windows"))]
-    let mut command = {
-        let mut c = Command::new("sh");
-        c.arg("-lc").arg(cmd);
-        c
+    let mut out = String::new();
+    for path in paths {
+        let (a, b) = (content(patch_a, path), content(patch_b, path));
+        if a == b {
+            continue;
+        }
+        let side = |c: &Option<String>, prefix: &str| {
+            c.as_ref()
+                .map_or("/dev/null".to_string(), |_| format!("{prefix}/{path}"))
+        };
+        let diff = TextDiff::from_lines(a.as_deref().unwrap_or(""), b.as_deref().unwrap_or(""));
+        out.push_str(
+            &diff
+                .unified_diff()
+                .context_radius(2)
+                .header(&side(&a, "a"), &side(&b, "b"))
+                .to_string(),
+        );
+    }
+    out
+}
+
+/// `text` with `content` inserted after the first line containing `pattern`, if any
+fn insert_after_pattern(text: &str, pattern: &str, content: &str) -> Option<String> {
+    let mut offset = 0;
+    for line in text.split_inclusive('\n') {
+        offset += line.len();
+        if line.trim_end_matches(['\r', '\n']).contains(pattern) {
+            let (before, after) = text.split_at(offset);
+            let mut updated = before.to_string();
+            push_lines(&mut updated, content);
+            updated.push_str(after);
+            return Some(updated);
+        }
+    }
+    None
+}
+
+/// Append `content` as whole lines, starting on a fresh line and ending with a newline
+fn push_lines(text: &mut String, content: &str) {
+    if !text.is_empty() && !text.ends_with('\n') {
+        text.push('\n');
+    }
+    text.push_str(content);
+    if !content.ends_with('\n') {
+        text.push('\n');
+    }
+}
+
+/// Directory (relative to the project root) holding pre-patch file backups
+pub const BACKUP_DIR: &str = ".rgr-backups";
+
+/// Copy every file the patch will modify into `backup_dir`, write a
+/// `restore.sh` there that reverts the patch, then apply it. When `backup_dir` is inside the
+/// project, its top-level directory is added to `.gitignore`, which is then among the
+/// returned paths; commit that entry beforehand to keep it out of the patch's commit.
+pub async fn backup_and_apply(
+    project_root: &Path,
+    patch: &LlmPatch,
+    backup_dir: &Path,
+    opts: ApplyOptions,
+) -> Result<Vec<PathBuf>> {
+    fs::create_dir_all(backup_dir).await?;
+    // Absolute paths keep restore.sh usable from any working directory
+    let root = fs::canonicalize(project_root).await?;
+    let backup_dir = &fs::canonicalize(backup_dir).await?;
+    let mut script = String::from(
+        "#!/bin/sh\n# Reverts the files changed by a red-green-refactor patch\nset -e\n",
+    );
+    for (rel, _) in normalized_edits(project_root, patch)? {
+        let target = root.join(&rel);
+        if fs::try_exists(&target).await? {
+            let backup = backup_dir.join(&rel);
+            if let Some(parent) = backup.parent() {
+                fs::create_dir_all(parent).await?;
+            }
+            fs::copy(&target, &backup).await?;
+            script.push_str(&format!("cp {} {}\n", sh_quote(&backup), sh_quote(&target)));
+        } else {
+            script.push_str(&format!("rm -f {}\n", sh_quote(&target)));
+        }
+    }
+    let restore = backup_dir.join("restore.sh");
+    fs::write(&restore, script).await?;
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        fs::set_permissions(&restore, std::fs::Permissions::from_mode(0o755)).await?;
+    }
+
+    let mut touched = apply_patch(project_root, patch, opts).await?;
+    // Backups kept outside the project have nothing to ignore
+    let top = backup_dir
+        .strip_prefix(&root)
+        .ok()
+        .and_then(|rel| rel.components().next());
+    if let Some(top) = top
+        && ensure_gitignored(
+            project_root,
+            &format!("{}/", top.as_os_str().to_string_lossy()),
+        )
+        .await?
+    {
+        touched.push(project_root.join(".gitignore"));
+    }
+    Ok(touched)
+}
+
+fn sh_quote(p: &Path) -> String {
+    format!("'{}'", p.display().to_string().replace('\'', "'\\''"))
+}
+
+/// Append `entry` to the project's `.gitignore` unless already listed.
+/// Returns whether the file was changed.
+pub async fn ensure_gitignored(project_root: &Path, entry: &str) -> Result<bool> {
+    let path = project_root.join(".gitignore");
+    let existing = if fs::try_exists(&path).await? {
+        fs::read_to_string(&path).await?
+    } else {
+        String::new()
+    };
+    let bare = entry.trim_matches('/');
+    if existing.lines().any(|l| l.trim().trim_matches('/') == bare) {
+        return Ok(false);
+    }
+    let mut updated = existing;
+    if !updated.is_empty() && !updated.ends_with('\n') {
+        updated.push('\n');
+    }
+    updated.push_str(entry);
+    updated.push('\n');
+    fs::write(&path, updated).await?;
+    Ok(true)
+}
+
+/// Project ecosystem, guessed from the build files in the project root
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Language {
+    Rust,
+    Node,
+    Python,
+    Go,
+    Java,
+    Unknown,
+}
+
+impl Language {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Language::Rust => "rust",
+            Language::Node => "node",
+            Language::Python => "python",
+            Language::Go => "go",
+            Language::Java => "java",
+            Language::Unknown => "unknown",
+        }
+    }
+
+    /// Conventional test command; `None` when there is nothing sensible to suggest
+    pub fn default_test_cmd(self) -> Option<&'static str> {
+        match self {
+            Language::Rust => Some("cargo test --color never"),
+            Language::Node => Some("npm test"),
+            Language::Python => Some("pytest -q"),
+            Language::Go => Some("go test ./..."),
+            Language::Java => Some("mvn -q test"),
+            Language::Unknown => None,
+        }
+    }
+}
+
+pub fn detect_language(project_root: &Path) -> Language {
+    let has = |name: &str| project_root.join(name).exists();
+    if has("Cargo.toml") {
+        Language::Rust
+    } else if has("package.json") {
+        Language::Node
+    } else if has("pyproject.toml") || has("setup.py") || has("requirements.txt") {
+        Language::Python
+    } else if has("go.mod") {
+        Language::Go
+    } else if has("pom.xml") {
+        Language::Java
+    } else {
+        Language::Unknown
+    }
+}
+
+/// Whether `--project` names a git remote rather than a local path
+pub fn is_remote_project(project: &str) -> bool {
+    project.starts_with("https://") || project.starts_with("http://") || project.starts_with("git@")
+}
+
+/// Clone a remote kata into `dest` (which must be missing or empty)
+pub async fn clone_project(url: &str, dest: &Path) -> Result<()> {
+    let dest_str = dest.to_string_lossy();
+    let (ok, out) = vcs::run_git(Path::new("."), &["clone", "--", url, &dest_str]).await?;
+    if !ok {
+        return Err(anyhow!("git clone {} failed: {}", url, out));
+    }
+    Ok(())
+}
+
+/// How long a timed-out test command gets to exit after SIGTERM before it is killed
+pub const TEST_KILL_GRACE: Duration = Duration::from_secs(5);
+
+/// Outcome of one test command run, with its streams kept apart: compilers report on
+/// stderr while libtest prints results on stdout
+#[derive(Debug, Clone, Default)]
+pub struct TestResult {
+    pub passed: bool,
+    /// `None` when the process was killed by a signal (including `test_timeout_secs`)
+    pub exit_code: Option<i32>,
+    pub stdout: String,
+    pub stderr: String,
+    /// Structured results from a report file the runner wrote (nextest's JUnit XML), which
+    /// take precedence over parsing the output
+    pub report: Option<TestReport>,
+    /// Passed/failed/ignored totals from cargo's summary lines, when it printed any
+    pub counts: Option<TestCounts>,
+}
+
+impl TestResult {
+    /// stdout followed by stderr, as the suite's output is shown to the LLM and parsers
+    pub fn output(&self) -> String {
+        format!("{}{}", self.stdout, self.stderr)
+    }
+
+    /// The run failed before any test ran: a compile error (cargo test and nextest alike)
+    /// or a pytest collection error
+    pub fn build_failed(&self) -> bool {
+        !self.passed
+            && [&self.stdout, &self.stderr]
+                .iter()
+                .any(|s| s.contains("error: could not compile") || s.contains("ERROR collecting"))
+    }
+
+    /// `Build failed (exit code 101)`, `Tests failed (exit code 1)`, `Tests passed`, ...
+    pub fn status_line(&self) -> String {
+        let what = if self.passed {
+            return "Tests passed".to_string();
+        } else if self.build_failed() {
+            "Build failed"
+        } else {
+            "Tests failed"
+        };
+        match self.exit_code {
+            Some(code) => format!("{what} (exit code {code})"),
+            None => format!("{what} (killed by a signal)"),
+        }
+    }
+
+    /// Cut the streams to `max_bytes` in total, each keeping a share proportional to its size
+    fn truncate(mut self, max_bytes: usize) -> Self {
+        let total = self.stdout.len() + self.stderr.len();
+        if total > max_bytes {
+            let stdout_max = max_bytes * self.stdout.len() / total;
+            self.stdout = truncate_output(&self.stdout, stdout_max);
+            self.stderr = truncate_output(&self.stderr, max_bytes - stdout_max);
+        }
+        self
+    }
+}
+
+/// Run the test command, re-running a failing suite up to `flaky_retries` more times;
+/// passes if any run passes, otherwise reports the last run's output. Output beyond
+/// `max_output_bytes` keeps only its head and tail; a run exceeding `timeout` fails.
+/// With `display` on a terminal, a `--color never` command also runs with `--color always`
+/// alongside each run, straight to stderr; only the plain run is captured and judged.
+pub async fn run_tests(
+    project_root: &Path,
+    cmd: &str,
+    max_output_bytes: usize,
+    flaky_retries: usize,
+    timeout: Option<Duration>,
+    display: bool,
+) -> Result<TestResult> {
+    let colored = colored_command(cmd).filter(|_| display && std::io::stderr().is_terminal());
+    let run = || async {
+        let captured = async {
+            match timeout {
+                Some(timeout) => {
+                    run_tests_with_timeout(project_root, cmd, timeout, TEST_KILL_GRACE).await
+                }
+                None => run_captured(project_root, cmd).await,
+            }
+        };
+        let Some(colored) = &colored else {
+            return captured.await;
+        };
+        let (result, shown) = tokio::join!(captured, run_displayed(project_root, colored, timeout));
+        if let Err(e) = shown {
+            warn!("Could not show the colored test run: {e:#}");
+        }
+        result
+    };
+    let mut started = SystemTime::now();
+    let mut result = run().await?;
+    for retry in 1..=flaky_retries {
+        if result.passed {
+            break;
+        }
+        warn!("Tests failed; flaky retry {retry}/{flaky_retries}");
+        started = SystemTime::now();
+        result = run().await?;
+    }
+    result.report = test_runner::read_nextest_report(project_root, cmd, started);
+    result.counts = test_runner::parse_test_counts(&result.output());
+    Ok(result.truncate(max_output_bytes))
+}
+
+/// The failing tests in `cargo test` output, each with just its panic: the `thread '...'
+/// panicked at` line and the assertion message after it, without what the test printed
+/// before. Tests reported `FAILED` without a panic block (e.g. a test binary that crashed)
+/// get an empty message.
+pub fn summarize_test_failures(output: &str) -> Vec<TestFailure> {
+    let mut failures = CargoParser.parse(output).failures;
+    for failure in &mut failures {
+        let lines: Vec<&str> = failure.message.lines().collect();
+        if let Some(start) = lines
+            .iter()
+            .position(|l| l.starts_with("thread '") && l.contains("panicked at"))
+        {
+            failure.message = lines[start..].join("\n");
+        }
+    }
+    for line in output.lines() {
+        if let Some(name) = line
+            .strip_prefix("test ")
+            .and_then(|l| l.strip_suffix(" ... FAILED"))
+            && !failures.iter().any(|f| f.name == name)
+        {
+            failures.push(TestFailure {
+                name: name.to_string(),
+                ..Default::default()
+            });
+        }
+    }
+    failures
+}
+
+/// Run the test command, failing it once it exceeds `timeout`: the whole process group
+/// gets SIGTERM, then SIGKILL if still running after `kill_grace` (on Windows the shell
+/// is killed outright). Output produced before the timeout is kept.
+pub async fn run_tests_with_timeout(
+    project_root: &Path,
+    cmd: &str,
+    timeout: Duration,
+    kill_grace: Duration,
+) -> Result<TestResult> {
+    let mut command = shell_command(cmd);
+    // Own process group, so the test binaries the shell spawned are signaled too
+    #[cfg(unix)]
+    command.process_group(0);
+    let mut child = command
+        .current_dir(project_root)
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .kill_on_drop(true)
+        .spawn()?;
+    let stdout = tokio::spawn(read_pipe(child.stdout.take()));
+    let stderr = tokio::spawn(read_pipe(child.stderr.take()));
+
+    let status = match tokio::time::timeout(timeout, child.wait()).await {
+        Ok(status) => Some(status?),
+        Err(_) => {
+            warn!(
+                "`{cmd}` timed out after {}s; terminating it",
+                timeout.as_secs()
+            );
+            terminate(&mut child, kill_grace).await?;
+            None
+        }
+    };
+    let mut result = TestResult {
+        passed: status.is_some_and(|s| s.success()),
+        exit_code: status.and_then(|s| s.code()),
+        stdout: stdout.await??,
+        stderr: stderr.await??,
+        report: None,
+        counts: None,
+    };
+    if status.is_none() {
+        result.stderr.push_str(&format!(
+            "\n[test command timed out after {}s]\n",
+            timeout.as_secs()
+        ));
+    }
+    Ok(result)
+}
+
+#[cfg(unix)]
+async fn terminate(child: &mut tokio::process::Child, kill_grace: Duration) -> Result<()> {
+    if let Some(pid) = child.id() {
+        let group = -(pid as libc::pid_t);
+        // SAFETY: kill(2) has no memory-safety preconditions
+        unsafe { libc::kill(group, libc::SIGTERM) };
+        let _ = tokio::time::timeout(kill_grace, child.wait()).await;
+        // Also reaps any test binary that outlived the shell
+        unsafe { libc::kill(group, libc::SIGKILL) };
+    }
+    child.wait().await?;
+    Ok(())
+}
+
+#[cfg(not(unix))]
+async fn terminate(child: &mut tokio::process::Child, _kill_grace: Duration) -> Result<()> {
+    child.kill().await?;
+    Ok(())
+}
+
+async fn read_pipe(pipe: Option<impl AsyncRead + Unpin>) -> std::io::Result<String> {
+    let mut buf = Vec::new();
+    if let Some(mut pipe) = pipe {
+        pipe.read_to_end(&mut buf).await?;
+    }
+    Ok(String::from_utf8_lossy(&buf).into_owned())
+}
+
+/// Test names from a listing command such as `cargo test -- --list` (`<name>: test` lines)
+pub async fn list_tests(project_root: &Path, cmd: &str) -> Result<Vec<String>> {
+    let (ok, out) = run_shell(project_root, cmd).await?;
+    if !ok {
+        return Err(anyhow!("`{cmd}` failed: {}", truncate_output(&out, 2000)));
+    }
+    Ok(out
+        .lines()
+        .filter_map(|l| l.strip_suffix(": test"))
+        .map(str::to_string)
+        .collect())
+}
+
+/// Run a lint command (style/correctness checks, as opposed to `run_tests`' behavior checks)
+pub async fn run_linter(project_root: &Path, cmd: &str) -> Result<(bool, String)> {
+    run_shell(project_root, cmd).await
+}
+
+/// Keep the first and last `max / 2` bytes of `s` (build errors come first, test failures
+/// last), replacing the middle with a `[... N bytes truncated ...]` marker
+pub fn truncate_output(s: &str, max: usize) -> String {
+    if s.len() <= max {
+        return s.to_string();
+    }
+    let mut head = max / 2;
+    while !s.is_char_boundary(head) {
+        head -= 1;
+    }
+    let mut tail = s.len() - max / 2;
+    while !s.is_char_boundary(tail) {
+        tail += 1;
+    }
+    format!(
+        "{}\n[... {} bytes truncated ...]\n{}",
+        &s[..head],
+        tail - head,
+        &s[tail..]
+    )
+}
+
+/// Run `cmd` through the platform shell in the project root; returns success and combined output
+pub async fn run_shell(project_root: &Path, cmd: &str) -> Result<(bool, String)> {
+    let result = run_captured(project_root, cmd).await?;
+    Ok((result.passed, result.output()))
+}
+
+/// Like `run_shell`, keeping the exit code and the two streams apart
+async fn run_captured(project_root: &Path, cmd: &str) -> Result<TestResult> {
+    let output = shell_command(cmd)
+        .current_dir(project_root)
+        .output()
+        .await?;
+    Ok(TestResult {
+        passed: output.status.success(),
+        exit_code: output.status.code(),
+        stdout: String::from_utf8_lossy(&output.stdout).into_owned(),
+        stderr: String::from_utf8_lossy(&output.stderr).into_owned(),
+        report: None,
+        counts: None,
+    })
+}
+
+/// `cmd` with cargo's `--color never` turned into `--color always`; `None` when it has none
+fn colored_command(cmd: &str) -> Option<String> {
+    ["--color never", "--color=never"]
+        .into_iter()
+        .find(|flag| cmd.contains(flag))
+        .map(|flag| cmd.replace(flag, "--color always"))
+}
+
+/// Run `cmd` with its output copied to stderr as it comes, for the user to watch. Once
+/// `timeout` runs out, its process group is terminated as in `run_tests_with_timeout`.
+async fn run_displayed(project_root: &Path, cmd: &str, timeout: Option<Duration>) -> Result<()> {
+    let mut command = shell_command(cmd);
+    // Own process group, so a stuck test binary is stopped along with the shell
+    #[cfg(unix)]
+    command.process_group(0);
+    let mut child = command
+        .current_dir(project_root)
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .kill_on_drop(true)
+        .spawn()?;
+    let stdout = tokio::spawn(show_pipe(child.stdout.take()));
+    let stderr = tokio::spawn(show_pipe(child.stderr.take()));
+    let status = match timeout {
+        Some(timeout) => tokio::time::timeout(timeout, child.wait()).await.ok(),
+        None => Some(child.wait().await),
     };
+    match status {
+        Some(status) => {
+            status?;
+        }
+        None => terminate(&mut child, TEST_KILL_GRACE).await?,
+    }
+    stdout.await??;
+    stderr.await??;
+    Ok(())
+}
 
-    let output = command.current_dir(project_root).output().await?;
-    let mut text = String::new();
-    text.push_str(&String::from_utf8_lossy(&output.stdout));
-    text.push_str(&String::from_utf8_lossy(&output.stderr));
-    let ok = output.status.success();
-    Ok((ok, text))
+async fn show_pipe(pipe: Option<impl AsyncRead + Unpin>) -> std::io::Result<()> {
+    if let Some(mut pipe) = pipe {
+        tokio::io::copy(&mut pipe, &mut tokio::io::stderr()).await?;
+    }
+    Ok(())
+}
+
+/// Run via shell to allow complex commands
+fn shell_command(cmd: &str) -> Command {
+    #[cfg(target_os = "windows")]
+    let mut command = Command::new("cmd");
+    #[cfg(target_os = "windows")]
+    command.arg("/C").arg(cmd);
+    #[cfg(not(target_os = "windows"))]
+    let mut command = Command::new("sh");
+    #[cfg(not(target_os = "windows"))]
+    command.arg("-lc").arg(cmd);
+    command
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn insert_after_pattern_on_the_first_line() {
+        let text = "use std::fmt;\nfn main() {}\n";
+        assert_eq!(
+            insert_after_pattern(text, "use std", "use std::io;").as_deref(),
+            Some("use std::fmt;\nuse std::io;\nfn main() {}\n")
+        );
+    }
+
+    #[test]
+    fn insert_after_pattern_in_the_middle() {
+        let text = "mod a;\nmod b;\n\nfn main() {}\n";
+        assert_eq!(
+            insert_after_pattern(text, "mod b", "mod c;\n").as_deref(),
+            Some("mod a;\nmod b;\nmod c;\n\nfn main() {}\n")
+        );
+    }
+
+    #[test]
+    fn insert_after_pattern_on_the_last_line_without_a_newline() {
+        let text = "mod a;\nmod b;";
+        assert_eq!(
+            insert_after_pattern(text, "mod b", "mod c;").as_deref(),
+            Some("mod a;\nmod b;\nmod c;\n")
+        );
+    }
+
+    #[test]
+    fn insert_after_pattern_uses_the_first_match_only() {
+        let text = "// x\n// x\n";
+        assert_eq!(
+            insert_after_pattern(text, "x", "y").as_deref(),
+            Some("// x\ny\n// x\n")
+        );
+        assert_eq!(insert_after_pattern(text, "missing", "y"), None);
+    }
+
+    fn edit(path: &str, mode: EditMode, content: &str) -> LlmPatch {
+        LlmPatch {
+            files: vec![FileEdit {
+                path: path.into(),
+                mode,
+                content: content.into(),
+            }],
+            ..Default::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn missing_pattern_fails_or_appends() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::write(dir.path().join("lib.rs"), "mod a;\n").unwrap();
+        let patch = edit(
+            "lib.rs",
+            EditMode::InsertAfterPattern {
+                pattern: "mod z".into(),
+            },
+            "mod b;",
+        );
+        assert!(
+            apply_patch(dir.path(), &patch, ApplyOptions::default())
+                .await
+                .is_err()
+        );
+        let opts = ApplyOptions {
+            missing_pattern: MissingPattern::Append,
+            ..Default::default()
+        };
+        apply_patch(dir.path(), &patch, opts).await.unwrap();
+        assert_eq!(
+            std::fs::read_to_string(dir.path().join("lib.rs")).unwrap(),
+            "mod a;\nmod b;\n"
+        );
+    }
+
+    #[tokio::test]
+    async fn rewrite_and_append_create_missing_parent_directories() {
+        let dir = tempfile::tempdir().unwrap();
+        for (path, mode) in [
+            ("src/a/b/c/d.rs", EditMode::Rewrite),
+            ("src/e/f/g/h.rs", EditMode::Append),
+        ] {
+            apply_patch(
+                dir.path(),
+                &edit(path, mode, "fn f() {}\n"),
+                ApplyOptions::default(),
+            )
+            .await
+            .unwrap();
+            assert_eq!(
+                std::fs::read_to_string(dir.path().join(path)).unwrap(),
+                "fn f() {}\n"
+            );
+        }
+    }
+
+    #[cfg(target_os = "linux")]
+    #[tokio::test]
+    async fn displayed_run_stops_the_whole_command_on_timeout() {
+        let dir = tempfile::tempdir().unwrap();
+        run_displayed(
+            dir.path(),
+            "sleep 30 & echo $! > bg.pid; wait",
+            Some(Duration::from_millis(500)),
+        )
+        .await
+        .unwrap();
+        let pid = std::fs::read_to_string(dir.path().join("bg.pid")).unwrap();
+        // Gone, or a zombie waiting to be reaped by init
+        let stat = std::fs::read_to_string(format!("/proc/{}/stat", pid.trim()));
+        assert!(
+            stat.map_or(true, |stat| stat.contains(") Z ")),
+            "the background sleep outlived the timeout"
+        );
+    }
+
+    #[tokio::test]
+    async fn backups_are_ignored_where_they_are_kept() {
+        let project = tempfile::tempdir().unwrap();
+        let root = project.path();
+        let patch = edit("src/lib.rs", EditMode::Rewrite, "fn f() {}\n");
+
+        let touched = backup_and_apply(
+            root,
+            &patch,
+            &root.join("backups/1"),
+            ApplyOptions::default(),
+        )
+        .await
+        .unwrap();
+        assert!(touched.contains(&root.join(".gitignore")));
+        assert_eq!(
+            std::fs::read_to_string(root.join(".gitignore")).unwrap(),
+            "backups/\n"
+        );
+
+        let outside = tempfile::tempdir().unwrap();
+        let touched = backup_and_apply(root, &patch, outside.path(), ApplyOptions::default())
+            .await
+            .unwrap();
+        assert_eq!(touched, [root.join("src/lib.rs")]);
+        assert_eq!(
+            std::fs::read_to_string(root.join(".gitignore")).unwrap(),
+            "backups/\n"
+        );
+    }
+
+    #[test]
+    fn diff_patches_shows_changed_and_one_sided_files() {
+        let a = LlmPatch {
+            files: vec![
+                FileEdit {
+                    path: "src/lib.rs".into(),
+                    mode: EditMode::Rewrite,
+                    content: "fn a() {}\nfn b() {}\n".into(),
+                },
+                FileEdit {
+                    path: "src/old.rs".into(),
+                    mode: EditMode::Rewrite,
+                    content: "gone\n".into(),
+                },
+            ],
+            ..Default::default()
+        };
+        let b = edit("src/lib.rs", EditMode::Rewrite, "fn a() {}\nfn c() {}\n");
+
+        assert_eq!(
+            diff_patches(&a, &b),
+            "--- a/src/lib.rs\n+++ b/src/lib.rs\n@@ -1,2 +1,2 @@\n fn a() {}\n-fn b() {}\n+fn c() {}\n\
+             --- a/src/old.rs\n+++ /dev/null\n@@ -1 +0,0 @@\n-gone\n"
+        );
+        assert_eq!(diff_patches(&b, &b), "");
+    }
+
+    #[test]
+    fn hunk_longer_than_the_file_does_not_match() {
+        let diff = "@@ -1,3 +1,3 @@\n a\n-b\n+B\n c\n";
+        let err = apply_unified_diff("a\n", diff).unwrap_err();
+        assert!(err.to_string().contains("does not match"), "{err}");
+    }
+
+    #[tokio::test]
+    async fn crlf_content_is_written_with_lf() {
+        let dir = tempfile::tempdir().unwrap();
+        let patch = edit(
+            "src/lib.rs",
+            EditMode::Rewrite,
+            "fn a() {}\r\nfn b() {}\r\n",
+        );
+        apply_patch(dir.path(), &patch, ApplyOptions::default())
+            .await
+            .unwrap();
+        assert_eq!(
+            std::fs::read(dir.path().join("src/lib.rs")).unwrap(),
+            b"fn a() {}\nfn b() {}\n"
+        );
+    }
+
+    #[tokio::test]
+    async fn preserve_crlf_keeps_crlf_content() {
+        let dir = tempfile::tempdir().unwrap();
+        let patch = edit("notes.txt", EditMode::Append, "a\r\nb\r\n");
+        let opts = ApplyOptions {
+            preserve_crlf: true,
+            ..Default::default()
+        };
+        apply_patch(dir.path(), &patch, opts).await.unwrap();
+        assert_eq!(
+            std::fs::read(dir.path().join("notes.txt")).unwrap(),
+            b"a\r\nb\r\n"
+        );
+    }
+
+    #[test]
+    fn test_size_counts_test_code_only() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::write(dir.path().join("lib.rs"), "pub fn a() {}\n").unwrap();
+        let stub = "pub fn a() {}\npub fn b() -> u32 {\n    todo!()\n}\n";
+        let test = "#[cfg(test)]\nmod tests {\n    #[test]\n    fn b() {\n        assert_eq!(super::b(), 1);\n    }\n}\n";
+        let patch = edit("lib.rs", EditMode::Rewrite, &format!("{stub}\n{test}"));
+        assert!(validate_test_size(dir.path(), &patch, 7).is_ok());
+        assert!(matches!(
+            validate_test_size(dir.path(), &patch, 6),
+            Err(ValidationError::TestTooLarge { lines: 7, max: 6 })
+        ));
+        let patch = edit("tests/b.rs", EditMode::Rewrite, stub);
+        assert!(validate_test_size(dir.path(), &patch, 3).is_err());
+    }
+
+    /// Fails on its first run in a directory, leaving a flag file that makes later runs pass
+    const FAILS_ONCE: &str = "if [ -f flaky.flag ]; then exit 0; else touch flaky.flag; exit 1; fi";
+
+    #[tokio::test]
+    async fn flaky_retry_passes_a_suite_that_fails_once() {
+        let dir = tempfile::tempdir().unwrap();
+        let result = run_tests(dir.path(), FAILS_ONCE, 10_000, 1, None, false)
+            .await
+            .unwrap();
+        assert!(result.passed);
+    }
+
+    #[tokio::test]
+    async fn without_flaky_retries_the_first_failure_stands() {
+        let dir = tempfile::tempdir().unwrap();
+        let result = run_tests(dir.path(), FAILS_ONCE, 10_000, 0, None, false)
+            .await
+            .unwrap();
+        assert!(!result.passed);
+        assert!(dir.path().join("flaky.flag").exists());
+    }
 }